@@ -1,29 +1,118 @@
-use crate::publish::Publish;
-use bisetmap::BisetMap;
+use crate::{
+    connection::Connection, publish::Publish, sys_stats::SysStats, MsgIdType,
+};
+use bytes::Bytes;
+use hashbrown::{HashMap, HashSet};
+use log::*;
+use std::collections::VecDeque;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 /// Cache for published messages
 use std::sync::Mutex;
 
+/// Default maximum number of messages cached for a sleeping client.
+pub const ASLEEP_MSG_CACHE_LIMIT: usize = 64;
+
+static LIMIT: AtomicUsize = AtomicUsize::new(ASLEEP_MSG_CACHE_LIMIT);
+
 lazy_static! {
-    static ref ASLEEP_MSG_CACHE: Mutex<BisetMap<SocketAddr, Publish>> =
-        Mutex::new(BisetMap::new());
+    // VecDeque keeps the messages in the published order.
+    static ref ASLEEP_MSG_CACHE: Mutex<HashMap<SocketAddr, VecDeque<Publish>>> =
+        Mutex::new(HashMap::new());
+    // msg_ids of the QoS 1 & 2 messages sent to an awake client,
+    // waiting for PUBACK or PUBCOMP.
+    static ref AWAKE_IN_FLIGHT: Mutex<HashMap<SocketAddr, HashSet<MsgIdType>>> =
+        Mutex::new(HashMap::new());
+    // The limits of the clients without the default limit, by client id.
+    static ref CLIENT_LIMITS: Mutex<HashMap<Bytes, usize>> =
+        Mutex::new(HashMap::new());
 }
 
 #[derive(Debug, Clone)]
 pub struct AsleepMsgCache {}
 
 impl AsleepMsgCache {
-    // Don't need vec of Publish because BisetMap allows the same key with different
-    // values. HashMap would require a Vec of Publish, one key maps to one value.
+    /// Set the default maximum number of messages cached for a client.
+    pub fn set_limit(limit: usize) {
+        LIMIT.store(limit, Ordering::Relaxed);
+    }
+    /// Set the maximum number of messages cached for the client_id, it
+    /// follows the session of the client to a new socket_addr.
+    pub fn set_client_limit(client_id: Bytes, limit: usize) {
+        CLIENT_LIMITS.lock().unwrap().insert(client_id, limit);
+    }
+    /// The limit of the session at the socket_addr.
+    fn limit(key: &SocketAddr) -> usize {
+        let limit = LIMIT.load(Ordering::Relaxed);
+        let client_limits = CLIENT_LIMITS.lock().unwrap();
+        if client_limits.is_empty() {
+            return limit;
+        }
+        match Connection::get(key) {
+            Some(conn) => *client_limits.get(&conn.client_id).unwrap_or(&limit),
+            None => limit,
+        }
+    }
+    /// When the cache of the client is full, the oldest message is dropped.
     pub fn insert(key: SocketAddr, value: Publish) {
-        let cache = ASLEEP_MSG_CACHE.lock().unwrap();
-        cache.insert(key, value);
+        let limit = AsleepMsgCache::limit(&key);
+        let mut cache = ASLEEP_MSG_CACHE.lock().unwrap();
+        let msg_deque = cache.entry(key).or_insert_with(VecDeque::new);
+        msg_deque.push_back(value);
+        while msg_deque.len() > limit {
+            if let Some(publish) = msg_deque.pop_front() {
+                warn!("asleep message cache full: {} {:?}", key, publish);
+                SysStats::dropped();
+            }
+        }
     }
 
-    // returns all the Publish objects with the key.
+    // returns all the Publish objects with the key, oldest first.
     pub fn delete(key: SocketAddr) -> Vec<Publish> {
-        let cache = ASLEEP_MSG_CACHE.lock().unwrap();
-        cache.delete(&key)
+        let mut cache = ASLEEP_MSG_CACHE.lock().unwrap();
+        match cache.remove(&key) {
+            Some(msg_deque) => msg_deque.into(),
+            None => Vec::new(),
+        }
+    }
+    /// Move the cached messages to the new socket_addr of the client.
+    pub fn rebind(key: &SocketAddr, new_key: SocketAddr) {
+        let mut cache = ASLEEP_MSG_CACHE.lock().unwrap();
+        if let Some(msg_deque) = cache.remove(key) {
+            cache.insert(new_key, msg_deque);
+        }
+        let mut in_flight = AWAKE_IN_FLIGHT.lock().unwrap();
+        if let Some(msg_id_set) = in_flight.remove(key) {
+            in_flight.insert(new_key, msg_id_set);
+        }
+    }
+    /// Replace the msg_ids waiting for acknowledgement from an awake client.
+    pub fn insert_in_flight(key: SocketAddr, msg_id_vec: Vec<MsgIdType>) {
+        let mut in_flight = AWAKE_IN_FLIGHT.lock().unwrap();
+        in_flight.insert(key, msg_id_vec.into_iter().collect());
+    }
+    /// Add the msg_ids sent to an awake client waiting for
+    /// acknowledgement, if it has any.
+    pub fn add_in_flight(key: SocketAddr, msg_id_vec: &[MsgIdType]) {
+        let mut in_flight = AWAKE_IN_FLIGHT.lock().unwrap();
+        if let Some(msg_id_set) = in_flight.get_mut(&key) {
+            msg_id_set.extend(msg_id_vec);
+        }
+    }
+    /// Remove an acknowledged msg_id, returns true if it was the last one.
+    pub fn remove_in_flight(key: SocketAddr, msg_id: MsgIdType) -> bool {
+        let mut in_flight = AWAKE_IN_FLIGHT.lock().unwrap();
+        match in_flight.get_mut(&key) {
+            Some(msg_id_set) => {
+                if msg_id_set.remove(&msg_id) && msg_id_set.is_empty() {
+                    in_flight.remove(&key);
+                    true
+                } else {
+                    false
+                }
+            }
+            None => false,
+        }
     }
     pub fn debug() {
         let cache = ASLEEP_MSG_CACHE.lock().unwrap();
@@ -53,3 +142,47 @@ fn test_asleep_cache() {
     dbg!(msg_vec);
     AsleepMsgCache::debug();
 }
+#[cfg(test)]
+#[test]
+fn test_client_limit() {
+    use crate::PROTOCOL_ID_V1_2;
+    use bytes::BytesMut;
+
+    let socket = "127.0.0.3:1200".parse::<SocketAddr>().unwrap();
+    let client_id = Bytes::from_static(b"asleep_limit");
+    Connection::try_insert(socket, 0, PROTOCOL_ID_V1_2, 30, client_id.clone())
+        .unwrap();
+    AsleepMsgCache::set_client_limit(client_id, 2);
+    assert_eq!(AsleepMsgCache::limit(&socket), 2);
+    let publish =
+        |msg_id| Publish::new(7, msg_id, 1, 3, BytesMut::from(&b"hello"[..]));
+    for msg_id in 1..=3 {
+        AsleepMsgCache::insert(socket, publish(msg_id));
+    }
+    // The oldest message is dropped.
+    assert_eq!(AsleepMsgCache::delete(socket), vec![publish(2), publish(3)]);
+}
+#[cfg(test)]
+#[test]
+fn test_asleep_cache_limit_and_in_flight() {
+    use bytes::BytesMut;
+    use std::net::SocketAddr;
+
+    let socket = "127.0.0.3:1200".parse::<SocketAddr>().unwrap();
+    let bytes = BytesMut::from(&b"hello"[..]);
+    for msg_id in 0..(ASLEEP_MSG_CACHE_LIMIT + 2) as u16 {
+        let p = Publish::new(1, msg_id, 1, 0, bytes.clone());
+        AsleepMsgCache::insert(socket, p);
+    }
+    let msg_vec = AsleepMsgCache::delete(socket);
+    assert_eq!(msg_vec.len(), ASLEEP_MSG_CACHE_LIMIT);
+    // the two oldest messages are dropped.
+    assert_eq!(msg_vec[0], Publish::new(1, 2, 1, 0, bytes));
+    assert!(AsleepMsgCache::delete(socket).is_empty());
+
+    AsleepMsgCache::insert_in_flight(socket, vec![1, 2]);
+    assert!(!AsleepMsgCache::remove_in_flight(socket, 1));
+    assert!(!AsleepMsgCache::remove_in_flight(socket, 3));
+    assert!(AsleepMsgCache::remove_in_flight(socket, 2));
+    assert!(!AsleepMsgCache::remove_in_flight(socket, 2));
+}
@@ -0,0 +1,446 @@
+/*
+MQTT-SN 2.0 AUTH
+Length    MsgType ReasonCode AuthMethodLength AuthMethod AuthData
+(octet 0) (1)     (2)        (3)              (4:m)      (m+1:n)
+• Length and MsgType: MsgType is coded "0x03".
+• ReasonCode: 0x00 Success, 0x18 Continue authentication,
+0x19 Re-authenticate.
+• AuthMethodLength: length of the AuthMethod string.
+• AuthMethod: name of the authentication method.
+• AuthData: method specific authentication data.
+AUTH is exchanged after a CONNECT with the Auth flag set, or later for
+re-authentication.
+*/
+use bytes::{BufMut, Bytes, BytesMut};
+use hashbrown::HashMap;
+use log::*;
+use serde::Deserialize;
+use std::fs;
+use std::net::SocketAddr;
+use std::str;
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    broker_lib::MqttSnClient,
+    conn_ack::ConnAck,
+    connect::{Connect, ConnectV2},
+    connection::Connection,
+    dbg_buf,
+    disconnect::Disconnect,
+    eformat, function,
+    keep_alive::KeepAliveTimeWheel,
+    msg_hdr::MsgHeader,
+    MSG_TYPE_AUTH, REASON_CODE_BAD_AUTHENTICATION_METHOD,
+    REASON_CODE_CONTINUE_AUTHENTICATION, REASON_CODE_NOT_AUTHORIZED,
+    REASON_CODE_SERVER_BUSY, REASON_CODE_SUCCESS,
+};
+
+/// Maximum number of CONNECTs waiting for their authentication.
+pub const AUTH_PENDING_LIMIT: usize = 1024;
+
+/// The outcome of an AUTH from the client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthResult {
+    Success,
+    /// Another round, the data is sent back to the client.
+    Continue(Bytes),
+    Fail,
+}
+
+/// An authentication method of the broker.
+pub trait Authenticator: Send + Sync {
+    /// The AuthMethod of the AUTH messages, e.g. "PLAIN".
+    fn method(&self) -> &str;
+    /// Check the AuthData of an AUTH from the client.
+    fn authenticate(&self, client_id: &Bytes, data: &[u8]) -> AuthResult;
+}
+
+/// SASL PLAIN (RFC 4616), the AuthData is
+/// [authzid] NUL authcid NUL passwd
+/// and the authcid and passwd must match a user. The users are loaded
+/// from a JSON file:
+///     [{"user": "sensor1", "password": "secret"}, ...]
+#[derive(Debug, Clone, Default)]
+pub struct PlainAuth {
+    users: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct PlainAuthEntry {
+    user: String,
+    password: String,
+}
+
+impl PlainAuth {
+    pub fn new() -> Self {
+        PlainAuth::default()
+    }
+    /// Load the users from a JSON file.
+    pub fn load(path: &str) -> Result<PlainAuth, String> {
+        match fs::read_to_string(path) {
+            Ok(json) => PlainAuth::load_str(&json),
+            Err(why) => Err(eformat!(path, why)),
+        }
+    }
+    pub fn load_str(json: &str) -> Result<PlainAuth, String> {
+        let entries: Vec<PlainAuthEntry> = match serde_json::from_str(json) {
+            Ok(entries) => entries,
+            Err(why) => return Err(eformat!(why)),
+        };
+        let mut plain_auth = PlainAuth::new();
+        for entry in entries {
+            plain_auth.insert(entry.user, entry.password);
+        }
+        Ok(plain_auth)
+    }
+    pub fn insert(&mut self, user: String, password: String) {
+        self.users.insert(user, password);
+    }
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+}
+
+impl Authenticator for PlainAuth {
+    fn method(&self) -> &str {
+        "PLAIN"
+    }
+    fn authenticate(&self, _client_id: &Bytes, data: &[u8]) -> AuthResult {
+        let mut fields = data.split(|byte| *byte == 0);
+        let (_authzid, user, password) = match (
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+        ) {
+            (Some(authzid), Some(user), Some(password), None) => {
+                (authzid, user, password)
+            }
+            _ => return AuthResult::Fail,
+        };
+        let user = match str::from_utf8(user) {
+            Ok(user) => user,
+            Err(_) => return AuthResult::Fail,
+        };
+        match self.users.get(user) {
+            Some(expected) if expected.as_bytes() == password => {
+                AuthResult::Success
+            }
+            _ => AuthResult::Fail,
+        }
+    }
+}
+
+lazy_static! {
+    static ref AUTHENTICATOR: Mutex<Option<Arc<dyn Authenticator>>> =
+        Mutex::new(None);
+    // The CONNECTs with the Auth flag, until the authentication ends.
+    static ref PENDING: Mutex<HashMap<SocketAddr, ConnectV2>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Without an authenticator, the CONNECTs with the Auth flag and the
+/// AUTH messages are refused with "Bad authentication method".
+#[derive(Debug, Clone)]
+pub struct Auth {}
+
+impl Auth {
+    pub fn set_authenticator(authenticator: Arc<dyn Authenticator>) {
+        *AUTHENTICATOR.lock().unwrap() = Some(authenticator);
+    }
+    fn authenticator() -> Option<Arc<dyn Authenticator>> {
+        AUTHENTICATOR.lock().unwrap().clone()
+    }
+    /// A CONNECT from the socket_addr waits for its AUTH.
+    pub fn is_pending(socket_addr: &SocketAddr) -> bool {
+        PENDING.lock().unwrap().contains_key(socket_addr)
+    }
+    /// Keep the CONNECT until the AUTH exchange ends, a new CONNECT from
+    /// the socket_addr replaces it.
+    pub fn insert_pending(
+        socket_addr: SocketAddr,
+        connect: ConnectV2,
+    ) -> Result<(), String> {
+        if Auth::authenticator().is_none() {
+            return Err(eformat!(socket_addr, "authentication not supported"));
+        }
+        let mut pending = PENDING.lock().unwrap();
+        if pending.len() >= AUTH_PENDING_LIMIT
+            && !pending.contains_key(&socket_addr)
+        {
+            return Err(eformat!(
+                socket_addr,
+                "too many pending",
+                pending.len()
+            ));
+        }
+        pending.insert(socket_addr, connect);
+        Ok(())
+    }
+    /// Parse the ReasonCode, AuthMethod and AuthData of an AUTH.
+    fn parse(
+        buf: &[u8],
+        size: usize,
+        header_len: usize,
+    ) -> Result<(u8, String, Bytes), String> {
+        // ReasonCode and AuthMethodLength
+        if size < header_len + 2 {
+            return Err(eformat!("len err", size));
+        }
+        let method_start = header_len + 2;
+        let method_end = method_start + buf[header_len + 1] as usize;
+        if size < method_end {
+            return Err(eformat!("len err", size));
+        }
+        let method = match str::from_utf8(&buf[method_start..method_end]) {
+            Ok(method) => method.to_string(),
+            Err(why) => return Err(eformat!(why)),
+        };
+        let data = Bytes::copy_from_slice(&buf[method_end..size]);
+        Ok((buf[header_len], method, data))
+    }
+    /// Refuse the pending CONNECT with a CONNACK, or disconnect the
+    /// client that failed to re-authenticate.
+    fn refuse(
+        client: &MqttSnClient,
+        msg_header: MsgHeader,
+        pending: bool,
+        reason_code: u8,
+    ) -> Result<(), String> {
+        let remote_addr = msg_header.remote_socket_addr;
+        if pending {
+            return ConnAck::send_v2(
+                client,
+                msg_header,
+                reason_code,
+                0,
+                Bytes::new(),
+            );
+        }
+        Disconnect::send_v2(client, msg_header, reason_code)?;
+        if Connection::get(&remote_addr).is_some() {
+            KeepAliveTimeWheel::cancel(&remote_addr)?;
+            let _conn = Connection::remove(&remote_addr)?;
+        }
+        Ok(())
+    }
+    pub fn recv(
+        buf: &[u8],
+        size: usize,
+        client: &MqttSnClient,
+        msg_header: MsgHeader,
+    ) -> Result<(), String> {
+        dbg_buf!(buf, size);
+        let remote_addr = msg_header.remote_socket_addr;
+        let (reason_code, method, data) =
+            match Auth::parse(buf, size, msg_header.header_len as usize) {
+                Ok(auth) => auth,
+                Err(why) => return Err(eformat!(remote_addr, why)),
+            };
+        debug!("Auth: {:?} {:#04x} {}", remote_addr, reason_code, method);
+        let pending = PENDING.lock().unwrap().remove(&remote_addr);
+        let is_pending = pending.is_some();
+        let authenticator = match Auth::authenticator() {
+            Some(authenticator) if authenticator.method() == method => {
+                authenticator
+            }
+            _ => {
+                Auth::refuse(
+                    client,
+                    msg_header,
+                    is_pending,
+                    REASON_CODE_BAD_AUTHENTICATION_METHOD,
+                )?;
+                return Err(eformat!(
+                    remote_addr,
+                    "auth method not supported",
+                    method
+                ));
+            }
+        };
+        // A CONNECT in progress or a re-authentication.
+        let client_id = match (&pending, Connection::get(&remote_addr)) {
+            (Some(connect), _) => connect.client_id.clone(),
+            (None, Some(conn)) => conn.client_id,
+            (None, None) => return Err(eformat!(remote_addr, "not connected")),
+        };
+        match authenticator.authenticate(&client_id, &data) {
+            AuthResult::Success => match pending {
+                Some(connect) => {
+                    Connect::accept_v2(client, msg_header, connect)
+                }
+                None => Auth::send(
+                    client,
+                    remote_addr,
+                    REASON_CODE_SUCCESS,
+                    &method,
+                    Bytes::new(),
+                ),
+            },
+            AuthResult::Continue(data) => {
+                if let Some(connect) = pending {
+                    if let Err(why) = Auth::insert_pending(remote_addr, connect)
+                    {
+                        Auth::refuse(
+                            client,
+                            msg_header,
+                            true,
+                            REASON_CODE_SERVER_BUSY,
+                        )?;
+                        return Err(why);
+                    }
+                }
+                Auth::send(
+                    client,
+                    remote_addr,
+                    REASON_CODE_CONTINUE_AUTHENTICATION,
+                    &method,
+                    data,
+                )
+            }
+            AuthResult::Fail => {
+                Auth::refuse(
+                    client,
+                    msg_header,
+                    is_pending,
+                    REASON_CODE_NOT_AUTHORIZED,
+                )?;
+                Err(eformat!(remote_addr, "not authorized", client_id))
+            }
+        }
+    }
+    pub fn send(
+        client: &MqttSnClient,
+        remote_addr: SocketAddr,
+        reason_code: u8,
+        method: &str,
+        data: Bytes,
+    ) -> Result<(), String> {
+        let len = 4 + method.len() + data.len();
+        if len > u8::MAX as usize {
+            return Err(eformat!(remote_addr, "len err", len));
+        }
+        let mut bytes_buf = BytesMut::with_capacity(len);
+        bytes_buf.put_u8(len as u8);
+        bytes_buf.put_u8(MSG_TYPE_AUTH);
+        bytes_buf.put_u8(reason_code);
+        bytes_buf.put_u8(method.len() as u8);
+        bytes_buf.put(method.as_bytes());
+        bytes_buf.put(data);
+        match client.egress_tx.try_send((remote_addr, bytes_buf)) {
+            Ok(()) => Ok(()),
+            Err(err) => Err(eformat!(remote_addr, err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_auth_parse() {
+        use super::Auth;
+        use crate::REASON_CODE_CONTINUE_AUTHENTICATION;
+
+        // AUTH Continue, method "PLAIN" and data "ab".
+        let buf = [11, 0x03, 0x18, 5, b'P', b'L', b'A', b'I', b'N', b'a', b'b'];
+        let (reason_code, method, data) = Auth::parse(&buf, 11, 2).unwrap();
+        assert_eq!(reason_code, REASON_CODE_CONTINUE_AUTHENTICATION);
+        assert_eq!(method, "PLAIN");
+        assert_eq!(&data[..], b"ab");
+        // The method is longer than the message.
+        assert!(Auth::parse(&buf, 7, 2).is_err());
+        assert!(Auth::parse(&buf, 3, 2).is_err());
+    }
+    #[test]
+    fn test_plain_auth() {
+        use super::{AuthResult, Authenticator, PlainAuth};
+        use bytes::Bytes;
+
+        let json = r#"[{"user": "sensor1", "password": "secret"}]"#;
+        let plain_auth = PlainAuth::load_str(json).unwrap();
+        assert_eq!(plain_auth.len(), 1);
+        let client_id = Bytes::from_static(b"plain_auth");
+        let check = |data: &[u8]| plain_auth.authenticate(&client_id, data);
+        assert_eq!(check(b"\0sensor1\0secret"), AuthResult::Success);
+        assert_eq!(check(b"admin\0sensor1\0secret"), AuthResult::Success);
+        assert_eq!(check(b"\0sensor1\0guess"), AuthResult::Fail);
+        assert_eq!(check(b"\0sensor2\0secret"), AuthResult::Fail);
+        assert_eq!(check(b"sensor1\0secret"), AuthResult::Fail);
+        assert!(PlainAuth::load_str("[{}]").is_err());
+    }
+    #[test]
+    fn test_auth_exchange() {
+        use super::*;
+        use crate::flags::{AUTH_TRUE, CLEAN_SESSION_TRUE};
+        use crate::{MSG_TYPE_CONNACK, MSG_TYPE_CONNECT, PROTOCOL_ID_V2_0};
+        use util::conn::Conn;
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let socket = runtime
+            .block_on(tokio::net::UdpSocket::bind("127.0.0.1:0"))
+            .unwrap();
+        let conn: Arc<dyn Conn + Send + Sync> = Arc::new(socket);
+        let client = MqttSnClient::new();
+        let addr = "127.0.0.96:1200".parse::<SocketAddr>().unwrap();
+        let header = |buf: &[u8]| {
+            MsgHeader::try_read(buf, buf.len(), addr, conn.clone()).unwrap()
+        };
+        let mut plain_auth = PlainAuth::new();
+        plain_auth.insert("sensor1".to_string(), "secret".to_string());
+        Auth::set_authenticator(Arc::new(plain_auth));
+
+        let mut connect = vec![
+            0,
+            MSG_TYPE_CONNECT,
+            AUTH_TRUE | CLEAN_SESSION_TRUE,
+            PROTOCOL_ID_V2_0,
+            0,
+            30,
+            0,
+            0,
+            0,
+            0,
+            0x05,
+            0xdc,
+        ];
+        connect.extend_from_slice(b"auth_exchange");
+        connect[0] = connect.len() as u8;
+        let auth = |password: &[u8]| {
+            let mut buf = vec![0, MSG_TYPE_AUTH, REASON_CODE_SUCCESS, 5];
+            buf.extend_from_slice(b"PLAIN\0sensor1\0");
+            buf.extend_from_slice(password);
+            buf[0] = buf.len() as u8;
+            buf
+        };
+
+        // The CONNECT waits for the AUTH of the client.
+        Connect::recv(&connect, connect.len(), &client, header(&connect))
+            .unwrap();
+        assert!(Auth::is_pending(&addr));
+        assert!(!Connection::contains_key(addr));
+        assert!(client.egress_rx.try_recv().is_err());
+
+        // A wrong password refuses the CONNECT.
+        let buf = auth(b"guess");
+        assert!(Auth::recv(&buf, buf.len(), &client, header(&buf)).is_err());
+        let (_addr, bytes) = client.egress_rx.try_recv().unwrap();
+        assert_eq!(bytes[1], MSG_TYPE_CONNACK);
+        assert_eq!(bytes[2], REASON_CODE_NOT_AUTHORIZED);
+        assert!(!Auth::is_pending(&addr));
+        assert!(!Connection::contains_key(addr));
+
+        // The right password accepts it.
+        Connect::recv(&connect, connect.len(), &client, header(&connect))
+            .unwrap();
+        let buf = auth(b"secret");
+        Auth::recv(&buf, buf.len(), &client, header(&buf)).unwrap();
+        let (_addr, bytes) = client.egress_rx.try_recv().unwrap();
+        assert_eq!(bytes[1], MSG_TYPE_CONNACK);
+        assert_eq!(bytes[2], REASON_CODE_SUCCESS);
+        assert!(!Auth::is_pending(&addr));
+        assert!(Connection::contains_key(addr));
+    }
+}
@@ -10,6 +10,7 @@ use util::conn::*;
 
 use crate::{
     advertise::*,
+    auth::Auth,
     // Channels::Channels,
     conn_ack::ConnAck,
     connect::Connect,
@@ -17,6 +18,8 @@ use crate::{
     dbg_buf,
     disconnect::Disconnect,
     eformat,
+    encap_msg::EncapMsg,
+    flags::{flag_qos_level, QoSConst, QOS_LEVEL_3},
     function,
     gw_info::GwInfo,
     hub::Hub,
@@ -34,10 +37,14 @@ use crate::{
     register::Register,
     retain_cache::Retain,
     retain_cache::RetainCache,
+    retain_cache::RetainSub,
     retransmit::RetransTimeWheel,
     search_gw::SearchGw,
+    session_expiry::SessionExpiry,
+    session_store::SessionStore,
     sub_ack::SubAck,
     subscribe::Subscribe,
+    sys_stats::SysStats,
     // tikv::TiKV,
     unsub_ack::UnsubAck,
     unsubscribe::Unsubscribe,
@@ -49,8 +56,17 @@ use crate::{
     will_topic_req::WillTopicReq,
     will_topic_resp::WillTopicResp,
     will_topic_upd::WillTopicUpd,
-    TopicIdType,
+    MSG_TYPE_AUTH,
     MSG_TYPE_CONNECT,
+    MSG_TYPE_PINGREQ,
+    MSG_TYPE_PUBLISH,
+    MSG_TYPE_SUBSCRIBE,
+    MSG_TYPE_UNSUBSCRIBE,
+    MSG_TYPE_WILL_MSG,
+    MSG_TYPE_WILL_MSG_UPD,
+    MSG_TYPE_WILL_TOPIC,
+    MSG_TYPE_WILL_TOPIC_UPD,
+    MTU,
 };
 // use trace_var::trace_var;
 
@@ -78,6 +94,8 @@ pub enum MessageTypeEnum {
 }
 pub type IngressChannelType = (SocketAddr, Bytes, Arc<dyn Conn + Send + Sync>);
 pub type EgressChannelType = (SocketAddr, BytesMut);
+/// The subscriber, the subscription and its QoS.
+pub type SubRetainChannelType = (SocketAddr, RetainSub, QoSConst);
 
 #[derive(Clone)]
 pub struct MqttSnClient {
@@ -92,8 +110,8 @@ pub struct MqttSnClient {
     pub egress_rx: Receiver<EgressChannelType>,
     pub pub_retain_tx: Sender<Retain>,
     pub pub_retain_rx: Receiver<Retain>,
-    pub sub_retain_tx: Sender<(SocketAddr, TopicIdType)>,
-    pub sub_retain_rx: Receiver<(SocketAddr, TopicIdType)>,
+    pub sub_retain_tx: Sender<SubRetainChannelType>,
+    pub sub_retain_rx: Receiver<SubRetainChannelType>,
 
     pub hub: Arc<Hub>,
     // pub retain_cache: RetainCache,
@@ -128,8 +146,8 @@ impl MqttSnClient {
             unbounded();
         // Channel for publish retain messages.
         let (sub_retain_tx, sub_retain_rx): (
-            Sender<(SocketAddr, TopicIdType)>,
-            Receiver<(SocketAddr, TopicIdType)>,
+            Sender<SubRetainChannelType>,
+            Receiver<SubRetainChannelType>,
         ) = unbounded();
         let hub = Arc::new(Hub::new(Arc::new(ingress_tx.clone())));
         // let retain_cache = RetainCache::new();
@@ -161,9 +179,28 @@ impl MqttSnClient {
         tokio::spawn(async move {
             loop {
                 match self.egress_rx.recv() {
-                    Ok((addr, data)) => {
+                    Ok((node_addr, data)) => {
+                        // Messages to a wireless node are sent to
+                        // its forwarder.
+                        let (addr, data) = match EncapMsg::wrap(node_addr, data)
+                        {
+                            Ok(msg) => msg,
+                            Err(why) => {
+                                error!("{}", why);
+                                continue;
+                            }
+                        };
+                        // The last message to a node without a session,
+                        // e.g. the DISCONNECT or a rejected CONNECT.
+                        if addr != node_addr
+                            && !Connection::contains_key(node_addr)
+                        {
+                            EncapMsg::remove_node(&node_addr);
+                        }
                         let dtls_conn = hub2.get_conn(addr).await.unwrap();
-                        let _result = dtls_conn.send(&data[..]).await;
+                        if dtls_conn.send(&data[..]).await.is_ok() {
+                            SysStats::sent();
+                        }
                     }
                     Err(why) => {
                         error!("{}", eformat!(why));
@@ -188,7 +225,7 @@ impl MqttSnClient {
             Advertise::recv,     // 0x00
             GwInfo::recv,        // 0x01
             GwInfo::recv,        // 0x02
-            reserved,            // 0x03
+            Auth::recv,          // 0x03
             Connect::recv,       // 0x04
             ConnAck::recv,       // 0x05
             WillTopicReq::recv,  // 0x06
@@ -221,6 +258,19 @@ impl MqttSnClient {
             loop {
                 match self.ingress_rx.recv() {
                     Ok((addr, bytes, conn)) => {
+                        // Unwrap the message from a forwarder,
+                        // the wireless node is the client.
+                        let (addr, bytes) = if EncapMsg::is_encap_msg(&bytes) {
+                            match EncapMsg::unwrap(addr, &bytes) {
+                                Ok(node) => node,
+                                Err(e) => {
+                                    error!("{}", e);
+                                    continue;
+                                }
+                            }
+                        } else {
+                            (addr, bytes)
+                        };
                         let buf = &bytes[..];
                         let size = bytes.len();
                         // Update the last seen time of the client.
@@ -234,24 +284,24 @@ impl MqttSnClient {
                                     continue;
                                 }
                             };
+                        SysStats::received();
                         let msg_type = msg_header.msg_type;
                         let fn_index = msg_header.msg_type as usize;
-                        // Existing MQTT-SN connection or new connection.
                         // DTLS connection is created at lower layer.
-                        if Connection::contains_key(addr) {
-                            // New connection.
-                            // TODO: the broadcast messages doesn't have connection.
-                            // TODO: broadcast messages are not encrypted.
-                            if msg_type == MSG_TYPE_CONNECT {
-                                error!("{}", "Connect message received twice.");
-                                continue;
-                            }
-                        } else {
-                            // Existing connection shouldn't receive CONNECT message.
-                            if msg_type != MSG_TYPE_CONNECT {
-                                error!("{}", "No connection found");
-                                continue;
-                            }
+                        // A CONNECT on a connected address is a reconnect,
+                        // Connection::take_over() closes the session of
+                        // another client at the address. A PINGREQ with
+                        // the client id moves the session of a sleeping
+                        // client to the new address. The AUTH of a CONNECT
+                        // with the Auth flag comes before the session.
+                        if !Connection::contains_key(addr)
+                            && msg_type != MSG_TYPE_CONNECT
+                            && msg_type != MSG_TYPE_PINGREQ
+                            && !(msg_type == MSG_TYPE_AUTH
+                                && Auth::is_pending(&addr))
+                        {
+                            error!("{}", "No connection found");
+                            continue;
                         }
                         if fn_index >= functions.len() {
                             error!(
@@ -272,6 +322,22 @@ impl MqttSnClient {
                         );
                         if result.is_err() {
                             error!("{}", result.unwrap_err());
+                            continue;
+                        }
+                        // Save the session after it changed.
+                        match msg_type {
+                            MSG_TYPE_CONNECT
+                            | MSG_TYPE_WILL_TOPIC
+                            | MSG_TYPE_WILL_MSG
+                            | MSG_TYPE_SUBSCRIBE
+                            | MSG_TYPE_UNSUBSCRIBE
+                            | MSG_TYPE_WILL_TOPIC_UPD
+                            | MSG_TYPE_WILL_MSG_UPD => {
+                                if let Err(why) = SessionStore::save(&addr) {
+                                    error!("{}", why);
+                                }
+                            }
+                            _ => {}
                         }
                         continue;
                     }
@@ -287,7 +353,6 @@ impl MqttSnClient {
     pub fn broker_rx_loop(self, socket: UdpSocket) {
         let self_transmit = self.clone();
         // name for easy debug
-        let socket_tx = socket.try_clone().expect("couldn't clone the socket");
         let builder = thread::Builder::new().name("recv_thread".into());
 
         let broadcast_socket_addr =
@@ -295,13 +360,69 @@ impl MqttSnClient {
         let gateway_info_socket_addr =
             "224.0.0.123:62000".parse::<SocketAddr>().unwrap();
 
-        KeepAliveTimeWheel::init();
         KeepAliveTimeWheel::run(self.clone());
-        RetransTimeWheel::init();
         RetransTimeWheel::run(self.clone());
+        SessionExpiry::run();
         Advertise::run(broadcast_socket_addr, 5, 2);
         GwInfo::run(gateway_info_socket_addr);
 
+        // Plain UDP listener for the QoS -1 PUBLISH messages from senders
+        // without a connection, MQTT-SN 1.2 spec section 6.8.
+        // All other messages must use the DTLS connection.
+        // The socket is non-blocking for tokio, the transmit thread sends
+        // with the same tokio socket, see below.
+        let self_udp = self.clone();
+        socket
+            .set_nonblocking(true)
+            .expect("couldn't set the socket non-blocking");
+        let socket_udp = Arc::new(
+            tokio::net::UdpSocket::from_std(socket)
+                .expect("couldn't register the socket with tokio"),
+        );
+        let socket_tx = socket_udp.clone();
+        let runtime = tokio::runtime::Handle::current();
+        tokio::spawn(async move {
+            let mut buf = [0; MTU];
+            loop {
+                let (size, addr) = match socket_udp.recv_from(&mut buf).await {
+                    Ok(recv) => recv,
+                    Err(why) => {
+                        error!("{}", why);
+                        continue;
+                    }
+                };
+                let msg_header = match MsgHeader::try_read(
+                    &buf,
+                    size,
+                    addr,
+                    socket_udp.clone(),
+                ) {
+                    Ok(header) => header,
+                    Err(e) => {
+                        error!("{}", e);
+                        continue;
+                    }
+                };
+                // The flags follow the message header.
+                let flags_index = msg_header.header_len as usize;
+                if msg_header.msg_type != MSG_TYPE_PUBLISH
+                    || size <= flags_index
+                    || flag_qos_level(buf[flags_index]) != QOS_LEVEL_3
+                {
+                    error!(
+                        "{}",
+                        eformat!(addr, "plain UDP is for QoS -1 PUBLISH only")
+                    );
+                    continue;
+                }
+                if let Err(why) =
+                    Publish::recv(&buf[..size], size, &self_udp, msg_header)
+                {
+                    error!("{}", why);
+                }
+            }
+        });
+
         // self.retain_cache.run2(&mut self.clone());
 
         // client runs this to search for gateway.
@@ -455,7 +576,9 @@ impl MqttSnClient {
                     let new_bytes = bytes.clone();
                     egress_tx.send((addr, new_bytes)).unwrap();
 
-                    match socket_tx.send_to(&bytes[..], addr) {
+                    // Waits until the non-blocking socket is writable.
+                    match runtime.block_on(socket_tx.send_to(&bytes[..], addr))
+                    {
                         Ok(size) if size == bytes.len() => (),
                         Ok(size) => {
                             error!(
@@ -1,8 +1,21 @@
 /// Client Id BisetMap stores client id and its socket addresses.
+/// An empty client id with CleanSession=true is assigned a unique client
+/// id by the broker, MQTT-SN 2.0 clients get it in the CONNACK.
 use bisetmap::BisetMap;
 use bytes::Bytes;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
+use uuid::Uuid;
+
+use crate::{eformat, flags::flag_is_clean_session, function};
+
+/// Prefix of the client ids assigned by the broker.
+pub const ASSIGNED_CLIENT_ID_PREFIX: &str = "mqttsn-";
+// The assigned client id fits in the 23 characters of MQTT-SN 1.2.
+const ASSIGNED_CLIENT_ID_LEN: usize = 23;
+
+static ANONYMOUS_SESSIONS: AtomicBool = AtomicBool::new(true);
 
 lazy_static! {
     static ref CLIENT_ID_MAP: Mutex<BisetMap<Bytes, SocketAddr>> =
@@ -34,6 +47,33 @@ impl ClientId {
     pub fn rev_delete(client_id: &SocketAddr) -> Vec<Bytes> {
         CLIENT_ID_MAP.lock().unwrap().rev_delete(client_id)
     }
+    /// Accept the CONNECT with an empty client id, default is true.
+    pub fn set_anonymous_sessions(anonymous: bool) {
+        ANONYMOUS_SESSIONS.store(anonymous, Ordering::Relaxed);
+    }
+    /// Assign a unique client id to a CONNECT with an empty client id.
+    /// A persistent session can't be resumed without its client id, so
+    /// CleanSession=false is rejected.
+    pub fn assign(flags: u8) -> Result<Bytes, String> {
+        if !ANONYMOUS_SESSIONS.load(Ordering::Relaxed) {
+            return Err(eformat!("anonymous sessions not allowed", flags));
+        }
+        if !flag_is_clean_session(flags) {
+            return Err(eformat!("empty client id with clean session", flags));
+        }
+        loop {
+            let uuid = Uuid::new_v4().to_simple().to_string();
+            let mut client_id = String::from(ASSIGNED_CLIENT_ID_PREFIX);
+            client_id.push_str(
+                &uuid[..ASSIGNED_CLIENT_ID_LEN
+                    - ASSIGNED_CLIENT_ID_PREFIX.len()],
+            );
+            let client_id = Bytes::from(client_id);
+            if !ClientId::exists(&client_id) {
+                return Ok(client_id);
+            }
+        }
+    }
     pub fn debug() {
         let cache = CLIENT_ID_MAP.lock().unwrap();
         dbg!(&cache);
@@ -71,3 +111,15 @@ fn test_client_id() {
     let val = ClientId::exists(&bytes);
     dbg!(val);
 }
+#[cfg(test)]
+#[test]
+fn test_assign_client_id() {
+    use crate::flags::CLEAN_SESSION_TRUE;
+
+    let client_id = ClientId::assign(CLEAN_SESSION_TRUE).unwrap();
+    assert_eq!(client_id.len(), ASSIGNED_CLIENT_ID_LEN);
+    assert!(client_id.starts_with(ASSIGNED_CLIENT_ID_PREFIX.as_bytes()));
+    assert_ne!(ClientId::assign(CLEAN_SESSION_TRUE).unwrap(), client_id);
+    // A persistent session needs the client id.
+    assert!(ClientId::assign(0).is_err());
+}
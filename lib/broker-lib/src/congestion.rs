@@ -0,0 +1,131 @@
+//! Congestion control, MQTT-SN 1.2 spec section 6.3 & 6.5.
+//! When the broker is overloaded, CONNECT, REGISTER, SUBSCRIBE and
+//! PUBLISH are answered with "rejected: congestion", the client should
+//! not retry before T_WAIT.
+//! The broker is overloaded when the messages waiting in the ingress
+//! channel reach the ingress limit, a CONNECT also when the sessions
+//! reach the session limit. A limit of 0 is no limit.
+
+use bytes::Bytes;
+use hashbrown::HashMap;
+use log::*;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::{
+    broker_lib::MqttSnClient, connection::Connection, MSG_TYPE_CONNECT,
+};
+
+/// Default maximum number of messages waiting in the ingress channel.
+pub const INGRESS_LIMIT: usize = 1024;
+/// Default maximum number of sessions, no limit.
+pub const SESSION_LIMIT: usize = 0;
+
+static MAX_INGRESS: AtomicUsize = AtomicUsize::new(INGRESS_LIMIT);
+static MAX_SESSIONS: AtomicUsize = AtomicUsize::new(SESSION_LIMIT);
+
+lazy_static! {
+    // msg_type -> number of times the congestion was signalled.
+    static ref SIGNALLED: Mutex<HashMap<u8, u64>> = Mutex::new(HashMap::new());
+}
+
+/// The messages waiting in the ingress channel are over the limit.
+fn congested(waiting: usize) -> bool {
+    let limit = MAX_INGRESS.load(Ordering::Relaxed);
+    limit != 0 && waiting >= limit
+}
+
+#[derive(Debug, Clone)]
+pub struct Congestion {}
+
+impl Congestion {
+    pub fn set_ingress_limit(limit: usize) {
+        MAX_INGRESS.store(limit, Ordering::Relaxed);
+    }
+    pub fn set_session_limit(limit: usize) {
+        MAX_SESSIONS.store(limit, Ordering::Relaxed);
+    }
+    /// Returns true and records the signal if the ingress channel is
+    /// over the limit, the caller rejects the message with congestion.
+    pub fn detect(
+        client: &MqttSnClient,
+        socket_addr: &SocketAddr,
+        msg_type: u8,
+    ) -> bool {
+        if !congested(client.ingress_rx.len()) {
+            return false;
+        }
+        Congestion::signal(socket_addr, msg_type);
+        true
+    }
+    /// Same as detect() for a CONNECT, a new session is also rejected
+    /// when the sessions are over the limit. A client with a session can
+    /// always reconnect.
+    pub fn detect_connect(
+        client: &MqttSnClient,
+        socket_addr: &SocketAddr,
+        client_id: &Bytes,
+    ) -> bool {
+        if Congestion::detect(client, socket_addr, MSG_TYPE_CONNECT) {
+            return true;
+        }
+        let limit = MAX_SESSIONS.load(Ordering::Relaxed);
+        if limit == 0
+            || Connection::len() < limit
+            || Connection::get_with_client_id(client_id).is_some()
+        {
+            return false;
+        }
+        Congestion::signal(socket_addr, MSG_TYPE_CONNECT);
+        true
+    }
+    fn signal(socket_addr: &SocketAddr, msg_type: u8) {
+        let mut signalled = SIGNALLED.lock().unwrap();
+        let count = signalled.entry(msg_type).or_insert(0);
+        *count += 1;
+        warn!(
+            "congestion: {} msg_type 0x{:x}, {} times",
+            socket_addr, msg_type, count
+        );
+    }
+    /// Number of times the congestion was signalled for the msg_type.
+    pub fn signalled(msg_type: u8) -> u64 {
+        SIGNALLED
+            .lock()
+            .unwrap()
+            .get(&msg_type)
+            .copied()
+            .unwrap_or(0)
+    }
+    /// Number of times the congestion was signalled for all messages.
+    pub fn signalled_total() -> u64 {
+        SIGNALLED.lock().unwrap().values().sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_congestion() {
+        use super::{congested, Congestion, INGRESS_LIMIT};
+        use crate::{MSG_TYPE_REGISTER, MSG_TYPE_SUBSCRIBE};
+        use std::net::SocketAddr;
+
+        assert!(!congested(INGRESS_LIMIT - 1));
+        assert!(congested(INGRESS_LIMIT));
+        // No limit.
+        Congestion::set_ingress_limit(0);
+        assert!(!congested(usize::MAX));
+        Congestion::set_ingress_limit(INGRESS_LIMIT);
+
+        let socket = "127.0.0.98:1200".parse::<SocketAddr>().unwrap();
+        let total = Congestion::signalled_total();
+        let register = Congestion::signalled(MSG_TYPE_REGISTER);
+        Congestion::signal(&socket, MSG_TYPE_REGISTER);
+        Congestion::signal(&socket, MSG_TYPE_SUBSCRIBE);
+        Congestion::signal(&socket, MSG_TYPE_REGISTER);
+        assert_eq!(Congestion::signalled(MSG_TYPE_REGISTER), register + 2);
+        assert!(Congestion::signalled_total() >= total + 3);
+    }
+}
@@ -8,18 +8,20 @@ shown in Table 10:
 • Length and MsgType: see Section 5.2.
 • ReturnCode: encoded according to Table 5
 */
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use custom_debug::Debug;
 use getset::{CopyGetters, Getters, MutGetters /* Setters */};
 
 use crate::{
     broker_lib::MqttSnClient,
+    connection::Connection,
     eformat,
     function,
     msg_hdr::MsgHeader,
     retransmit::RetransTimeWheel,
     // flags::{flags_set, flag_qos_level, },
     MSG_LEN_CONNACK,
+    MSG_LEN_CONNACK_V2,
     MSG_TYPE_CONNACK,
 };
 
@@ -103,6 +105,26 @@ impl ConnAck {
         msg_header: MsgHeader,
         return_code: u8,
     ) -> Result<(), String> {
+        let remote_socket_addr = msg_header.remote_socket_addr;
+        if Connection::is_v2(&remote_socket_addr) {
+            let conn = match Connection::get(&remote_socket_addr) {
+                Some(conn) => conn,
+                None => return Err(eformat!(remote_socket_addr, "not found.")),
+            };
+            // Tell the client the client id assigned by the broker.
+            let assigned_client_id = if conn.client_id_assigned {
+                conn.client_id
+            } else {
+                Bytes::new()
+            };
+            return ConnAck::send_v2(
+                client,
+                msg_header,
+                Connection::return_code(&remote_socket_addr, return_code),
+                conn.session_expiry,
+                assigned_client_id,
+            );
+        }
         let connack = ConnAck {
             len: MSG_LEN_CONNACK,
             msg_type: MSG_TYPE_CONNACK,
@@ -121,4 +143,32 @@ impl ConnAck {
             Err(err) => Err(eformat!(msg_header.remote_socket_addr, err)),
         }
     }
+    /// MQTT-SN 2.0 CONNACK
+    /// Length    MsgType ReasonCode SessionExpiryInterval
+    /// (octet 0) (1)     (2)        (3-6)
+    /// AssignedClientId
+    /// (7:n), only if the broker assigned the client id.
+    pub fn send_v2(
+        client: &MqttSnClient,
+        msg_header: MsgHeader,
+        reason_code: u8,
+        session_expiry: u32,
+        assigned_client_id: Bytes,
+    ) -> Result<(), String> {
+        let remote_socket_addr = msg_header.remote_socket_addr;
+        let len = MSG_LEN_CONNACK_V2 as usize + assigned_client_id.len();
+        if len > u8::MAX as usize {
+            return Err(eformat!(remote_socket_addr, "len err", len));
+        }
+        let mut bytes_buf = BytesMut::with_capacity(len);
+        bytes_buf.put_u8(len as u8);
+        bytes_buf.put_u8(MSG_TYPE_CONNACK);
+        bytes_buf.put_u8(reason_code);
+        bytes_buf.put_u32(session_expiry);
+        bytes_buf.put(assigned_client_id);
+        match client.egress_tx.try_send((remote_socket_addr, bytes_buf)) {
+            Ok(()) => Ok(()),
+            Err(err) => Err(eformat!(remote_socket_addr, err)),
+        }
+    }
 }
@@ -33,20 +33,37 @@ use std::mem;
 use std::str;
 
 use crate::{
+    auth::Auth,
     broker_lib::MqttSnClient,
+    client_id::ClientId,
+    congestion::Congestion,
     conn_ack::ConnAck,
     connection::Connection,
     dbg_buf, eformat,
-    flags::flag_is_will,
+    flags::{flag_is_auth, flag_is_will},
     function,
     keep_alive::KeepAliveTimeWheel,
     msg_hdr::{MsgHeader, MsgHeaderLenEnum},
+    publish::Publish,
     retransmit::RetransTimeWheel,
     will_topic_req::WillTopicReq,
-    MSG_LEN_CONNECT_HEADER, MSG_TYPE_CONNACK, MSG_TYPE_CONNECT,
-    RETURN_CODE_ACCEPTED,
+    MSG_LEN_CONNECT_HEADER, MSG_LEN_CONNECT_V2_HEADER, MSG_TYPE_CONNACK,
+    MSG_TYPE_CONNECT, PROTOCOL_ID_V2_0, REASON_CODE_BAD_AUTHENTICATION_METHOD,
+    REASON_CODE_CLIENT_ID_NOT_VALID, REASON_CODE_SERVER_BUSY,
+    RETURN_CODE_ACCEPTED, RETURN_CODE_CONGESTION, RETURN_CODE_NOT_SUPPORTED,
 };
 
+/// The fields of a MQTT-SN 2.0 CONNECT, kept by Auth until the
+/// authentication ends.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectV2 {
+    pub flags: u8,
+    pub keep_alive: u16,
+    pub session_expiry: u32,
+    pub client_id: Bytes,
+    pub client_id_assigned: bool,
+}
+
 /// Connect and Connect4 are for sending CONNECT messages with different header lengths.
 #[derive(
     Debug, Clone, Getters, MutGetters, CopyGetters, Default, PartialEq,
@@ -120,7 +137,6 @@ impl Connect {
                 MSG_TYPE_CONNACK,
                 0,
                 0,
-                1,
                 bytes_buf,
             )?;
             return Ok(());
@@ -154,7 +170,6 @@ impl Connect {
                 MSG_TYPE_CONNACK,
                 0,
                 0,
-                1,
                 bytes_buf,
             )?;
             return Ok(());
@@ -171,6 +186,11 @@ impl Connect {
         msg_header: MsgHeader,
     ) -> Result<(), String> {
         dbg_buf!(buf, size);
+        // The ProtocolId follows the Flags.
+        let header_len = msg_header.header_len as usize;
+        if size > header_len + 1 && buf[header_len + 1] == PROTOCOL_ID_V2_0 {
+            return Connect::recv_v2(buf, size, client, msg_header);
+        }
         let (connect, _read_fixed_len) = match msg_header.header_len {
             MsgHeaderLenEnum::Short => Connect::try_read(buf, size).unwrap(),
             MsgHeaderLenEnum::Long => {
@@ -183,13 +203,40 @@ impl Connect {
         dbg!(&connect);
         // Create a new connection will messages and conn_ack messages.
         let remote_addr = msg_header.remote_socket_addr;
+        // Several clients with an empty client id would share the session.
+        let client_id_assigned = connect.client_id.is_empty();
+        let client_id = if client_id_assigned {
+            match ClientId::assign(connect.flags) {
+                Ok(client_id) => client_id,
+                Err(why) => {
+                    ConnAck::send(
+                        client,
+                        msg_header,
+                        RETURN_CODE_NOT_SUPPORTED,
+                    )?;
+                    return Err(eformat!(remote_addr, why));
+                }
+            }
+        } else {
+            connect.client_id
+        };
+        // The client waits T_WAIT before it connects again.
+        if Congestion::detect_connect(client, &remote_addr, &client_id) {
+            ConnAck::send(client, msg_header, RETURN_CODE_CONGESTION)?;
+            return Err(eformat!(remote_addr, "congestion"));
+        }
+        Connection::take_over(remote_addr, &client_id, client)?;
         Connection::try_insert(
             remote_addr,
             connect.flags,
             connect.protocol_id,
             connect.duration,
-            connect.client_id,
+            client_id,
         )?;
+        if client_id_assigned {
+            // MQTT-SN 1.2 has no way to tell the client its client id.
+            Connection::set_client_id_assigned(&remote_addr)?;
+        }
         KeepAliveTimeWheel::schedule(remote_addr, connect.duration)?;
         if flag_is_will(connect.flags) {
             // Client set the Will Flag, so the GW must send a Will Topic Request message.
@@ -197,6 +244,122 @@ impl Connect {
         } else {
             // Client did not set the Will Flag, so the GW must send a Connect Ack message.
             ConnAck::send(client, msg_header, RETURN_CODE_ACCEPTED)?;
+            Publish::send_offline_msgs(client, remote_addr);
+        }
+        Ok(())
+    }
+    /// MQTT-SN 2.0 CONNECT
+    /// Length MsgType Flags ProtocolId KeepAlive SessionExpiryInterval
+    /// (octet 0) (1)  (2)   (3)        (4,5)     (6-9)
+    /// MaximumPacketSize ClientId
+    /// (10,11)           (12:n)
+    /// The Will and CleanStart flags are in the same bits as 1.2, the
+    /// DefaultAwake and Auth flags in bits 1 and 0.
+    fn recv_v2(
+        buf: &[u8],
+        size: usize,
+        client: &MqttSnClient,
+        msg_header: MsgHeader,
+    ) -> Result<(), String> {
+        let remote_addr = msg_header.remote_socket_addr;
+        // Skip the 2 or 4 bytes message header.
+        let body = &buf[msg_header.header_len as usize..size];
+        let fixed_len = MSG_LEN_CONNECT_V2_HEADER as usize - 2;
+        if body.len() < fixed_len {
+            return Err(eformat!(remote_addr, "len err", size));
+        }
+        let flags = body[0];
+        let keep_alive = u16::from_be_bytes([body[2], body[3]]);
+        let session_expiry =
+            u32::from_be_bytes([body[4], body[5], body[6], body[7]]);
+        let _max_packet_size = u16::from_be_bytes([body[8], body[9]]);
+        let mut client_id = Bytes::copy_from_slice(&body[fixed_len..]);
+        // The assigned client id is returned in the CONNACK.
+        let client_id_assigned = client_id.is_empty();
+        if client_id_assigned {
+            client_id = match ClientId::assign(flags) {
+                Ok(client_id) => client_id,
+                Err(why) => {
+                    ConnAck::send_v2(
+                        client,
+                        msg_header,
+                        REASON_CODE_CLIENT_ID_NOT_VALID,
+                        0,
+                        Bytes::new(),
+                    )?;
+                    return Err(eformat!(remote_addr, why));
+                }
+            };
+        }
+        let connect = ConnectV2 {
+            flags,
+            keep_alive,
+            session_expiry,
+            client_id,
+            client_id_assigned,
+        };
+        if flag_is_auth(flags) {
+            // The client continues with AUTH, the CONNECT is accepted
+            // when the authentication succeeds, see Auth::recv().
+            return match Auth::insert_pending(remote_addr, connect) {
+                Ok(()) => Ok(()),
+                Err(why) => {
+                    ConnAck::send_v2(
+                        client,
+                        msg_header,
+                        REASON_CODE_BAD_AUTHENTICATION_METHOD,
+                        0,
+                        Bytes::new(),
+                    )?;
+                    Err(eformat!(remote_addr, why))
+                }
+            };
+        }
+        Connect::accept_v2(client, msg_header, connect)
+    }
+    /// Connect the MQTT-SN 2.0 client, after the authentication if the
+    /// CONNECT has the Auth flag.
+    pub fn accept_v2(
+        client: &MqttSnClient,
+        msg_header: MsgHeader,
+        connect: ConnectV2,
+    ) -> Result<(), String> {
+        let remote_addr = msg_header.remote_socket_addr;
+        let ConnectV2 {
+            flags,
+            keep_alive,
+            session_expiry,
+            client_id,
+            client_id_assigned,
+        } = connect;
+        if Congestion::detect_connect(client, &remote_addr, &client_id) {
+            ConnAck::send_v2(
+                client,
+                msg_header,
+                REASON_CODE_SERVER_BUSY,
+                0,
+                Bytes::new(),
+            )?;
+            return Err(eformat!(remote_addr, "congestion"));
+        }
+        Connection::take_over(remote_addr, &client_id, client)?;
+        Connection::try_insert(
+            remote_addr,
+            flags,
+            PROTOCOL_ID_V2_0,
+            keep_alive,
+            client_id,
+        )?;
+        Connection::update_session_expiry(&remote_addr, session_expiry)?;
+        if client_id_assigned {
+            Connection::set_client_id_assigned(&remote_addr)?;
+        }
+        KeepAliveTimeWheel::schedule(remote_addr, keep_alive)?;
+        if flag_is_will(flags) {
+            WillTopicReq::send(client, msg_header)?;
+        } else {
+            ConnAck::send(client, msg_header, RETURN_CODE_ACCEPTED)?;
+            Publish::send_offline_msgs(client, remote_addr);
         }
         Ok(())
     }
@@ -1,13 +1,23 @@
 use crate::{
-    broker_lib::MqttSnClient, client_id::ClientId, eformat, filter::*,
-    flags::*, function, publish::Publish, TopicIdType,
+    asleep_msg_cache::AsleepMsgCache, broker_lib::MqttSnClient,
+    client_id::ClientId, disconnect::Disconnect, eformat, encap_msg::EncapMsg,
+    filter::*, flags::*, function, in_flight::InFlight,
+    keep_alive::KeepAliveTimeWheel, offline_queue::OfflineQueue,
+    pub_msg_cache::PubMsgCache, publish::Publish, register::Register,
+    retransmit::RetransTimeWheel, rtt::Rtt, session_expiry::SessionExpiry,
+    session_store::SessionStore, TopicIdType, PROTOCOL_ID_V2_0,
+    REASON_CODE_IMPLEMENTATION_SPECIFIC_ERROR, REASON_CODE_SERVER_BUSY,
+    REASON_CODE_SESSION_TAKEN_OVER, REASON_CODE_SUCCESS,
+    REASON_CODE_TOPIC_NAME_INVALID, REASON_CODE_UNSPECIFIED_ERROR,
+    RETURN_CODE_ACCEPTED, RETURN_CODE_CONGESTION, RETURN_CODE_INVALID_TOPIC_ID,
+    RETURN_CODE_NOT_SUPPORTED,
 };
-// use log::*;
+use log::*;
 // use rand::Rng;
-use bisetmap::BisetMap;
 use bytes::{BufMut, Bytes, BytesMut};
 use hashbrown::HashMap;
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::{sync::Arc, sync::Mutex};
 use trace_caller::trace;
@@ -74,22 +84,28 @@ pub fn generate_conn_id(
     Ok(uuid)
 }
 
+// Publish the will of a session closed by a takeover.
+static TAKEOVER_WILL: AtomicBool = AtomicBool::new(false);
+// Next parked socket_addr, see Connection::parked_socket_addr().
+static PARKED: AtomicU64 = AtomicU64::new(1);
+
 lazy_static! {
-    // TODO add comments!!!
-    static ref CONN_HASHMAP: Mutex<HashMap<SocketAddr, Connection>> =
+    // The sessions are keyed by the client id, the socket_addr is an
+    // attribute of the session. ClientId maps the socket_addr of each
+    // session to its client id.
+    static ref CONN_HASHMAP: Mutex<HashMap<Bytes, Connection>> =
         Mutex::new(HashMap::new());
+}
 
-    // TODO: for connection migration, when the client has a new socket_addr,
-    //       use the ConnId to locate the connection.
-    static ref CONN_ID_BISET_MAP: Mutex<BisetMap<ConnId, SocketAddr>> =
-        Mutex::new(BisetMap::new());
+/// The client id of the session at the socket_addr.
+fn client_id_of(socket_addr: &SocketAddr) -> Option<Bytes> {
+    ClientId::rev_get(socket_addr).into_iter().next()
 }
 
-/// A connection is CURRENT network connection a client connects to the server.
-/// The filter is used to delete its global filters when the client disconnects
-/// or unsubscribes.
-/// In the future, the client might be able to connect to multiple servers and
-/// move to a different network connection.
+/// A connection is the session of a client with the server, keyed by the
+/// client id. The socket_addr is the CURRENT network address of the client,
+/// it's updated by rebind() when the client moves to a new address, and the
+/// subscriptions, in-flight messages and timers follow it.
 
 // TODO: remove later
 // #[allow(dead_code)]
@@ -100,10 +116,14 @@ pub struct Connection {
     pub protocol_id: u8,
     pub duration: u16,
     pub client_id: Bytes,
+    // The client connected with an empty client id.
+    pub client_id_assigned: bool,
     state: Arc<Mutex<StateEnum2>>,
     pub will_topic_id: Option<TopicIdType>,
     pub will_topic: Bytes, // *NOTE: this is a Bytes, not a BytesMut.
     pub will_message: Bytes,
+    // MQTT-SN 2.0, in seconds.
+    pub session_expiry: u32,
     // TODO pub sleep_msg_vec: Vec<Bytes>,
 }
 
@@ -145,10 +165,12 @@ impl Connection {
             protocol_id,
             duration,
             client_id: client_id.clone(),
+            client_id_assigned: false,
             state: Arc::new(Mutex::new(StateEnum2::ACTIVE)),
             will_topic_id: None,
             will_topic: Bytes::new(),
             will_message: Bytes::new(),
+            session_expiry: 0,
         }
     }
     pub fn try_insert(
@@ -158,16 +180,32 @@ impl Connection {
         duration: u16,
         client_id: Bytes,
     ) -> Result<(), String> {
+        match client_id_of(&socket_addr) {
+            Some(other_client_id) if other_client_id != client_id => {
+                // The address belongs to the session of another client.
+                return Err(eformat!(socket_addr, other_client_id, client_id));
+            }
+            _ => {}
+        }
+        // Topic ids registered by the gateway are only valid for the
+        // previous connection.
+        delete_registered_topic_ids(&socket_addr);
+        Register::delete_pending(&socket_addr);
+        // The session of the client doesn't expire while connected.
+        SessionExpiry::cancel(&client_id);
         if ClientId::contains(&client_id, &socket_addr) {
             // An existing client with same the socket_addr reconnects
             Connection::update_state(&socket_addr, StateEnum2::ACTIVE)?;
+            if let Some(conn) = CONN_HASHMAP.lock().unwrap().get_mut(&client_id)
+            {
+                conn.flags = flags;
+                conn.protocol_id = protocol_id;
+                conn.duration = duration;
+            }
             if flag_is_clean_session(flags) {
                 // Delete all subscriptions
-                let topic_id_vec =
-                    delete_topic_ids_with_socket_addr(&socket_addr);
-                for topic_id in topic_id_vec {
-                    let _qos = remove_qos(&topic_id, &socket_addr);
-                }
+                Connection::delete_session_state(&socket_addr);
+                OfflineQueue::delete(&client_id);
             }
             if flag_is_will(flags) {
                 // Delete will data, will_topic_id from the connection struct
@@ -178,63 +216,56 @@ impl Connection {
             }
             return Ok(());
         }
-        dbg!(&socket_addr);
-        // For existing client_id with different socket_addr or new client_id.
-        // Default values for a new will
-        let mut will_topic_id = None;
-        let mut will_topic = Bytes::new();
-        let mut will_message = Bytes::new();
-        // ClientId::get() should return one old_socket_addr, but the get() returns
-        // vec. Use for loop to traverse.
-        for old_socket_addr in ClientId::get(&client_id) {
-            // Existing client id with different socket_addr
-            // Possible client migration or restart.
-            dbg!(old_socket_addr);
-            // Move existing subscriptions for non-clean session
-            if !flag_is_clean_session(flags) {
-                // remove all the topic ids link to the old socket_addr
-                let topic_id_vec =
-                    delete_topic_ids_with_socket_addr(&old_socket_addr);
-                for topic_id in topic_id_vec {
-                    // remove each QoS entries
-                    let qos = remove_qos(&topic_id, &old_socket_addr).unwrap();
-                    // subscribe with new socket_addr
-                    let _result =
-                        subscribe_with_topic_id(socket_addr, topic_id, qos);
-                }
-            }
-            // copy will data for will flag == false
-            if !flag_is_will(flags) {
-                match CONN_HASHMAP.lock().unwrap().get(&old_socket_addr) {
-                    Some(conn) => {
-                        will_topic_id = conn.will_topic_id;
-                        will_topic = conn.will_topic.clone();
-                        will_message = conn.will_message.clone();
-                    }
-                    None => {
-                        return Err(eformat!(socket_addr, client_id));
+        if CONN_HASHMAP.lock().unwrap().contains_key(&client_id) {
+            // Existing client id with different socket_addr, the client
+            // moved or restarted.
+            if flag_is_clean_session(flags) {
+                // Start the new session with the old one deleted.
+                let old_conn = Connection::remove_with_client_id(&client_id)?;
+                Connection::delete_session_state(&old_conn.socket_addr);
+            } else {
+                // The session follows the client to the new address.
+                Connection::rebind(&client_id, socket_addr)?;
+                delete_registered_topic_ids(&socket_addr);
+                Register::delete_pending(&socket_addr);
+                let mut conn_hashmap = CONN_HASHMAP.lock().unwrap();
+                if let Some(conn) = conn_hashmap.get_mut(&client_id) {
+                    *conn.state.lock().unwrap() = StateEnum2::ACTIVE;
+                    conn.flags = flags;
+                    conn.protocol_id = protocol_id;
+                    conn.duration = duration;
+                    if flag_is_will(flags) {
+                        // The client is prompted for the new will.
+                        conn.will_topic_id = None;
+                        conn.will_topic = Bytes::new();
+                        conn.will_message = Bytes::new();
                     }
                 }
+                return Ok(());
             }
         }
-        // Initialize the connection with new socket_addr with
-        // existing or new client_id.
+        if flag_is_clean_session(flags) {
+            OfflineQueue::delete(&client_id);
+        }
+        // New session of the client.
         let conn = Connection {
             socket_addr,
             flags,
             protocol_id,
             duration,
             client_id: client_id.clone(),
+            client_id_assigned: false,
             state: Arc::new(Mutex::new(StateEnum2::ACTIVE)),
-            will_topic_id,
-            will_topic,
-            will_message,
+            will_topic_id: None,
+            will_topic: Bytes::new(),
+            will_message: Bytes::new(),
+            session_expiry: 0,
             // TODO  sleep_msg_vec: Vec::new(),
         };
-        dbg!(&conn);
-        ClientId::insert(client_id, socket_addr);
+        debug!("New session: {:?}", conn);
+        ClientId::insert(client_id.clone(), socket_addr);
         if let Err(why) =
-            CONN_HASHMAP.lock().unwrap().try_insert(socket_addr, conn)
+            CONN_HASHMAP.lock().unwrap().try_insert(client_id, conn)
         {
             return Err(eformat!(
                 socket_addr,
@@ -244,11 +275,218 @@ impl Connection {
         }
         Ok(())
     }
+    /// The session outlives the connection: CleanSession=false with
+    /// MQTT-SN 1.2, a non-zero Session Expiry Interval with MQTT-SN 2.0.
+    pub fn is_persistent(&self) -> bool {
+        if self.protocol_id == PROTOCOL_ID_V2_0 {
+            self.session_expiry > 0
+        } else {
+            !flag_is_clean_session(self.flags)
+        }
+    }
+    /// A MQTT-SN 2.0 client connected with the DefaultAwake flag never
+    /// sleeps, the 1.2 clients may always sleep.
+    pub fn is_default_awake(&self) -> bool {
+        self.protocol_id == PROTOCOL_ID_V2_0
+            && flag_is_default_awake(self.flags)
+    }
+    /// Delete a session at the end of its Session Expiry Interval, unless
+    /// the client connected again.
+    pub fn expire(client_id: &Bytes) -> Result<(), String> {
+        let conn = match Connection::get_with_client_id(client_id) {
+            Some(conn) => conn,
+            None => return Ok(()),
+        };
+        let state = conn.state.lock().unwrap().clone();
+        if !matches!(state, StateEnum2::DISCONNECTED | StateEnum2::LOST) {
+            return Ok(());
+        }
+        Connection::remove_with_client_id(client_id)?;
+        Connection::delete_session_state(&conn.socket_addr);
+        OfflineQueue::delete(client_id);
+        EncapMsg::remove_node(&conn.socket_addr);
+        SessionStore::delete(client_id)
+    }
+    /// Publish the will of the old session when a client takes over its
+    /// session from a new socket_addr, default is false.
+    pub fn set_takeover_will(publish_will: bool) {
+        TAKEOVER_WILL.store(publish_will, Ordering::Relaxed);
+    }
+    /// Close the sessions in the way of a CONNECT of the client_id from
+    /// the socket_addr, before try_insert() moves or deletes the state:
+    /// (1) The session of another client at the socket_addr, its
+    ///     transport is now used by the new client.
+    /// (2) The live session of the client_id at an old socket_addr, the
+    ///     old transport gets a DISCONNECT and its timers are cancelled.
+    /// The will of the closed session is only published if
+    /// set_takeover_will() is true.
+    pub fn take_over(
+        socket_addr: SocketAddr,
+        client_id: &Bytes,
+        client: &MqttSnClient,
+    ) -> Result<(), String> {
+        if let Some(other_client_id) = client_id_of(&socket_addr) {
+            if other_client_id == *client_id {
+                // Same client on the same socket_addr, a reconnect.
+                return Ok(());
+            }
+            debug!(
+                "Take over: {:?} {:?} {:?}",
+                socket_addr, other_client_id, client_id
+            );
+            Connection::close_taken_over(&socket_addr, client);
+            match Connection::get_with_client_id(&other_client_id) {
+                Some(other_conn) if other_conn.is_persistent() => {
+                    // Keep the session of the other client until it
+                    // connects again from its new socket_addr.
+                    Connection::update_state(
+                        &socket_addr,
+                        StateEnum2::DISCONNECTED,
+                    )?;
+                    let parked_addr = Connection::park(&other_client_id)?;
+                    SessionStore::save(&parked_addr)?;
+                    if other_conn.protocol_id == PROTOCOL_ID_V2_0 {
+                        SessionExpiry::schedule(
+                            other_client_id,
+                            other_conn.session_expiry,
+                        );
+                    }
+                }
+                _ => {
+                    Connection::remove_with_client_id(&other_client_id)?;
+                    Connection::delete_session_state(&socket_addr);
+                    OfflineQueue::delete(&other_client_id);
+                    SessionStore::delete(&other_client_id)?;
+                }
+            }
+        }
+        let old_conn = match Connection::get_with_client_id(client_id) {
+            Some(old_conn) => old_conn,
+            None => return Ok(()),
+        };
+        let old_socket_addr = old_conn.socket_addr;
+        debug!(
+            "Take over: {:?} {:?} {:?}",
+            client_id, old_socket_addr, socket_addr
+        );
+        let state = old_conn.state.lock().unwrap().clone();
+        if matches!(
+            state,
+            StateEnum2::ACTIVE | StateEnum2::ASLEEP | StateEnum2::AWAKE
+        ) {
+            Connection::close_taken_over(&old_socket_addr, client);
+            if old_conn.protocol_id == PROTOCOL_ID_V2_0 {
+                Disconnect::send_v2_to(
+                    client,
+                    old_socket_addr,
+                    REASON_CODE_SESSION_TAKEN_OVER,
+                )?;
+            } else {
+                Disconnect::send_to(client, old_socket_addr)?;
+            }
+        }
+        Connection::update_state(&old_socket_addr, StateEnum2::DISCONNECTED)
+    }
+    /// Publish the will per the takeover policy and cancel the keep alive
+    /// of the session at the socket_addr.
+    fn close_taken_over(socket_addr: &SocketAddr, client: &MqttSnClient) {
+        if TAKEOVER_WILL.load(Ordering::Relaxed) {
+            let _result = Connection::publish_will(socket_addr, client);
+        }
+        let _result = KeepAliveTimeWheel::cancel(socket_addr);
+    }
+    /// Move the session of the client to a new socket_addr. The
+    /// subscriptions, in-flight messages, caches and timers of the old
+    /// socket_addr follow the client.
+    pub fn rebind(
+        client_id: &Bytes,
+        new_socket_addr: SocketAddr,
+    ) -> Result<SocketAddr, String> {
+        let old_socket_addr = {
+            let mut conn_hashmap = CONN_HASHMAP.lock().unwrap();
+            match conn_hashmap.get_mut(client_id) {
+                Some(conn) => {
+                    let old_socket_addr = conn.socket_addr;
+                    conn.socket_addr = new_socket_addr;
+                    old_socket_addr
+                }
+                None => return Err(eformat!(new_socket_addr, client_id)),
+            }
+        };
+        if old_socket_addr == new_socket_addr {
+            return Ok(old_socket_addr);
+        }
+        debug!("Rebind: {:?} {:?}", old_socket_addr, new_socket_addr);
+        ClientId::rev_delete(&old_socket_addr);
+        ClientId::insert(client_id.clone(), new_socket_addr);
+        rebind_socket_addr(&old_socket_addr, new_socket_addr);
+        InFlight::rebind(&old_socket_addr, new_socket_addr);
+        Register::rebind(&old_socket_addr, new_socket_addr);
+        AsleepMsgCache::rebind(&old_socket_addr, new_socket_addr);
+        PubMsgCache::rebind(&old_socket_addr, new_socket_addr);
+        Rtt::rebind(&old_socket_addr, new_socket_addr);
+        KeepAliveTimeWheel::rebind(&old_socket_addr, new_socket_addr)?;
+        RetransTimeWheel::rebind(&old_socket_addr, new_socket_addr)?;
+        Ok(old_socket_addr)
+    }
+    /// An unused socket_addr in the discard prefix 100::/64 (RFC 6666)
+    /// for a session without a transport.
+    pub fn parked_socket_addr() -> SocketAddr {
+        loop {
+            let n = PARKED.fetch_add(1, Ordering::Relaxed);
+            let ip = Ipv6Addr::new(
+                0x100,
+                0,
+                0,
+                0,
+                (n >> 48) as u16,
+                (n >> 32) as u16,
+                (n >> 16) as u16,
+                n as u16,
+            );
+            let socket_addr = SocketAddr::new(IpAddr::V6(ip), 0);
+            if client_id_of(&socket_addr).is_none() {
+                return socket_addr;
+            }
+        }
+    }
+    /// Move the DISCONNECTED session of the client off the socket_addr
+    /// taken by another client. The session keeps its state at a parked
+    /// socket_addr, try_insert() moves it back when the client connects.
+    pub fn park(client_id: &Bytes) -> Result<SocketAddr, String> {
+        let parked_addr = Connection::parked_socket_addr();
+        let old_socket_addr = Connection::rebind(client_id, parked_addr)?;
+        // Nothing is sent to a parked session.
+        let _result = KeepAliveTimeWheel::cancel(&parked_addr);
+        RetransTimeWheel::cancel_addr(&parked_addr)?;
+        debug!(
+            "Park: {:?} {:?} {:?}",
+            client_id, old_socket_addr, parked_addr
+        );
+        Ok(parked_addr)
+    }
+    /// Delete the subscriptions and in-flight messages of a session.
+    fn delete_session_state(socket_addr: &SocketAddr) {
+        let topic_id_vec = delete_topic_ids_with_socket_addr(socket_addr);
+        for topic_id in topic_id_vec {
+            let _qos = remove_qos(&topic_id, socket_addr);
+        }
+        delete_filters_with_socket_addr(socket_addr);
+        delete_registered_topic_ids(socket_addr);
+        Register::delete_pending(socket_addr);
+        InFlight::delete(socket_addr);
+        let _msg_vec = AsleepMsgCache::delete(*socket_addr);
+        let _result = KeepAliveTimeWheel::cancel(socket_addr);
+        let _result = RetransTimeWheel::cancel_addr(socket_addr);
+        Rtt::delete(socket_addr);
+        PubMsgCache::delete_msg_ids(socket_addr);
+    }
     // TODO avoid lookup by using the connection struct.
     // use method on the Connection struct.
     pub fn get_state(socket_addr: &SocketAddr) -> Result<StateEnum2, String> {
+        let client_id = client_id_of(socket_addr).unwrap_or_default();
         let conn_hashmap = CONN_HASHMAP.lock().unwrap();
-        match conn_hashmap.get(socket_addr) {
+        match conn_hashmap.get(&client_id) {
             Some(conn) => {
                 let state = conn.state.lock().unwrap().clone();
                 Ok(state)
@@ -260,8 +498,9 @@ impl Connection {
         socket_addr: &SocketAddr,
         new_state: StateEnum2,
     ) -> Result<(), String> {
+        let client_id = client_id_of(socket_addr).unwrap_or_default();
         let mut conn_hashmap = CONN_HASHMAP.lock().unwrap();
-        match conn_hashmap.get_mut(socket_addr) {
+        match conn_hashmap.get_mut(&client_id) {
             Some(conn) => {
                 *conn.state.lock().unwrap() = new_state;
                 Ok(())
@@ -269,24 +508,139 @@ impl Connection {
             None => Err(eformat!(socket_addr, "state not found.")),
         }
     }
+    pub fn update_session_expiry(
+        socket_addr: &SocketAddr,
+        session_expiry: u32,
+    ) -> Result<(), String> {
+        let client_id = client_id_of(socket_addr).unwrap_or_default();
+        let mut conn_hashmap = CONN_HASHMAP.lock().unwrap();
+        match conn_hashmap.get_mut(&client_id) {
+            Some(conn) => {
+                conn.session_expiry = session_expiry;
+                Ok(())
+            }
+            None => Err(eformat!(socket_addr, "not found.")),
+        }
+    }
+    /// Mark the client id of the session as assigned by the broker.
+    pub fn set_client_id_assigned(
+        socket_addr: &SocketAddr,
+    ) -> Result<(), String> {
+        let client_id = client_id_of(socket_addr).unwrap_or_default();
+        let mut conn_hashmap = CONN_HASHMAP.lock().unwrap();
+        match conn_hashmap.get_mut(&client_id) {
+            Some(conn) => {
+                conn.client_id_assigned = true;
+                Ok(())
+            }
+            None => Err(eformat!(socket_addr, "not found.")),
+        }
+    }
+    pub fn get_session_expiry(socket_addr: &SocketAddr) -> Result<u32, String> {
+        match Connection::get(socket_addr) {
+            Some(conn) => Ok(conn.session_expiry),
+            None => Err(eformat!(socket_addr, "not found.")),
+        }
+    }
+    /// The client connected with MQTT-SN 2.0.
+    pub fn is_v2(socket_addr: &SocketAddr) -> bool {
+        match Connection::get(socket_addr) {
+            Some(conn) => conn.protocol_id == PROTOCOL_ID_V2_0,
+            None => false,
+        }
+    }
+    /// Map a MQTT-SN 1.2 return code to the MQTT-SN 2.0 reason code
+    /// for 2.0 clients, 1.2 clients get the return code unchanged.
+    pub fn return_code(socket_addr: &SocketAddr, return_code: u8) -> u8 {
+        if !Connection::is_v2(socket_addr) {
+            return return_code;
+        }
+        match return_code {
+            RETURN_CODE_ACCEPTED => REASON_CODE_SUCCESS,
+            RETURN_CODE_CONGESTION => REASON_CODE_SERVER_BUSY,
+            RETURN_CODE_INVALID_TOPIC_ID => REASON_CODE_TOPIC_NAME_INVALID,
+            RETURN_CODE_NOT_SUPPORTED => {
+                REASON_CODE_IMPLEMENTATION_SPECIFIC_ERROR
+            }
+            // already a reason code
+            _ if return_code >= REASON_CODE_UNSPECIFIED_ERROR => return_code,
+            _ => REASON_CODE_UNSPECIFIED_ERROR,
+        }
+    }
+    pub fn get(socket_addr: &SocketAddr) -> Option<Connection> {
+        let client_id = client_id_of(socket_addr)?;
+        CONN_HASHMAP.lock().unwrap().get(&client_id).cloned()
+    }
+    pub fn get_with_client_id(client_id: &Bytes) -> Option<Connection> {
+        CONN_HASHMAP.lock().unwrap().get(client_id).cloned()
+    }
+    /// Insert a session restored by the SessionStore, it's DISCONNECTED
+    /// until the client connects again.
+    pub fn insert_restored(mut conn: Connection) -> Result<(), String> {
+        conn.state = Arc::new(Mutex::new(StateEnum2::DISCONNECTED));
+        let socket_addr = conn.socket_addr;
+        if let Some(client_id) = client_id_of(&socket_addr) {
+            return Err(eformat!(socket_addr, client_id, "exists."));
+        }
+        ClientId::insert(conn.client_id.clone(), socket_addr);
+        if let Err(why) = CONN_HASHMAP
+            .lock()
+            .unwrap()
+            .try_insert(conn.client_id.clone(), conn)
+        {
+            return Err(eformat!(socket_addr, why.entry.key(), "exists."));
+        }
+        Ok(())
+    }
+    /// Number of sessions, including the DISCONNECTED and LOST ones.
+    pub fn len() -> usize {
+        CONN_HASHMAP.lock().unwrap().len()
+    }
+    /// The state of each session.
+    pub fn states() -> Vec<StateEnum2> {
+        CONN_HASHMAP
+            .lock()
+            .unwrap()
+            .values()
+            .map(|conn| conn.state.lock().unwrap().clone())
+            .collect()
+    }
     pub fn contains_key(socket_addr: SocketAddr) -> bool {
-        CONN_HASHMAP.lock().unwrap().contains_key(&socket_addr)
+        match client_id_of(&socket_addr) {
+            Some(client_id) => {
+                CONN_HASHMAP.lock().unwrap().contains_key(&client_id)
+            }
+            None => false,
+        }
     }
+    /// Remove the session at the socket_addr.
     #[trace]
     pub fn remove(socket_addr: &SocketAddr) -> Result<Connection, String> {
-        let mut conn_hashmap = CONN_HASHMAP.lock().unwrap();
-        match conn_hashmap.remove(socket_addr) {
-            Some(val) => Ok(val),
+        match client_id_of(socket_addr) {
+            Some(client_id) => Connection::remove_with_client_id(&client_id),
             None => Err(eformat!(socket_addr, "not found.")),
         }
     }
+    pub fn remove_with_client_id(
+        client_id: &Bytes,
+    ) -> Result<Connection, String> {
+        let mut conn_hashmap = CONN_HASHMAP.lock().unwrap();
+        match conn_hashmap.remove(client_id) {
+            Some(val) => {
+                ClientId::delete(client_id);
+                Ok(val)
+            }
+            None => Err(eformat!(client_id, "not found.")),
+        }
+    }
     // Update will topic to an existing connection
     pub fn update_will_topic(
         socket_addr: SocketAddr,
         topic: String,
     ) -> Result<(), String> {
+        let client_id = client_id_of(&socket_addr).unwrap_or_default();
         let mut conn_hashmap = CONN_HASHMAP.lock().unwrap();
-        match conn_hashmap.get_mut(&socket_addr) {
+        match conn_hashmap.get_mut(&client_id) {
             Some(conn) => {
                 conn.will_topic = Bytes::from(topic.clone());
                 let topic_id = try_insert_topic_name(topic)?;
@@ -300,8 +654,9 @@ impl Connection {
         socket_addr: SocketAddr,
         message: String,
     ) -> Result<(), String> {
+        let client_id = client_id_of(&socket_addr).unwrap_or_default();
         let mut conn_hashmap = CONN_HASHMAP.lock().unwrap();
-        match conn_hashmap.get_mut(&socket_addr) {
+        match conn_hashmap.get_mut(&client_id) {
             Some(conn) => {
                 conn.will_message = Bytes::from(message);
                 Ok(())
@@ -312,8 +667,9 @@ impl Connection {
     pub fn delete_will_topic_id(
         socket_addr: &SocketAddr,
     ) -> Result<TopicIdType, String> {
+        let client_id = client_id_of(socket_addr).unwrap_or_default();
         let mut conn_hashmap = CONN_HASHMAP.lock().unwrap();
-        match conn_hashmap.get_mut(socket_addr) {
+        match conn_hashmap.get_mut(&client_id) {
             Some(conn) => {
                 let topic_id = conn.will_topic_id;
                 conn.will_topic_id = None;
@@ -326,35 +682,34 @@ impl Connection {
         socket_addr: &SocketAddr,
         client: &MqttSnClient,
     ) -> Result<(), String> {
-        let mut conn_hashmap = CONN_HASHMAP.lock().unwrap();
-        match conn_hashmap.get_mut(socket_addr) {
-            Some(conn) => {
-                // let topic_id = conn.will_topic_id;
-                if let Some(topic_id) = conn.will_topic_id {
-                    let subscriber_vec =
-                        get_subscribers_with_topic_id(topic_id);
-                    for subscriber in subscriber_vec {
-                        // Can't return error, because not all subscribers will have error.
-                        // TODO error for every subscriber/message
-                        // TODO use Bytes not BytesMut to eliminate clone/copy.
-                        // TODO new tx method to reduce have try_write() run once for every subscriber.
-                        let mut msg = BytesMut::new();
-                        msg.put(conn.will_message.clone()); // TODO replace BytesMut with Bytes because clone doesn't copy data in Bytes
-                        let _result = Publish::send(
-                            topic_id,
-                            0, // TODO what is the msg_id?
-                            subscriber.qos,
-                            RETAIN_FALSE,
-                            msg.freeze(),
-                            client,
-                            subscriber.socket_addr,
-                        );
-                    }
-                }
-                return Ok(());
+        let client_id = client_id_of(socket_addr).unwrap_or_default();
+        // The connections are unlocked before the subscribers are looked up,
+        // the shared subscriptions read the state of their members.
+        let (will_topic_id, will_message) =
+            match CONN_HASHMAP.lock().unwrap().get(&client_id) {
+                Some(conn) => (conn.will_topic_id, conn.will_message.clone()),
+                None => return Err(eformat!(socket_addr, "not found.")),
+            };
+        if let Some(topic_id) = will_topic_id {
+            let subscriber_vec = get_subscribers_with_topic_id(topic_id);
+            for subscriber in subscriber_vec {
+                // Can't return error, because not all subscribers will have error.
+                // TODO error for every subscriber/message
+                // TODO use Bytes not BytesMut to eliminate clone/copy.
+                // TODO new tx method to reduce have try_write() run once for every subscriber.
+                let mut msg = BytesMut::new();
+                msg.put(will_message.clone()); // TODO replace BytesMut with Bytes because clone doesn't copy data in Bytes
+                let _result = Publish::send(
+                    topic_id,
+                    subscriber.qos,
+                    RETAIN_FALSE,
+                    msg.freeze(),
+                    client,
+                    subscriber.socket_addr,
+                );
             }
-            None => Err(eformat!(socket_addr, "not found.")),
         }
+        Ok(())
     }
     #[allow(unused_must_use)]
     pub fn debug() {
@@ -442,4 +797,187 @@ mod test {
         assert!(id.is_ok());
         dbg!(id);
     }
+    #[test]
+    fn test_return_code() {
+        use super::{Connection, StateEnum2};
+        use crate::flags::DEFAULT_AWAKE_TRUE;
+        use crate::{PROTOCOL_ID_V1_2, PROTOCOL_ID_V2_0};
+        use bytes::Bytes;
+        use std::net::SocketAddr;
+
+        let v1 = "127.0.0.71:1200".parse::<SocketAddr>().unwrap();
+        let v2 = "127.0.0.72:1200".parse::<SocketAddr>().unwrap();
+        Connection::try_insert(
+            v1,
+            0,
+            PROTOCOL_ID_V1_2,
+            30,
+            Bytes::from_static(b"rc_v1"),
+        )
+        .unwrap();
+        Connection::try_insert(
+            v2,
+            0,
+            PROTOCOL_ID_V2_0,
+            30,
+            Bytes::from_static(b"rc_v2"),
+        )
+        .unwrap();
+        assert!(!Connection::is_v2(&v1));
+        assert!(Connection::is_v2(&v2));
+        // 1.2 clients get the return codes unchanged.
+        assert_eq!(Connection::return_code(&v1, 2), 2);
+        // Accepted, congestion, invalid topic id and not supported.
+        assert_eq!(Connection::return_code(&v2, 0), 0x00);
+        assert_eq!(Connection::return_code(&v2, 1), 0x89);
+        assert_eq!(Connection::return_code(&v2, 2), 0x90);
+        assert_eq!(Connection::return_code(&v2, 3), 0x83);
+        // Only a 2.0 client has the DefaultAwake flag.
+        assert!(!Connection::get(&v2).unwrap().is_default_awake());
+        let mut conn = Connection::get(&v2).unwrap();
+        conn.flags = DEFAULT_AWAKE_TRUE;
+        assert!(conn.is_default_awake());
+        conn.protocol_id = PROTOCOL_ID_V1_2;
+        assert!(!conn.is_default_awake());
+        // The 2.0 session outlives the connection with an expiry.
+        assert!(!Connection::get(&v2).unwrap().is_persistent());
+        Connection::update_session_expiry(&v2, 3600).unwrap();
+        assert_eq!(Connection::get_session_expiry(&v2), Ok(3600));
+        assert!(Connection::get(&v2).unwrap().is_persistent());
+        // Only a disconnected session expires.
+        let client_id = Bytes::from_static(b"rc_v2");
+        Connection::expire(&client_id).unwrap();
+        assert!(Connection::get(&v2).is_some());
+        Connection::update_state(&v2, StateEnum2::DISCONNECTED).unwrap();
+        Connection::expire(&client_id).unwrap();
+        assert!(Connection::get(&v2).is_none());
+    }
+    #[test]
+    fn test_rebind() {
+        use super::Connection;
+        use crate::filter::{
+            get_topic_ids_with_socket_addr, subscribe_with_topic_id,
+        };
+        use crate::flags::CLEAN_SESSION_TRUE;
+        use crate::in_flight::InFlight;
+        use crate::{MSG_TYPE_PUBLISH, PROTOCOL_ID_V1_2};
+        use bytes::Bytes;
+        use std::net::SocketAddr;
+
+        let old = "127.0.0.81:1200".parse::<SocketAddr>().unwrap();
+        let new = "127.0.0.82:1200".parse::<SocketAddr>().unwrap();
+        let other = "127.0.0.83:1200".parse::<SocketAddr>().unwrap();
+        let client_id = Bytes::from_static(b"rebind");
+        Connection::try_insert(old, 0, PROTOCOL_ID_V1_2, 30, client_id.clone())
+            .unwrap();
+        subscribe_with_topic_id(old, 81, 0).unwrap();
+        let msg_id = InFlight::alloc(old, MSG_TYPE_PUBLISH).unwrap();
+
+        // CleanSession=false from a new address, the session follows.
+        Connection::try_insert(new, 0, PROTOCOL_ID_V1_2, 30, client_id.clone())
+            .unwrap();
+        assert!(!Connection::contains_key(old));
+        assert_eq!(Connection::get(&new).unwrap().socket_addr, new);
+        assert!(get_topic_ids_with_socket_addr(&old).is_empty());
+        assert_eq!(get_topic_ids_with_socket_addr(&new), vec![(81, 0)]);
+        assert!(InFlight::contains(&new, msg_id));
+
+        // The address belongs to another client.
+        assert!(Connection::try_insert(
+            new,
+            0,
+            PROTOCOL_ID_V1_2,
+            30,
+            Bytes::from_static(b"rebind2")
+        )
+        .is_err());
+
+        // CleanSession=true deletes the session at the old address.
+        Connection::try_insert(
+            other,
+            CLEAN_SESSION_TRUE,
+            PROTOCOL_ID_V1_2,
+            30,
+            client_id,
+        )
+        .unwrap();
+        assert!(!Connection::contains_key(new));
+        assert!(get_topic_ids_with_socket_addr(&new).is_empty());
+        assert!(get_topic_ids_with_socket_addr(&other).is_empty());
+    }
+    #[test]
+    fn test_take_over() {
+        use super::{Connection, StateEnum2};
+        use crate::broker_lib::MqttSnClient;
+        use crate::filter::{
+            get_topic_ids_with_socket_addr, subscribe_with_topic_id,
+        };
+        use crate::flags::CLEAN_SESSION_TRUE;
+        use crate::{MSG_TYPE_DISCONNECT, PROTOCOL_ID_V1_2};
+        use bytes::Bytes;
+        use std::net::SocketAddr;
+
+        let client = MqttSnClient::new();
+        let old = "127.0.0.91:1200".parse::<SocketAddr>().unwrap();
+        let new = "127.0.0.92:1200".parse::<SocketAddr>().unwrap();
+        let client_id = Bytes::from_static(b"take_over");
+        let client_id2 = Bytes::from_static(b"take_over2");
+        Connection::try_insert(old, 0, PROTOCOL_ID_V1_2, 30, client_id.clone())
+            .unwrap();
+
+        // The live session at the old address gets a DISCONNECT.
+        Connection::take_over(new, &client_id, &client).unwrap();
+        let (addr, bytes) = client.egress_rx.try_recv().unwrap();
+        assert_eq!(addr, old);
+        assert_eq!(bytes[1], MSG_TYPE_DISCONNECT);
+        assert!(matches!(
+            Connection::get_state(&old),
+            Ok(StateEnum2::DISCONNECTED)
+        ));
+        Connection::try_insert(new, 0, PROTOCOL_ID_V1_2, 30, client_id.clone())
+            .unwrap();
+        assert!(matches!(
+            Connection::get_state(&new),
+            Ok(StateEnum2::ACTIVE)
+        ));
+
+        // A reconnect on the same address doesn't close the session.
+        Connection::take_over(new, &client_id, &client).unwrap();
+        assert!(client.egress_rx.try_recv().is_err());
+
+        // Another client on the address parks the persistent session.
+        subscribe_with_topic_id(new, 91, 0).unwrap();
+        Connection::take_over(new, &client_id2, &client).unwrap();
+        let parked = Connection::get_with_client_id(&client_id).unwrap();
+        assert_ne!(parked.socket_addr, new);
+        assert!(matches!(
+            *parked.state.lock().unwrap(),
+            StateEnum2::DISCONNECTED
+        ));
+        assert!(get_topic_ids_with_socket_addr(&new).is_empty());
+        assert_eq!(
+            get_topic_ids_with_socket_addr(&parked.socket_addr),
+            vec![(91, 0)]
+        );
+        Connection::try_insert(
+            new,
+            CLEAN_SESSION_TRUE,
+            PROTOCOL_ID_V1_2,
+            30,
+            client_id2.clone(),
+        )
+        .unwrap();
+
+        // Another client on the address closes the clean session.
+        let client_id3 = Bytes::from_static(b"take_over3");
+        Connection::take_over(new, &client_id3, &client).unwrap();
+        assert!(Connection::get_with_client_id(&client_id2).is_none());
+        Connection::try_insert(new, 0, PROTOCOL_ID_V1_2, 30, client_id3)
+            .unwrap();
+
+        // The parked session follows the client to its new address.
+        Connection::try_insert(old, 0, PROTOCOL_ID_V1_2, 30, client_id)
+            .unwrap();
+        assert_eq!(get_topic_ids_with_socket_addr(&old), vec![(91, 0)]);
+    }
 }
@@ -21,24 +21,36 @@ a duration field).
 use bytes::{BufMut, BytesMut};
 use custom_debug::Debug;
 use getset::{CopyGetters, Getters, MutGetters};
+use log::*;
 use std::mem;
+use std::net::SocketAddr;
 
 use crate::{
     broker_lib::MqttSnClient,
-    client_id::ClientId,
     connection::Connection,
     connection::StateEnum2,
     eformat,
-    filter::get_subscribers_with_topic_id,
-    flags::RETAIN_FALSE,
+    filter::{
+        delete_filters_with_socket_addr, delete_topic_ids_with_socket_addr,
+        get_subscribers_with_topic_id, remove_qos,
+    },
+    flags::{flag_is_clean_session, RETAIN_FALSE},
     function,
     keep_alive::KeepAliveTimeWheel,
     msg_hdr::MsgHeader,
+    offline_queue::OfflineQueue,
     publish::Publish,
+    session_expiry::SessionExpiry,
+    session_store::SessionStore,
     MSG_LEN_DISCONNECT,
     MSG_LEN_DISCONNECT_DURATION,
+    MSG_LEN_DISCONNECT_V2_EXPIRY,
+    MSG_LEN_DISCONNECT_V2_REASON,
     // flags::{flags_set, flag_qos_level, },
     MSG_TYPE_DISCONNECT,
+    REASON_CODE_DISCONNECT_WITH_WILL,
+    REASON_CODE_PROTOCOL_ERROR,
+    REASON_CODE_SUCCESS,
 };
 
 #[derive(
@@ -81,6 +93,9 @@ impl Disconnect {
         msg_header: MsgHeader,
     ) -> Result<(), String> {
         let remote_addr = msg_header.remote_socket_addr;
+        if Connection::is_v2(&remote_addr) {
+            return Disconnect::recv_v2(buf, size, client, msg_header);
+        }
         if size == MSG_LEN_DISCONNECT as usize {
             let (disconnect, _read_len) =
                 Disconnect::try_read(buf, size).unwrap();
@@ -94,9 +109,19 @@ impl Disconnect {
                 },
                 Err(why) => return Err(eformat!(why, &remote_addr)),
             }
-            let conn = Connection::remove(&remote_addr)?;
-            ClientId::rev_delete(&remote_addr);
             KeepAliveTimeWheel::cancel(&remote_addr)?;
+            let conn = match Connection::get(&remote_addr) {
+                Some(conn) if !flag_is_clean_session(conn.flags) => {
+                    // Keep the persistent session, the messages are queued
+                    // until the client connects again.
+                    Connection::update_state(
+                        &remote_addr,
+                        StateEnum2::DISCONNECTED,
+                    )?;
+                    conn
+                }
+                _ => Connection::remove(&remote_addr)?,
+            };
             Connection::debug();
             Disconnect::send(client, msg_header)?;
             if publish_will == false {
@@ -113,7 +138,6 @@ impl Disconnect {
                     msg.put(conn.will_message.clone()); // TODO replace BytesMut with Bytes because clone doesn't copy data in Bytes
                     let _result = Publish::send(
                         topic_id,
-                        0, // TODO what is the msg_id?
                         subscriber.qos,
                         RETAIN_FALSE,
                         msg.freeze(),
@@ -137,15 +161,152 @@ impl Disconnect {
         }
     }
 
+    /// MQTT-SN 2.0 DISCONNECT
+    /// Length MsgType ReasonCode SessionExpiryInterval ReasonString
+    /// (octet 0) (1)  (2)        (3-6)                 (7:n)
+    /// The fields after the MsgType are optional.
+    /// The SessionExpiryInterval replaces the one of the CONNECT, a
+    /// non-zero interval keeps the session DISCONNECTED until the client
+    /// connects again or the interval ends, see SessionExpiry.
+    /// A sleeping client sends the 4 octet form with the sleep Duration
+    /// in octets 2-3, like MQTT-SN 1.2. A client connected with the
+    /// DefaultAwake flag can't sleep, its connection is closed instead.
+    /// The will is only published with the "Disconnect with Will Message"
+    /// reason code.
+    fn recv_v2(
+        buf: &[u8],
+        size: usize,
+        client: &MqttSnClient,
+        msg_header: MsgHeader,
+    ) -> Result<(), String> {
+        let remote_addr = msg_header.remote_socket_addr;
+        if size < MSG_LEN_DISCONNECT as usize {
+            return Err(eformat!(remote_addr, "len err", size));
+        }
+        // The reason code of the DISCONNECT sent back.
+        let mut ack_code = REASON_CODE_SUCCESS;
+        let (reason_code, session_expiry) = if size
+            == MSG_LEN_DISCONNECT_DURATION as usize
+        {
+            let duration = u16::from_be_bytes([buf[2], buf[3]]);
+            let conn = match Connection::get(&remote_addr) {
+                Some(conn) => conn,
+                None => return Err(eformat!(remote_addr, "not connected")),
+            };
+            if !conn.is_default_awake() {
+                debug!("Disconnect to sleep: {:?} {}", remote_addr, duration);
+                Connection::update_state(&remote_addr, StateEnum2::ASLEEP)?;
+                KeepAliveTimeWheel::schedule(remote_addr, duration)?;
+                return Disconnect::send_v2(
+                    client,
+                    msg_header,
+                    REASON_CODE_SUCCESS,
+                );
+            }
+            // The client connected with DefaultAwake never sleeps, the
+            // connection is closed with a protocol error.
+            ack_code = REASON_CODE_PROTOCOL_ERROR;
+            (REASON_CODE_SUCCESS, conn.session_expiry)
+        } else {
+            let reason_code = if size >= MSG_LEN_DISCONNECT_V2_REASON as usize {
+                buf[2]
+            } else {
+                REASON_CODE_SUCCESS
+            };
+            let session_expiry =
+                if size >= MSG_LEN_DISCONNECT_V2_EXPIRY as usize {
+                    u32::from_be_bytes([buf[3], buf[4], buf[5], buf[6]])
+                } else {
+                    Connection::get_session_expiry(&remote_addr)?
+                };
+            (reason_code, session_expiry)
+        };
+        debug!(
+            "Disconnect: {:?} {:#04x} {}",
+            remote_addr, reason_code, session_expiry
+        );
+        if reason_code == REASON_CODE_DISCONNECT_WITH_WILL
+            && matches!(
+                Connection::get_state(&remote_addr)?,
+                StateEnum2::ACTIVE
+            )
+        {
+            Connection::publish_will(&remote_addr, client)?;
+        }
+        KeepAliveTimeWheel::cancel(&remote_addr)?;
+        if session_expiry > 0 {
+            // Keep the session, the messages are queued until the client
+            // connects again or the session expires.
+            Connection::update_state(&remote_addr, StateEnum2::DISCONNECTED)?;
+            Connection::update_session_expiry(&remote_addr, session_expiry)?;
+            let conn = match Connection::get(&remote_addr) {
+                Some(conn) => conn,
+                None => return Err(eformat!(remote_addr, "not connected")),
+            };
+            SessionExpiry::schedule(conn.client_id, session_expiry);
+            SessionStore::save(&remote_addr)?;
+            return Disconnect::send_v2(client, msg_header, ack_code);
+        }
+        // The session ends with the connection.
+        let conn = Connection::remove(&remote_addr)?;
+        for topic_id in delete_topic_ids_with_socket_addr(&remote_addr) {
+            let _qos = remove_qos(&topic_id, &remote_addr);
+        }
+        delete_filters_with_socket_addr(&remote_addr);
+        SessionStore::delete(&conn.client_id)?;
+        OfflineQueue::delete(&conn.client_id);
+        Disconnect::send_v2(client, msg_header, ack_code)
+    }
+
+    /// MQTT-SN 2.0 DISCONNECT with a reason code.
+    pub fn send_v2(
+        client: &MqttSnClient,
+        msg_header: MsgHeader,
+        reason_code: u8,
+    ) -> Result<(), String> {
+        Disconnect::send_v2_to(
+            client,
+            msg_header.remote_socket_addr,
+            reason_code,
+        )
+    }
+
+    /// The gateway closes the connection of the client at remote_addr.
+    pub fn send_v2_to(
+        client: &MqttSnClient,
+        remote_addr: SocketAddr,
+        reason_code: u8,
+    ) -> Result<(), String> {
+        let mut bytes_buf =
+            BytesMut::with_capacity(MSG_LEN_DISCONNECT_V2_REASON as usize);
+        bytes_buf.put_u8(MSG_LEN_DISCONNECT_V2_REASON);
+        bytes_buf.put_u8(MSG_TYPE_DISCONNECT);
+        bytes_buf.put_u8(reason_code);
+        match client
+            .egress_tx
+            .try_send((remote_addr, bytes_buf.to_owned()))
+        {
+            Ok(()) => Ok(()),
+            Err(err) => Err(eformat!(remote_addr, err)),
+        }
+    }
+
     pub fn send(
         client: &MqttSnClient,
         msg_header: MsgHeader,
+    ) -> Result<(), String> {
+        Disconnect::send_to(client, msg_header.remote_socket_addr)
+    }
+
+    /// The gateway closes the connection of the client at remote_addr.
+    pub fn send_to(
+        client: &MqttSnClient,
+        remote_addr: SocketAddr,
     ) -> Result<(), String> {
         let disconnect = Disconnect {
             len: MSG_LEN_DISCONNECT as u8,
             msg_type: MSG_TYPE_DISCONNECT,
         };
-        let remote_addr = msg_header.remote_socket_addr;
         let mut bytes_buf =
             BytesMut::with_capacity(MSG_LEN_DISCONNECT as usize);
         dbg!(disconnect.clone());
@@ -0,0 +1,239 @@
+/*
+5.5 Forwarder Encapsulation
+Length    MsgType Ctrl Wireless Node Id MQTT-SN message
+(octet 0) (1)     (2)  (3:n)            (n+1:m)
+Table 25: Encapsulated MQTT-SN Frame
+• Length: 1-octet long, specifies the number of octets up to the end of the “Wireless Node Id” field (incl. the
+Length octet itself)
+• MsgType: coded “0xFE”, see Table 3.
+• Ctrl: The Ctrl octet contains control information exchanged between the GW and the forwarder. Its format
+is shown in Table 26:
+– Radius: broadcast radius (only relevant in direction GW to forwarder)
+– All remaining bits are reserved
+• Wireless Node Id: identifies the wireless node which has sent or should receive the encapsulated MQTT-SN
+message. The coding of this Id depends on the actual wireless network, e.g. it may be a 16-bit or 64-bit
+address, or a user-defined string.
+• MQTT-SN message: the MQTT-SN message, coded according to Table 1.
+*/
+
+//! Each wireless node behind a forwarder is a client session of its own.
+//! The (forwarder socket_addr, wireless node id) pair is mapped to a node
+//! socket_addr, [fd00::<n>]:0, used as the session key by the rest of the
+//! broker. Port 0 is never the port of a real client.
+//! Ingress messages are unwrapped to the node socket_addr, egress messages
+//! to the node socket_addr are wrapped and sent to the forwarder.
+//! Only a CONNECT maps a new node, up to the node limit of the forwarder.
+//! The mapping is removed when the node has no session, see remove_node().
+
+use bytes::{BufMut, Bytes, BytesMut};
+use hashbrown::HashMap;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::{
+    eformat, function, MsgLenConst, MSG_LEN_ENCAP_MSG_HEADER, MSG_TYPE_CONNECT,
+    MSG_TYPE_ENCAP_MSG,
+};
+
+/// Ctrl octet: the broadcast radius, the other bits are reserved.
+pub const CTRL_RADIUS_MASK: u8 = 0b_0000_0011;
+
+/// Default maximum number of wireless nodes mapped behind a forwarder.
+pub const FORWARDER_NODE_LIMIT: usize = 1024;
+
+static NODE_COUNTER: AtomicU32 = AtomicU32::new(1);
+static MAX_NODES: AtomicUsize = AtomicUsize::new(FORWARDER_NODE_LIMIT);
+
+lazy_static! {
+    static ref NODE_TO_SOCKET_ADDR: Mutex<HashMap<(SocketAddr, Bytes), SocketAddr>> =
+        Mutex::new(HashMap::new());
+    static ref SOCKET_ADDR_TO_NODE: Mutex<HashMap<SocketAddr, (SocketAddr, Bytes)>> =
+        Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone)]
+pub struct EncapMsg {}
+
+impl EncapMsg {
+    pub fn set_node_limit(limit: usize) {
+        MAX_NODES.store(limit, Ordering::Relaxed);
+    }
+    /// Returns true if the buffer is an encapsulated message.
+    /// The Length field is 1 octet, so no 4 byte header.
+    pub fn is_encap_msg(buf: &[u8]) -> bool {
+        buf.len() >= MSG_LEN_ENCAP_MSG_HEADER as usize
+            && buf[0] >= MSG_LEN_ENCAP_MSG_HEADER
+            && buf[1] == MSG_TYPE_ENCAP_MSG
+    }
+    /// Unwrap an encapsulated message from a forwarder.
+    /// Returns the node socket_addr and the MQTT-SN message.
+    pub fn unwrap(
+        forwarder_addr: SocketAddr,
+        bytes: &Bytes,
+    ) -> Result<(SocketAddr, Bytes), String> {
+        if !EncapMsg::is_encap_msg(bytes) {
+            return Err(eformat!(forwarder_addr, "not encapsulated"));
+        }
+        let header_len = bytes[0] as usize;
+        if bytes.len() <= header_len {
+            return Err(eformat!(forwarder_addr, "len err", bytes.len()));
+        }
+        // The radius is only relevant from the gateway to the forwarder.
+        let ctrl = bytes[2];
+        if ctrl & !CTRL_RADIUS_MASK != 0 {
+            return Err(eformat!(forwarder_addr, "reserved Ctrl bits", ctrl));
+        }
+        let node_id =
+            bytes.slice(MSG_LEN_ENCAP_MSG_HEADER as usize..header_len);
+        let msg = bytes.slice(header_len..);
+        let node_addr = match EncapMsg::msg_type(&msg) {
+            Some(MSG_TYPE_CONNECT) => {
+                let limit = MAX_NODES.load(Ordering::Relaxed);
+                EncapMsg::get_or_insert_node(forwarder_addr, node_id, limit)?
+            }
+            _ => match NODE_TO_SOCKET_ADDR
+                .lock()
+                .unwrap()
+                .get(&(forwarder_addr, node_id))
+            {
+                Some(node_addr) => *node_addr,
+                None => return Err(eformat!(forwarder_addr, "unknown node")),
+            },
+        };
+        Ok((node_addr, msg))
+    }
+    /// The MsgType of a MQTT-SN message with a short or long header.
+    fn msg_type(msg: &[u8]) -> Option<u8> {
+        match msg.first()? {
+            0x01 => msg.get(3).copied(),
+            _ => msg.get(1).copied(),
+        }
+    }
+    /// Wrap a message to a node socket_addr for its forwarder.
+    /// Messages to other socket_addr are returned as is.
+    pub fn wrap(
+        socket_addr: SocketAddr,
+        bytes: BytesMut,
+    ) -> Result<(SocketAddr, BytesMut), String> {
+        let (forwarder_addr, node_id) = match EncapMsg::get_node(&socket_addr) {
+            Some(node) => node,
+            None => return Ok((socket_addr, bytes)),
+        };
+        let header_len = MSG_LEN_ENCAP_MSG_HEADER as usize + node_id.len();
+        if header_len > MsgLenConst::MAX as usize {
+            return Err(eformat!(socket_addr, "node id too long", header_len));
+        }
+        let mut buf = BytesMut::with_capacity(header_len + bytes.len());
+        buf.put_u8(header_len as u8);
+        buf.put_u8(MSG_TYPE_ENCAP_MSG);
+        // Ctrl: radius is only for broadcast, 0 for unicast.
+        buf.put_u8(0);
+        buf.put(node_id);
+        buf.put(bytes);
+        Ok((forwarder_addr, buf))
+    }
+    /// Get the (forwarder socket_addr, wireless node id) of a node
+    /// socket_addr.
+    pub fn get_node(socket_addr: &SocketAddr) -> Option<(SocketAddr, Bytes)> {
+        SOCKET_ADDR_TO_NODE
+            .lock()
+            .unwrap()
+            .get(socket_addr)
+            .cloned()
+    }
+    /// Remove the mapping of a node socket_addr without a session.
+    pub fn remove_node(socket_addr: &SocketAddr) {
+        let mut node_map = NODE_TO_SOCKET_ADDR.lock().unwrap();
+        if let Some(key) =
+            SOCKET_ADDR_TO_NODE.lock().unwrap().remove(socket_addr)
+        {
+            node_map.remove(&key);
+        }
+    }
+    fn get_or_insert_node(
+        forwarder_addr: SocketAddr,
+        node_id: Bytes,
+        limit: usize,
+    ) -> Result<SocketAddr, String> {
+        let mut node_map = NODE_TO_SOCKET_ADDR.lock().unwrap();
+        let key = (forwarder_addr, node_id);
+        if let Some(node_addr) = node_map.get(&key) {
+            return Ok(*node_addr);
+        }
+        // Only a new node is counted, it's rare.
+        let nodes = node_map
+            .keys()
+            .filter(|(socket_addr, _node_id)| *socket_addr == forwarder_addr)
+            .count();
+        if nodes >= limit {
+            return Err(eformat!(forwarder_addr, "node limit", limit));
+        }
+        let n = NODE_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let ip =
+            Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, (n >> 16) as u16, n as u16);
+        let node_addr = SocketAddr::new(IpAddr::V6(ip), 0);
+        node_map.insert(key.clone(), node_addr);
+        SOCKET_ADDR_TO_NODE.lock().unwrap().insert(node_addr, key);
+        Ok(node_addr)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_encap_msg() {
+        use super::EncapMsg;
+        use bytes::{Bytes, BytesMut};
+        use std::net::SocketAddr;
+
+        let forwarder = "127.0.0.21:1200".parse::<SocketAddr>().unwrap();
+        // PINGREQ from the unknown node "n1".
+        let buf = Bytes::from(&[5, 0xFE, 0, b'n', b'1', 2, 0x16][..]);
+        assert!(EncapMsg::is_encap_msg(&buf));
+        assert!(!EncapMsg::is_encap_msg(&[2, 0x16]));
+        assert!(EncapMsg::unwrap(forwarder, &buf).is_err());
+        // CONNECT from node "n1", then from node "n2".
+        let connect = [5, 0xFE, 0, b'n', b'1', 6, 0x04, 0, 1, 0, 10];
+        let (node1, _) =
+            EncapMsg::unwrap(forwarder, &Bytes::copy_from_slice(&connect))
+                .unwrap();
+        assert_eq!(node1.port(), 0);
+        let (node1_again, msg) = EncapMsg::unwrap(forwarder, &buf).unwrap();
+        assert_eq!(node1, node1_again);
+        assert_eq!(&msg[..], &[2, 0x16]);
+        let mut connect2 = connect;
+        connect2[4] = b'2';
+        let (node2, _) =
+            EncapMsg::unwrap(forwarder, &Bytes::copy_from_slice(&connect2))
+                .unwrap();
+        assert_ne!(node1, node2);
+        // missing MQTT-SN message
+        let buf3 = Bytes::from(&[5, 0xFE, 0, b'n', b'3'][..]);
+        assert!(EncapMsg::unwrap(forwarder, &buf3).is_err());
+        // reserved Ctrl bits
+        let buf4 = Bytes::from(&[5, 0xFE, 0x80, b'n', b'1', 2, 0x16][..]);
+        assert!(EncapMsg::unwrap(forwarder, &buf4).is_err());
+
+        // PINGRESP to node "n1" is wrapped for the forwarder.
+        let (addr, wrapped) =
+            EncapMsg::wrap(node1, BytesMut::from(&[2, 0x17][..])).unwrap();
+        assert_eq!(addr, forwarder);
+        assert_eq!(&wrapped[..], &[5, 0xFE, 0, b'n', b'1', 2, 0x17]);
+        // Not a node, not wrapped.
+        let (addr, bytes) =
+            EncapMsg::wrap(forwarder, BytesMut::from(&[2, 0x17][..])).unwrap();
+        assert_eq!(addr, forwarder);
+        assert_eq!(&bytes[..], &[2, 0x17]);
+
+        // The node without a session is removed.
+        EncapMsg::remove_node(&node2);
+        assert!(EncapMsg::get_node(&node2).is_none());
+        let mut buf5 = buf.to_vec();
+        buf5[4] = b'2';
+        assert!(EncapMsg::unwrap(forwarder, &Bytes::from(buf5)).is_err());
+        // The node limit of the forwarder.
+        let node_id = Bytes::from_static(b"n2");
+        assert!(EncapMsg::get_or_insert_node(forwarder, node_id, 1).is_err());
+    }
+}
@@ -10,7 +10,72 @@ use std::net::SocketAddr;
 //use uuid::v1::{Context, Timestamp};
 //use uuid::Uuid;
 
-use crate::{eformat, flags::QoSConst, function};
+use crate::{
+    connection::{Connection, StateEnum2},
+    eformat,
+    flags::QoSConst,
+    function,
+    in_flight::InFlight,
+    topic_registry::TOPIC_REGISTRY,
+};
+
+/// Prefix of a shared subscription "$share/<group>/<filter>",
+/// MQTT 5.0 spec section 4.8.2. A message matching the filter is sent to
+/// one member of the group only.
+pub const SHARED_PREFIX: &str = "$share/";
+
+/// How the member of a shared subscription group is chosen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SharedPolicy {
+    RoundRobin,
+    /// The member with the fewest PUBLISH messages in flight and queued.
+    LeastInFlight,
+}
+
+#[derive(Debug, Default)]
+struct SharedGroup {
+    members: Vec<Subscriber>,
+    // The round robin position.
+    next: usize,
+}
+
+impl SharedGroup {
+    /// Pick the member for the next message. The excluded members already
+    /// get the message, the members without a load are unavailable (LOST,
+    /// ASLEEP or DISCONNECTED) and skipped.
+    /// Without an available member, the next one in turn not excluded gets
+    /// the message, it's queued until the member reconnects or wakes up.
+    /// None if all the members are excluded.
+    fn pick(
+        &mut self,
+        policy: SharedPolicy,
+        excluded: impl Fn(&SocketAddr) -> bool,
+        load: impl Fn(&SocketAddr) -> Option<usize>,
+    ) -> Option<Subscriber> {
+        let len = self.members.len();
+        let start = if len == 0 { 0 } else { self.next % len };
+        let candidate_vec: Vec<usize> = (0..len)
+            .map(|i| (start + i) % len)
+            .filter(|i| !excluded(&self.members[*i].socket_addr))
+            .collect();
+        let mut available = candidate_vec.iter().filter_map(|i| {
+            load(&self.members[*i].socket_addr).map(|load| (*i, load))
+        });
+        let picked = match policy {
+            SharedPolicy::RoundRobin => available.next(),
+            // Ties are broken in round robin order.
+            SharedPolicy::LeastInFlight => {
+                available.min_by_key(|(_i, load)| *load)
+            }
+        };
+        let picked = match picked {
+            Some((i, _load)) => i,
+            None => *candidate_vec.first()?,
+        };
+        self.next = picked + 1;
+        Some(self.members[picked].clone())
+    }
+}
 
 /// Checks if a topic or topic filter has wildcards
 #[inline(always)]
@@ -21,22 +86,23 @@ pub fn has_wildcards(filter: &str) -> bool {
 // https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718106
 // A subscription topic filter can contain # or + to allow the client to
 // subscribe to multiple topics at once.
+// The multi level wildcard # must be the last level, and both wildcards
+// must occupy an entire level, i.e. "a/#" and "a/+/c" but not "a/b#".
 #[inline(always)]
 pub fn valid_filter(filter: &str) -> bool {
-    if !filter.is_empty() {
-        if has_wildcards(filter) {
-            // Verify multi level wildcards.
-            if filter.find('#') == Some(filter.len() - 1)
-                && filter.ends_with("/#")
-            {
-                return true;
-            }
-        // TODO verify single level wildcards.
-        } else {
-            return true;
+    if filter.is_empty() {
+        return false;
+    }
+    let last = filter.split('/').count() - 1;
+    for (i, level) in filter.split('/').enumerate() {
+        if level.contains('#') && (level != "#" || i != last) {
+            return false;
+        }
+        if level.contains('+') && level != "+" {
+            return false;
         }
     }
-    false
+    true
 }
 
 // XXX copy from rumqtt
@@ -270,16 +336,25 @@ lazy_static! {
         Mutex::new(BisetMap::new());
     pub static ref WILDCARD_FILTERS: Mutex<BisetMap<String, SocketAddr>> =
         Mutex::new(BisetMap::new());
+    /// store QoS for each wildcard filter/subscriber
+    pub static ref WILDCARD_FILTERS_QOS: Mutex<HashMap<(String, SocketAddr), QoSConst>> =
+        Mutex::new(HashMap::new());
+    /// SocketAddr/subscriber <-> topic_ids sent with REGISTER.
+    /// The SUBACK of a wildcard subscription has topic id 0x0000, the topic ids
+    /// of the matching topics are registered before the first PUBLISH.
+    pub static ref REGISTERED_TOPIC_IDS: Mutex<BisetMap<SocketAddr, TopicIdType>> =
+        Mutex::new(BisetMap::new());
     /// topic_id <-> SocketAddr/subscribers
     pub static ref TOPIC_IDS: Mutex<BisetMap<TopicIdType, SocketAddr>> =
         Mutex::new(BisetMap::new());
     /// store QoS for each top_id/subscriber
     pub static ref TOPIC_IDS_QOS: Mutex<HashMap<(TopicIdType, SocketAddr), QoSConst>> =
         Mutex::new(HashMap::new());
-    /// Topic name to topic id map is 1:1. Using a BisetMap to allow access from both sides.
-    pub static ref TOPIC_NAME_TO_IDS: Mutex<BisetMap<String, TopicIdType>> =
-        Mutex::new(BisetMap::new());
-    pub static ref TOPIC_ID_COUNTER: Mutex<TopicIdType> = Mutex::new(0);
+    /// (group, filter) -> members of the shared subscription
+    static ref SHARED_GROUPS: Mutex<HashMap<(String, String), SharedGroup>> =
+        Mutex::new(HashMap::new());
+    static ref SHARED_POLICY: Mutex<SharedPolicy> =
+        Mutex::new(SharedPolicy::RoundRobin);
 }
 // Delete QoS data
 pub fn remove_qos(
@@ -301,60 +376,75 @@ pub fn delete_topic_id(topic_id: &TopicIdType) {
     }
 }
 pub fn get_topic_id_with_topic_name(topic_name: String) -> Option<TopicIdType> {
-    let topic_ids = TOPIC_NAME_TO_IDS.lock().unwrap().get(&topic_name);
-    if topic_ids.is_empty() {
-        None
-    } else {
-        Some(topic_ids[0])
-    }
+    TOPIC_REGISTRY.lock().unwrap().get_id(&topic_name)
+}
+
+pub fn get_topic_name_with_topic_id(topic_id: TopicIdType) -> Option<String> {
+    TOPIC_REGISTRY.lock().unwrap().get_name(topic_id)
 }
 
+/// Register the topic name with a given topic id, returns an error if
+/// either of them is already registered with another one.
 pub fn try_register_topic_name(
     topic_name: String,
     topic_id: TopicIdType,
 ) -> Result<TopicIdType, String> {
-    let topic_ids = TOPIC_NAME_TO_IDS.lock().unwrap().get(&topic_name);
-    // If topic name is already in the map, return the existing topic id,
-    // otherwise insert the topic name and topic id into the map.
-    if topic_ids.is_empty() {
-        TOPIC_NAME_TO_IDS
-            .lock()
-            .unwrap()
-            .insert(topic_name, topic_id);
-        Ok(topic_id)
-    } else {
-        if topic_ids[0] == topic_id {
-            // Topic name is already in the map with one topic id.
-            Ok(topic_ids[0])
-        } else {
-            Err(eformat!(
-                "topic name/id pair already exists",
-                topic_name,
-                topic_id,
-                topic_ids[0]
-            ))
-        }
-    }
+    TOPIC_REGISTRY
+        .lock()
+        .unwrap()
+        .register(topic_name, topic_id)
 }
 
-/// Try to insert a NEW topic name, topic id is assigned using TOPIC_ID_COUNTER
+/// Try to insert a NEW topic name, the topic id is assigned by the
+/// TOPIC_REGISTRY. Returns an error when the topic ids are exhausted.
 pub fn try_insert_topic_name(
     topic_name: String,
 ) -> Result<TopicIdType, String> {
-    let topic_ids = TOPIC_NAME_TO_IDS.lock().unwrap().get(&topic_name);
-    // If topic name is already in the map, return the existing topic id,
-    // otherwise insert the topic name and topic id into the map.
-    if topic_ids.is_empty() {
-        let topic_id = *TOPIC_ID_COUNTER.lock().unwrap();
-        TOPIC_NAME_TO_IDS
+    TOPIC_REGISTRY
+        .lock()
+        .unwrap()
+        .insert(topic_name, is_topic_id_in_use)
+}
+
+/// Reclaim the topic ids not used under the ReclaimPolicy of the
+/// TOPIC_REGISTRY, returns the number of topic ids reclaimed.
+pub fn reclaim_topic_ids() -> usize {
+    TOPIC_REGISTRY.lock().unwrap().reclaim(is_topic_id_in_use)
+}
+
+/// A topic id is in use while it has subscribers or a client knows it
+/// from a REGISTER.
+fn is_topic_id_in_use(topic_id: TopicIdType) -> bool {
+    !TOPIC_IDS.lock().unwrap().get(&topic_id).is_empty()
+        || !REGISTERED_TOPIC_IDS
             .lock()
             .unwrap()
-            .insert(topic_name, topic_id);
-        *TOPIC_ID_COUNTER.lock().unwrap() = topic_id + 1;
-        Ok(topic_id)
+            .rev_get(&topic_id)
+            .is_empty()
+}
+
+/// A short topic name has a fixed length of two octets and is carried
+/// in the topic id field instead of being registered.
+pub fn is_short_topic_name(topic_name: &str) -> bool {
+    topic_name.len() == 2 && !has_wildcards(topic_name)
+}
+
+/// Encode a short topic name into the topic id field.
+pub fn short_topic_name_to_id(topic_name: &str) -> Option<TopicIdType> {
+    if is_short_topic_name(topic_name) {
+        let bytes = topic_name.as_bytes();
+        Some(((bytes[0] as TopicIdType) << 8) | bytes[1] as TopicIdType)
     } else {
-        // Topic name is already in the map with only one topic id.
-        Ok(topic_ids[0])
+        None
+    }
+}
+
+/// Decode the topic id field of a short topic name.
+pub fn short_topic_id_to_name(topic_id: TopicIdType) -> Result<String, String> {
+    match String::from_utf8(topic_id.to_be_bytes().to_vec()) {
+        Ok(topic_name) if is_short_topic_name(&topic_name) => Ok(topic_name),
+        Ok(topic_name) => Err(eformat!("invalid short topic name", topic_name)),
+        Err(why) => Err(eformat!(topic_id, why)),
     }
 }
 
@@ -391,10 +481,8 @@ pub fn unsubscribe_with_topic_name(
     topic_name: String,
 ) -> Result<(), String> {
     // Get the topic id from the topic name.
-    let topic_ids = TOPIC_NAME_TO_IDS.lock().unwrap().get(&topic_name);
-    if !topic_ids.is_empty() {
+    if let Some(topic_id) = get_topic_id_with_topic_name(topic_name) {
         // Remove socket_addr from the topic id map.
-        let topic_id = topic_ids[0];
         unsubscribe_with_topic_id(socket_addr, topic_id)?;
         Ok(())
     } else {
@@ -418,6 +506,8 @@ pub struct Subscriber {
 }
 
 /// Get the vector of subscribers with the topic_id key.
+/// Subscribers of wildcard filters matching the topic name are included,
+/// a subscriber with both kinds of subscription is returned once.
 #[inline(always)]
 pub fn get_subscribers_with_topic_id(id: u16) -> Vec<Subscriber> {
     // Get the list of socket_addr that subscribed to the topic_id.
@@ -432,6 +522,230 @@ pub fn get_subscribers_with_topic_id(id: u16) -> Vec<Subscriber> {
             });
         }
     }
+    if let Some(topic_name) = get_topic_name_with_topic_id(id) {
+        for subscriber in get_subscribers_with_topic_name(&topic_name) {
+            if !return_vec
+                .iter()
+                .any(|sub| sub.socket_addr == subscriber.socket_addr)
+            {
+                return_vec.push(subscriber);
+            }
+        }
+        // The groups pick members without a copy of the message yet,
+        // a member picked by several groups gets one copy.
+        let subscriber_vec =
+            get_shared_subscribers_with_topic_name(&topic_name, &return_vec);
+        for subscriber in subscriber_vec {
+            if !return_vec
+                .iter()
+                .any(|sub| sub.socket_addr == subscriber.socket_addr)
+            {
+                return_vec.push(subscriber);
+            }
+        }
+    }
+    return_vec
+}
+
+/// Get the vector of subscribers whose wildcard filters match the topic name.
+/// A subscriber with several matching filters gets the highest QoS.
+#[inline(always)]
+pub fn get_subscribers_with_topic_name(topic_name: &String) -> Vec<Subscriber> {
+    let mut return_vec: Vec<Subscriber> = Vec::new();
+    for socket_addr in match_wildcard_topics(topic_name) {
+        let filter_vec = WILDCARD_FILTERS.lock().unwrap().rev_get(&socket_addr);
+        let qos_map = WILDCARD_FILTERS_QOS.lock().unwrap();
+        let qos = filter_vec
+            .into_iter()
+            .filter(|filter| match_topic(topic_name, filter))
+            .filter_map(|filter| qos_map.get(&(filter, socket_addr)).copied())
+            .max();
+        if let Some(qos) = qos {
+            return_vec.push(Subscriber { socket_addr, qos });
+        }
+    }
+    return_vec
+}
+
+/// Is the topic filter a shared subscription?
+#[inline(always)]
+pub fn is_shared(filter: &str) -> bool {
+    filter.starts_with(SHARED_PREFIX)
+}
+
+/// Split a shared subscription into its group and topic filter.
+/// None if the group is empty or has wildcards, or the filter is invalid.
+pub fn parse_shared(filter: &str) -> Option<(String, String)> {
+    let (group, filter) =
+        filter.strip_prefix(SHARED_PREFIX)?.split_once('/')?;
+    if group.is_empty() || has_wildcards(group) || !valid_filter(filter) {
+        return None;
+    }
+    Some((group.to_string(), filter.to_string()))
+}
+
+pub fn set_shared_policy(policy: SharedPolicy) {
+    *SHARED_POLICY.lock().unwrap() = policy;
+}
+
+/// The load of a shared subscription member, None if it can't receive
+/// now. Only the ACTIVE members are available, the messages of a member
+/// that becomes LOST or ASLEEP go to the other members of the group.
+fn member_load(socket_addr: &SocketAddr) -> Option<usize> {
+    match Connection::get_state(socket_addr) {
+        Ok(StateEnum2::ACTIVE) => Some(
+            InFlight::publish_len(socket_addr)
+                + InFlight::queued_len(socket_addr),
+        ),
+        _ => None,
+    }
+}
+
+/// Get one member of each shared subscription group with a filter
+/// matching the topic name. The members of the subscriber_vec already get
+/// the message, the groups pick other members.
+#[inline(always)]
+pub fn get_shared_subscribers_with_topic_name(
+    topic_name: &String,
+    subscriber_vec: &[Subscriber],
+) -> Vec<Subscriber> {
+    pick_shared_subscribers(topic_name, subscriber_vec, member_load)
+}
+
+fn pick_shared_subscribers(
+    topic_name: &String,
+    subscriber_vec: &[Subscriber],
+    load: impl Fn(&SocketAddr) -> Option<usize>,
+) -> Vec<Subscriber> {
+    // The loads are read without the groups locked, they lock the
+    // connections and the in-flight sessions.
+    let socket_vec: Vec<SocketAddr> = SHARED_GROUPS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|((_group, filter), _)| match_topic(topic_name, filter))
+        .flat_map(|(_key, group)| {
+            group.members.iter().map(|member| member.socket_addr)
+        })
+        .collect();
+    if socket_vec.is_empty() {
+        return Vec::new();
+    }
+    let load_map: HashMap<SocketAddr, Option<usize>> = socket_vec
+        .into_iter()
+        .map(|socket_addr| (socket_addr, load(&socket_addr)))
+        .collect();
+    let policy = *SHARED_POLICY.lock().unwrap();
+    let excluded = |socket_addr: &SocketAddr| {
+        subscriber_vec
+            .iter()
+            .any(|sub| sub.socket_addr == *socket_addr)
+    };
+    let mut return_vec = Vec::new();
+    for ((_group, filter), group) in SHARED_GROUPS.lock().unwrap().iter_mut() {
+        if !match_topic(topic_name, filter) {
+            continue;
+        }
+        let picked = group.pick(policy, excluded, |socket_addr| {
+            load_map.get(socket_addr).copied().flatten()
+        });
+        if let Some(subscriber) = picked {
+            return_vec.push(subscriber);
+        }
+    }
+    return_vec
+}
+
+/// Join a shared subscription group, a member already in the group
+/// gets the new QoS.
+fn subscribe_shared(
+    socket_addr: SocketAddr,
+    filter: String,
+    qos: QoSConst,
+) -> Result<(), String> {
+    let key = match parse_shared(&filter) {
+        Some(key) => key,
+        None => {
+            return Err(eformat!(socket_addr, "invalid shared filter", filter))
+        }
+    };
+    let mut groups = SHARED_GROUPS.lock().unwrap();
+    let group = groups.entry(key).or_default();
+    match group
+        .members
+        .iter_mut()
+        .find(|member| member.socket_addr == socket_addr)
+    {
+        Some(member) => member.qos = qos,
+        None => group.members.push(Subscriber { socket_addr, qos }),
+    }
+    Ok(())
+}
+
+/// Leave a shared subscription group, the last member removes the group.
+fn unsubscribe_shared(
+    socket_addr: SocketAddr,
+    filter: String,
+) -> Result<(), String> {
+    let mut groups = SHARED_GROUPS.lock().unwrap();
+    let key = parse_shared(&filter);
+    let group = match key.as_ref().and_then(|key| groups.get_mut(key)) {
+        Some(group) => group,
+        None => return Err(eformat!(socket_addr, "not subscribed", filter)),
+    };
+    let len = group.members.len();
+    group
+        .members
+        .retain(|member| member.socket_addr != socket_addr);
+    if group.members.len() == len {
+        return Err(eformat!(socket_addr, "not subscribed", filter));
+    }
+    if group.members.is_empty() {
+        groups.remove(&key.unwrap());
+    }
+    Ok(())
+}
+
+/// Remove the subscriber from all shared subscription groups.
+/// Returns the "$share/<group>/<filter>" filters and their QoS.
+fn delete_shared_with_socket_addr(
+    socket_addr: &SocketAddr,
+) -> Vec<(String, QoSConst)> {
+    let mut return_vec = Vec::new();
+    SHARED_GROUPS
+        .lock()
+        .unwrap()
+        .retain(|(group, filter), shared| {
+            shared.members.retain(|member| {
+                if member.socket_addr != *socket_addr {
+                    return true;
+                }
+                return_vec.push((
+                    format!("{}{}/{}", SHARED_PREFIX, group, filter),
+                    member.qos,
+                ));
+                false
+            });
+            !shared.members.is_empty()
+        });
+    return_vec
+}
+
+/// The "$share/<group>/<filter>" filters and QoS of a subscriber.
+fn get_shared_with_socket_addr(
+    socket_addr: &SocketAddr,
+) -> Vec<(String, QoSConst)> {
+    let mut return_vec = Vec::new();
+    for ((group, filter), shared) in SHARED_GROUPS.lock().unwrap().iter() {
+        for member in shared.members.iter() {
+            if member.socket_addr == *socket_addr {
+                return_vec.push((
+                    format!("{}{}/{}", SHARED_PREFIX, group, filter),
+                    member.qos,
+                ));
+            }
+        }
+    }
     return_vec
 }
 
@@ -442,6 +756,182 @@ pub fn delete_topic_ids_with_socket_addr(
     TOPIC_IDS.lock().unwrap().rev_delete(socket_addr)
 }
 
+/// Subscribe to a topic filter with wildcards or a shared subscription.
+/// Cached topics matching the filter get the new subscriber now,
+/// other topics are matched on their next PUBLISH by match_wildcard_topics().
+#[inline(always)]
+pub fn subscribe_with_filter(
+    socket_addr: SocketAddr,
+    filter: String,
+    qos: QoSConst,
+) -> Result<(), String> {
+    if is_shared(&filter) {
+        return subscribe_shared(socket_addr, filter, qos);
+    }
+    if !has_wildcards(&filter) || !valid_filter(&filter) {
+        return Err(eformat!(socket_addr, "invalid wildcard filter", filter));
+    }
+    WILDCARD_FILTERS
+        .lock()
+        .unwrap()
+        .insert(filter.clone(), socket_addr);
+    WILDCARD_FILTERS_QOS
+        .lock()
+        .unwrap()
+        .insert((filter.clone(), socket_addr), qos);
+    let wildcard_topics = WILDCARD_TOPICS.lock().unwrap();
+    for (topic, _socket_vec) in wildcard_topics.collect() {
+        if match_topic(&topic, &filter) {
+            wildcard_topics.insert(topic, socket_addr);
+        }
+    }
+    Ok(())
+}
+
+/// Unsubscribe from a topic filter with wildcards or a shared subscription.
+/// The cached topics of the subscriber are rebuilt from its remaining filters.
+#[inline(always)]
+pub fn unsubscribe_with_filter(
+    socket_addr: SocketAddr,
+    filter: String,
+) -> Result<(), String> {
+    if is_shared(&filter) {
+        return unsubscribe_shared(socket_addr, filter);
+    }
+    let filters = WILDCARD_FILTERS.lock().unwrap();
+    if !filters.contains(&filter, &socket_addr) {
+        return Err(eformat!(socket_addr, "not subscribed", filter));
+    }
+    filters.remove(&filter, &socket_addr);
+    let remaining_vec = filters.rev_get(&socket_addr);
+    drop(filters);
+    WILDCARD_FILTERS_QOS
+        .lock()
+        .unwrap()
+        .remove(&(filter, socket_addr));
+    let wildcard_topics = WILDCARD_TOPICS.lock().unwrap();
+    for topic in wildcard_topics.rev_delete(&socket_addr) {
+        if remaining_vec
+            .iter()
+            .any(|filter| match_topic(&topic, filter))
+        {
+            wildcard_topics.insert(topic, socket_addr);
+        }
+    }
+    Ok(())
+}
+
+/// Remove all wildcard filters and shared subscriptions of a subscriber.
+/// Returns the filters and their QoS, for migrating the subscriptions.
+#[inline(always)]
+pub fn delete_filters_with_socket_addr(
+    socket_addr: &SocketAddr,
+) -> Vec<(String, QoSConst)> {
+    let filter_vec = WILDCARD_FILTERS.lock().unwrap().rev_delete(socket_addr);
+    WILDCARD_TOPICS.lock().unwrap().rev_delete(socket_addr);
+    let mut qos_map = WILDCARD_FILTERS_QOS.lock().unwrap();
+    let mut return_vec = Vec::new();
+    for filter in filter_vec {
+        if let Some(qos) = qos_map.remove(&(filter.clone(), *socket_addr)) {
+            return_vec.push((filter, qos));
+        }
+    }
+    drop(qos_map);
+    return_vec.append(&mut delete_shared_with_socket_addr(socket_addr));
+    return_vec
+}
+
+/// Move the subscriptions and registered topic ids of a subscriber to
+/// its new socket_addr.
+pub fn rebind_socket_addr(
+    old_socket_addr: &SocketAddr,
+    new_socket_addr: SocketAddr,
+) {
+    for topic_id in delete_topic_ids_with_socket_addr(old_socket_addr) {
+        if let Some(qos) = remove_qos(&topic_id, old_socket_addr) {
+            let _result =
+                subscribe_with_topic_id(new_socket_addr, topic_id, qos);
+        }
+    }
+    for (filter, qos) in delete_filters_with_socket_addr(old_socket_addr) {
+        let _result = subscribe_with_filter(new_socket_addr, filter, qos);
+    }
+    for topic_id in delete_registered_topic_ids(old_socket_addr) {
+        insert_registered_topic_id(new_socket_addr, topic_id);
+    }
+}
+
+/// The topic ids and QoS subscribed by a subscriber.
+#[inline(always)]
+pub fn get_topic_ids_with_socket_addr(
+    socket_addr: &SocketAddr,
+) -> Vec<(TopicIdType, QoSConst)> {
+    let topic_id_vec = TOPIC_IDS.lock().unwrap().rev_get(socket_addr);
+    let qos_map = TOPIC_IDS_QOS.lock().unwrap();
+    topic_id_vec
+        .into_iter()
+        .filter_map(|topic_id| {
+            qos_map
+                .get(&(topic_id, *socket_addr))
+                .map(|qos| (topic_id, *qos))
+        })
+        .collect()
+}
+
+/// The wildcard filters, shared subscriptions and QoS subscribed by a
+/// subscriber.
+#[inline(always)]
+pub fn get_filters_with_socket_addr(
+    socket_addr: &SocketAddr,
+) -> Vec<(String, QoSConst)> {
+    let filter_vec = WILDCARD_FILTERS.lock().unwrap().rev_get(socket_addr);
+    let qos_map = WILDCARD_FILTERS_QOS.lock().unwrap();
+    let mut return_vec: Vec<(String, QoSConst)> = filter_vec
+        .into_iter()
+        .filter_map(|filter| {
+            let qos = qos_map.get(&(filter.clone(), *socket_addr)).copied();
+            qos.map(|qos| (filter, qos))
+        })
+        .collect();
+    drop(qos_map);
+    return_vec.append(&mut get_shared_with_socket_addr(socket_addr));
+    return_vec
+}
+
+/// Does the subscriber know the topic id, either from the SUBACK of
+/// its subscription or from a REGISTER sent by the gateway?
+#[inline(always)]
+pub fn is_topic_id_known(
+    socket_addr: &SocketAddr,
+    topic_id: &TopicIdType,
+) -> bool {
+    TOPIC_IDS.lock().unwrap().contains(topic_id, socket_addr)
+        || REGISTERED_TOPIC_IDS
+            .lock()
+            .unwrap()
+            .contains(socket_addr, topic_id)
+}
+
+#[inline(always)]
+pub fn insert_registered_topic_id(
+    socket_addr: SocketAddr,
+    topic_id: TopicIdType,
+) {
+    REGISTERED_TOPIC_IDS
+        .lock()
+        .unwrap()
+        .insert(socket_addr, topic_id);
+}
+
+/// Topic ids registered by the gateway are only valid for the connection,
+/// delete them when the client connects again.
+#[inline(always)]
+pub fn delete_registered_topic_ids(
+    socket_addr: &SocketAddr,
+) -> Vec<TopicIdType> {
+    REGISTERED_TOPIC_IDS.lock().unwrap().delete(socket_addr)
+}
+
 #[inline(always)]
 pub fn insert_filter(
     filter: String,
@@ -461,9 +951,8 @@ pub fn insert_filter(
 /// Remove topics and filters from the bisetmaps using the rev_delete()
 #[inline(always)]
 pub fn delete_filter(socket_addr: SocketAddr) {
-    WILDCARD_FILTERS.lock().unwrap().rev_delete(&socket_addr);
+    delete_filters_with_socket_addr(&socket_addr);
     CONCRETE_TOPICS.lock().unwrap().rev_delete(&socket_addr);
-    WILDCARD_TOPICS.lock().unwrap().rev_delete(&socket_addr);
 }
 
 #[inline(always)]
@@ -471,8 +960,14 @@ pub fn match_concrete_topics(topic: &String) -> Vec<SocketAddr> {
     CONCRETE_TOPICS.lock().unwrap().get(topic)
 }
 
+/// Match a topic against the wildcard filters.
+/// The result is cached in WILDCARD_TOPICS for the next PUBLISH.
 #[inline(always)]
-pub fn match_topics(topic: &String) -> Vec<SocketAddr> {
+pub fn match_wildcard_topics(topic: &String) -> Vec<SocketAddr> {
+    // Publish topic shouldn't have wildcards.
+    if has_wildcards(topic) {
+        return Vec::new();
+    }
     let sock_vec = WILDCARD_TOPICS.lock().unwrap().get(topic);
     if sock_vec.is_empty() {
         // The topic doesn't match any wildcard topics.
@@ -485,8 +980,14 @@ pub fn match_topics(topic: &String) -> Vec<SocketAddr> {
                 }
             }
         }
+        return WILDCARD_TOPICS.lock().unwrap().get(topic);
     }
-    let wildcards = WILDCARD_TOPICS.lock().unwrap().get(topic);
+    sock_vec
+}
+
+#[inline(always)]
+pub fn match_topics(topic: &String) -> Vec<SocketAddr> {
+    let wildcards = match_wildcard_topics(topic);
     let mut concretes = CONCRETE_TOPICS.lock().unwrap().get(topic);
     concretes.append(&mut wildcards.clone());
     concretes.sort();
@@ -509,17 +1010,33 @@ mod test {
 
     #[test]
     fn test_topic_name_and_id() {
+        // 0x0000 is reserved, the first topic id is 0x0001.
         let topic_id =
             super::try_insert_topic_name("test".to_string()).unwrap();
-        assert_eq!(topic_id, 0);
-        let topic_id =
+        assert_ne!(topic_id, 0);
+        let topic_id2 =
             super::try_insert_topic_name("test".to_string()).unwrap();
-        assert_eq!(topic_id, 0);
-        let topic_id =
+        assert_eq!(topic_id2, topic_id);
+        let topic_id3 =
             super::try_insert_topic_name("test/now".to_string()).unwrap();
-        assert_eq!(topic_id, 1);
-        dbg!(super::TOPIC_NAME_TO_IDS.lock().unwrap());
-        dbg!(super::TOPIC_ID_COUNTER.lock().unwrap());
+        assert_ne!(topic_id3, 0);
+        assert_ne!(topic_id3, topic_id);
+        assert_eq!(
+            super::get_topic_name_with_topic_id(topic_id3),
+            Some("test/now".to_string())
+        );
+        dbg!(crate::topic_registry::TOPIC_REGISTRY.lock().unwrap());
+    }
+    #[test]
+    fn test_short_topic_name() {
+        assert!(super::is_short_topic_name("ab"));
+        assert!(!super::is_short_topic_name("abc"));
+        assert!(!super::is_short_topic_name("a#"));
+        assert_eq!(super::short_topic_name_to_id("ab"), Some(0x6162));
+        assert_eq!(super::short_topic_name_to_id("a/b"), None);
+        assert_eq!(super::short_topic_id_to_name(0x6162).unwrap(), "ab");
+        assert!(super::short_topic_id_to_name(0x612b).is_err());
+        assert!(super::short_topic_id_to_name(0xffff).is_err());
     }
     #[test]
     fn test_topic_id() {
@@ -860,4 +1377,188 @@ mod test {
         assert!(!super::match_topic(filter2, filter1));
     }
     */
+
+    #[test]
+    fn test_wildcard_subscribers() {
+        use crate::flags::{QOS_LEVEL_1, QOS_LEVEL_2};
+        use std::net::SocketAddr;
+
+        let socket = "127.0.0.11:1200".parse::<SocketAddr>().unwrap();
+        let socket2 = "127.0.0.12:1200".parse::<SocketAddr>().unwrap();
+        let topic_id =
+            super::try_register_topic_name("plant/1/temp".to_string(), 0x7001)
+                .unwrap();
+
+        assert!(super::subscribe_with_filter(
+            socket,
+            "plant/+/temp".to_string(),
+            QOS_LEVEL_1
+        )
+        .is_ok());
+        assert!(super::subscribe_with_filter(
+            socket,
+            "plant/#".to_string(),
+            QOS_LEVEL_2
+        )
+        .is_ok());
+        assert!(super::subscribe_with_filter(
+            socket2,
+            "plant/+/humidity".to_string(),
+            QOS_LEVEL_1
+        )
+        .is_ok());
+        // Not a wildcard filter, or an invalid one.
+        assert!(super::subscribe_with_filter(
+            socket2,
+            "plant/1/temp".to_string(),
+            QOS_LEVEL_1
+        )
+        .is_err());
+        assert!(super::subscribe_with_filter(
+            socket2,
+            "plant/#/temp".to_string(),
+            QOS_LEVEL_1
+        )
+        .is_err());
+
+        // The highest QoS of the matching filters.
+        let subscriber_vec = super::get_subscribers_with_topic_id(topic_id);
+        assert_eq!(subscriber_vec.len(), 1);
+        assert_eq!(subscriber_vec[0].socket_addr, socket);
+        assert_eq!(subscriber_vec[0].qos, QOS_LEVEL_2);
+
+        // The cached topic follows the remaining filter.
+        super::unsubscribe_with_filter(socket, "plant/#".to_string()).unwrap();
+        let subscriber_vec = super::get_subscribers_with_topic_id(topic_id);
+        assert_eq!(subscriber_vec.len(), 1);
+        assert_eq!(subscriber_vec[0].qos, QOS_LEVEL_1);
+
+        super::unsubscribe_with_filter(socket, "plant/+/temp".to_string())
+            .unwrap();
+        assert!(super::get_subscribers_with_topic_id(topic_id).is_empty());
+        assert!(super::unsubscribe_with_filter(
+            socket,
+            "plant/+/temp".to_string()
+        )
+        .is_err());
+
+        // The topic id is known after it's registered.
+        assert!(!super::is_topic_id_known(&socket2, &topic_id));
+        super::insert_registered_topic_id(socket2, topic_id);
+        assert!(super::is_topic_id_known(&socket2, &topic_id));
+        super::delete_registered_topic_ids(&socket2);
+        assert!(!super::is_topic_id_known(&socket2, &topic_id));
+    }
+
+    #[test]
+    fn test_shared_subscribers() {
+        use super::{pick_shared_subscribers, SharedPolicy};
+        use crate::flags::{QOS_LEVEL_0, QOS_LEVEL_1};
+        use std::net::SocketAddr;
+
+        let socket = "127.0.0.13:1200".parse::<SocketAddr>().unwrap();
+        let socket2 = "127.0.0.14:1200".parse::<SocketAddr>().unwrap();
+        let socket3 = "127.0.0.15:1200".parse::<SocketAddr>().unwrap();
+        let topic = "farm/1/temp".to_string();
+
+        assert_eq!(
+            super::parse_shared("$share/g1/farm/+/temp"),
+            Some(("g1".to_string(), "farm/+/temp".to_string()))
+        );
+        assert_eq!(super::parse_shared("$share//farm/1/temp"), None);
+        assert_eq!(super::parse_shared("$share/g+/farm/1/temp"), None);
+        assert_eq!(super::parse_shared("$share/g1"), None);
+        assert!(super::subscribe_with_filter(
+            socket,
+            "$share/g1/farm/#/temp".to_string(),
+            QOS_LEVEL_1
+        )
+        .is_err());
+
+        for socket_addr in [socket, socket2, socket3] {
+            super::subscribe_with_filter(
+                socket_addr,
+                "$share/g1/farm/+/temp".to_string(),
+                QOS_LEVEL_1,
+            )
+            .unwrap();
+        }
+        // A shared subscription without wildcards is a group of its own.
+        super::subscribe_with_filter(
+            socket3,
+            "$share/g2/farm/1/temp".to_string(),
+            QOS_LEVEL_0,
+        )
+        .unwrap();
+
+        // Round robin, the unavailable socket2 is skipped.
+        let load =
+            |socket_addr: &SocketAddr| (*socket_addr != socket2).then_some(0);
+        let mut picked_vec = Vec::new();
+        for _ in 0..4 {
+            let subscriber_vec = pick_shared_subscribers(&topic, &[], load);
+            assert_eq!(subscriber_vec.len(), 2);
+            for subscriber in subscriber_vec {
+                if subscriber.qos == QOS_LEVEL_1 {
+                    picked_vec.push(subscriber.socket_addr);
+                }
+            }
+        }
+        assert!(!picked_vec.contains(&socket2));
+        assert_ne!(picked_vec[0], picked_vec[1]);
+        assert_eq!(picked_vec[0], picked_vec[2]);
+
+        // Least in-flight.
+        super::set_shared_policy(SharedPolicy::LeastInFlight);
+        let load = |socket_addr: &SocketAddr| {
+            Some(if *socket_addr == socket2 { 1 } else { 5 })
+        };
+        for _ in 0..3 {
+            let subscriber_vec = pick_shared_subscribers(&topic, &[], load);
+            assert!(subscriber_vec
+                .iter()
+                .any(|sub| sub.socket_addr == socket2));
+        }
+        super::set_shared_policy(SharedPolicy::RoundRobin);
+
+        // No member available, the message is queued for one of them.
+        let subscriber_vec = pick_shared_subscribers(&topic, &[], |_| None);
+        assert_eq!(subscriber_vec.len(), 2);
+
+        // A member already subscribed to the topic is excluded, the group
+        // delivers its copy to another member.
+        let subscriber = super::Subscriber {
+            socket_addr: socket3,
+            qos: QOS_LEVEL_1,
+        };
+        for _ in 0..3 {
+            let subscriber_vec =
+                pick_shared_subscribers(&topic, &[subscriber.clone()], load);
+            assert_eq!(subscriber_vec.len(), 1);
+            assert_ne!(subscriber_vec[0].socket_addr, socket3);
+        }
+
+        // The shared subscriptions are saved and migrated with the filters.
+        let filter_vec = super::get_filters_with_socket_addr(&socket3);
+        assert_eq!(filter_vec.len(), 2);
+        assert_eq!(super::delete_filters_with_socket_addr(&socket3).len(), 2);
+        assert_eq!(pick_shared_subscribers(&topic, &[], load).len(), 1);
+
+        super::unsubscribe_with_filter(
+            socket,
+            "$share/g1/farm/+/temp".to_string(),
+        )
+        .unwrap();
+        assert!(super::unsubscribe_with_filter(
+            socket,
+            "$share/g1/farm/+/temp".to_string()
+        )
+        .is_err());
+        super::unsubscribe_with_filter(
+            socket2,
+            "$share/g1/farm/+/temp".to_string(),
+        )
+        .unwrap();
+        assert!(pick_shared_subscribers(&topic, &[], load).is_empty());
+    }
 }
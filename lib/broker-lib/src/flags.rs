@@ -22,6 +22,17 @@ pub type CleanSessionConst = u8;
 pub const CLEAN_SESSION_FALSE: CleanSessionConst = 0b_0_00_0_0_0_00;
 pub const CLEAN_SESSION_TRUE: CleanSessionConst = 0b_0_00_0_0_1_00;
 
+// MQTT-SN 2.0 CONNECT flags: the Will and CleanStart flags are in the
+// bits of 1.2, DefaultAwake in bit 1 and Auth in bit 0. The bits 7-4 are
+// reserved.
+pub type DefaultAwakeConst = u8;
+pub const DEFAULT_AWAKE_FALSE: DefaultAwakeConst = 0b_0_00_0_0_0_00;
+pub const DEFAULT_AWAKE_TRUE: DefaultAwakeConst = 0b_0_00_0_0_0_10;
+
+pub type AuthConst = u8;
+pub const AUTH_FALSE: AuthConst = 0b_0_00_0_0_0_00;
+pub const AUTH_TRUE: AuthConst = 0b_0_00_0_0_0_01;
+
 pub type TopicIdTypeConst = u8;
 pub const TOPIC_ID_TYPE_NORMAL: TopicIdTypeConst = 0b_0_00_0_0_0_00;
 pub const TOPIC_ID_TYPE_PRE_DEFINED: TopicIdTypeConst = 0b_0_00_0_0_0_01;
@@ -48,6 +59,16 @@ pub fn flag_is_will(input: u8) -> bool {
 pub fn flag_is_clean_session(input: u8) -> bool {
     (input & 0b00000_1_00) != 0
 }
+/// MQTT-SN 2.0 CONNECT only, the client continues with AUTH.
+#[inline(always)]
+pub fn flag_is_auth(input: u8) -> bool {
+    (input & 0b0000000_1) != 0
+}
+/// MQTT-SN 2.0 CONNECT only, the client never sleeps.
+#[inline(always)]
+pub fn flag_is_default_awake(input: u8) -> bool {
+    (input & 0b000000_1_0) != 0
+}
 #[inline(always)]
 pub fn flag_topic_id_type(input: u8) -> TopicIdTypeConst {
     input & 0b11
@@ -67,3 +88,23 @@ pub fn flags_set(
 pub fn flag_set_dup(bytes: &[u8], dup: DupConst) -> u8 {
     dup | bytes[2]
 }
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_connect_v2_flags() {
+        use super::*;
+
+        let flags = WILL_TRUE | CLEAN_SESSION_TRUE | AUTH_TRUE;
+        assert!(flag_is_auth(flags));
+        assert!(!flag_is_default_awake(flags));
+        assert!(flag_is_will(flags));
+        assert!(flag_is_clean_session(flags));
+        let flags = DEFAULT_AWAKE_TRUE | AUTH_FALSE;
+        assert!(!flag_is_auth(flags));
+        assert!(flag_is_default_awake(flags));
+        assert!(!flag_is_will(flags));
+        // The 1.2 DUP bit isn't the Auth flag.
+        assert!(!flag_is_auth(DUP_TRUE | DEFAULT_AWAKE_FALSE));
+    }
+}
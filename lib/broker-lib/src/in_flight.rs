@@ -0,0 +1,272 @@
+//! Outbound msg_id allocation and in-flight tracking per client session.
+//! The broker assigns its own msg_id to every QoS 1 & 2 PUBLISH and every
+//! REGISTER it sends, the publisher's msg_id is only valid between the
+//! publisher and the broker.
+//! A msg_id stays in flight until the exchange is closed by the PUBACK,
+//! PUBCOMP or REGACK, or the retransmission times out, and is not reused
+//! before that.
+//! The QoS 1 & 2 PUBLISH messages in flight are limited by the window of
+//! the session, the excess messages are queued in order until the
+//! window opens.
+
+use bytes::Bytes;
+use hashbrown::HashMap;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::{eformat, function, MsgIdType, MSG_TYPE_PUBLISH};
+
+/// Default maximum number of QoS 1 & 2 PUBLISH messages in flight to
+/// each client.
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 16;
+
+static MAX_IN_FLIGHT: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_IN_FLIGHT);
+
+/// A PUBLISH waiting for the in-flight window, the topic id is resolved.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueuedPublish {
+    pub topic_id_type: u8,
+    pub topic_id: u16,
+    pub qos: u8,
+    pub retain: u8,
+    pub data: Bytes,
+}
+
+#[derive(Debug, Clone)]
+struct Session {
+    next_msg_id: MsgIdType,
+    // msg_id -> msg_type of the message sent.
+    in_flight: HashMap<MsgIdType, u8>,
+    queue: VecDeque<QueuedPublish>,
+    max_in_flight: usize,
+}
+
+impl Session {
+    fn new() -> Self {
+        Session {
+            next_msg_id: 1,
+            in_flight: HashMap::new(),
+            queue: VecDeque::new(),
+            max_in_flight: MAX_IN_FLIGHT.load(Ordering::Relaxed),
+        }
+    }
+    fn alloc(&mut self, msg_type: u8) -> Option<MsgIdType> {
+        if self.in_flight.len() >= MsgIdType::MAX as usize {
+            return None;
+        }
+        let mut msg_id = self.next_msg_id;
+        while msg_id == 0 || self.in_flight.contains_key(&msg_id) {
+            msg_id = msg_id.wrapping_add(1);
+        }
+        self.next_msg_id = msg_id.wrapping_add(1);
+        self.in_flight.insert(msg_id, msg_type);
+        Some(msg_id)
+    }
+    fn publish_in_flight(&self) -> usize {
+        self.in_flight
+            .values()
+            .filter(|msg_type| **msg_type == MSG_TYPE_PUBLISH)
+            .count()
+    }
+    fn window_open(&self) -> bool {
+        self.publish_in_flight() < self.max_in_flight
+    }
+}
+
+lazy_static! {
+    static ref SESSIONS: Mutex<HashMap<SocketAddr, Session>> =
+        Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone)]
+pub struct InFlight {}
+
+impl InFlight {
+    /// Set the maximum number of QoS 1 & 2 PUBLISH messages in flight to
+    /// each client, at least 1. The sessions created later use it.
+    pub fn set_max_in_flight(max_in_flight: usize) {
+        MAX_IN_FLIGHT.store(max_in_flight.max(1), Ordering::Relaxed);
+    }
+    /// Set the maximum of the session of the client only, at least 1.
+    pub fn set_session_max_in_flight(
+        socket_addr: SocketAddr,
+        max_in_flight: usize,
+    ) {
+        let mut sessions = SESSIONS.lock().unwrap();
+        let session = sessions.entry(socket_addr).or_insert_with(Session::new);
+        session.max_in_flight = max_in_flight.max(1);
+    }
+    /// Allocate the next free msg_id for a message sent to the client.
+    /// msg_id 0x0000 is reserved for QoS 0 and -1 messages.
+    pub fn alloc(
+        socket_addr: SocketAddr,
+        msg_type: u8,
+    ) -> Result<MsgIdType, String> {
+        let mut sessions = SESSIONS.lock().unwrap();
+        let session = sessions.entry(socket_addr).or_insert_with(Session::new);
+        match session.alloc(msg_type) {
+            Some(msg_id) => Ok(msg_id),
+            None => Err(eformat!(socket_addr, "no free msg_id")),
+        }
+    }
+    /// Allocate the msg_id of a PUBLISH if the window is open and no
+    /// message is queued before it, otherwise queue the PUBLISH and
+    /// return None.
+    pub fn alloc_publish(
+        socket_addr: SocketAddr,
+        publish: QueuedPublish,
+    ) -> Result<Option<MsgIdType>, String> {
+        let mut sessions = SESSIONS.lock().unwrap();
+        let session = sessions.entry(socket_addr).or_insert_with(Session::new);
+        if session.queue.is_empty() && session.window_open() {
+            match session.alloc(MSG_TYPE_PUBLISH) {
+                Some(msg_id) => return Ok(Some(msg_id)),
+                None => return Err(eformat!(socket_addr, "no free msg_id")),
+            }
+        }
+        session.queue.push_back(publish);
+        Ok(None)
+    }
+    /// The oldest queued PUBLISH with its msg_id when the window is open.
+    pub fn pop_queued(
+        socket_addr: &SocketAddr,
+    ) -> Option<(MsgIdType, QueuedPublish)> {
+        let mut sessions = SESSIONS.lock().unwrap();
+        let session = sessions.get_mut(socket_addr)?;
+        if session.queue.is_empty() || !session.window_open() {
+            return None;
+        }
+        let msg_id = session.alloc(MSG_TYPE_PUBLISH)?;
+        let publish = session.queue.pop_front()?;
+        Some((msg_id, publish))
+    }
+    /// Number of QoS 1 & 2 PUBLISH messages in flight to the client.
+    pub fn publish_len(socket_addr: &SocketAddr) -> usize {
+        match SESSIONS.lock().unwrap().get(socket_addr) {
+            Some(session) => session.publish_in_flight(),
+            None => 0,
+        }
+    }
+    /// Number of PUBLISH messages waiting for the window.
+    pub fn queued_len(socket_addr: &SocketAddr) -> usize {
+        match SESSIONS.lock().unwrap().get(socket_addr) {
+            Some(session) => session.queue.len(),
+            None => 0,
+        }
+    }
+    /// Close the exchange, the msg_id can be reused.
+    /// Returns the msg_type of the message, None if not in flight.
+    pub fn release(socket_addr: &SocketAddr, msg_id: MsgIdType) -> Option<u8> {
+        match SESSIONS.lock().unwrap().get_mut(socket_addr) {
+            Some(session) => session.in_flight.remove(&msg_id),
+            None => None,
+        }
+    }
+    pub fn contains(socket_addr: &SocketAddr, msg_id: MsgIdType) -> bool {
+        match SESSIONS.lock().unwrap().get(socket_addr) {
+            Some(session) => session.in_flight.contains_key(&msg_id),
+            None => false,
+        }
+    }
+    /// Number of open exchanges with the client.
+    pub fn len(socket_addr: &SocketAddr) -> usize {
+        match SESSIONS.lock().unwrap().get(socket_addr) {
+            Some(session) => session.in_flight.len(),
+            None => 0,
+        }
+    }
+    /// Move the session to the new socket_addr of the client.
+    pub fn rebind(old_socket_addr: &SocketAddr, new_socket_addr: SocketAddr) {
+        let mut sessions = SESSIONS.lock().unwrap();
+        if let Some(session) = sessions.remove(old_socket_addr) {
+            sessions.insert(new_socket_addr, session);
+        }
+    }
+    /// Delete the session when the client starts a clean session.
+    pub fn delete(socket_addr: &SocketAddr) {
+        SESSIONS.lock().unwrap().remove(socket_addr);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_in_flight() {
+        use super::InFlight;
+        use crate::{MSG_TYPE_PUBLISH, MSG_TYPE_REGISTER};
+        use std::net::SocketAddr;
+
+        let socket = "127.0.0.91:1200".parse::<SocketAddr>().unwrap();
+        let socket2 = "127.0.0.92:1200".parse::<SocketAddr>().unwrap();
+        assert_eq!(InFlight::alloc(socket, MSG_TYPE_PUBLISH), Ok(1));
+        assert_eq!(InFlight::alloc(socket, MSG_TYPE_REGISTER), Ok(2));
+        // Each session has its own msg_ids.
+        assert_eq!(InFlight::alloc(socket2, MSG_TYPE_PUBLISH), Ok(1));
+        assert_eq!(InFlight::len(&socket), 2);
+        assert!(InFlight::contains(&socket, 1));
+        assert_eq!(InFlight::release(&socket, 1), Some(MSG_TYPE_PUBLISH));
+        assert_eq!(InFlight::release(&socket, 1), None);
+        assert!(!InFlight::contains(&socket, 1));
+
+        // msg_id 2 is still in flight after the wrap around, skip it and 0.
+        for msg_id in 3..=u16::MAX {
+            assert_eq!(InFlight::alloc(socket, MSG_TYPE_PUBLISH), Ok(msg_id));
+            InFlight::release(&socket, msg_id);
+        }
+        assert_eq!(InFlight::alloc(socket, MSG_TYPE_PUBLISH), Ok(1));
+        assert_eq!(InFlight::alloc(socket, MSG_TYPE_PUBLISH), Ok(3));
+
+        // The msg_ids in flight follow the client to its new address.
+        let socket3 = "127.0.0.94:1200".parse::<SocketAddr>().unwrap();
+        InFlight::rebind(&socket, socket3);
+        assert_eq!(InFlight::len(&socket), 0);
+        assert!(InFlight::contains(&socket3, 3));
+        InFlight::rebind(&socket3, socket);
+
+        InFlight::delete(&socket);
+        assert_eq!(InFlight::len(&socket), 0);
+        assert_eq!(InFlight::alloc(socket, MSG_TYPE_PUBLISH), Ok(1));
+    }
+    #[test]
+    fn test_in_flight_window() {
+        use super::{InFlight, QueuedPublish};
+        use crate::MSG_TYPE_REGISTER;
+        use bytes::Bytes;
+        use std::net::SocketAddr;
+
+        let socket = "127.0.0.97:1200".parse::<SocketAddr>().unwrap();
+        let publish = |n: u8| QueuedPublish {
+            topic_id_type: 0,
+            topic_id: 1,
+            qos: 1,
+            retain: 0,
+            data: Bytes::from(vec![n]),
+        };
+        InFlight::set_session_max_in_flight(socket, 2);
+        assert_eq!(InFlight::alloc_publish(socket, publish(1)), Ok(Some(1)));
+        // A REGISTER doesn't count in the window.
+        assert_eq!(InFlight::alloc(socket, MSG_TYPE_REGISTER), Ok(2));
+        assert_eq!(InFlight::alloc_publish(socket, publish(2)), Ok(Some(3)));
+        assert_eq!(InFlight::alloc_publish(socket, publish(3)), Ok(None));
+        assert_eq!(InFlight::alloc_publish(socket, publish(4)), Ok(None));
+        assert_eq!(InFlight::publish_len(&socket), 2);
+        assert_eq!(InFlight::queued_len(&socket), 2);
+        assert_eq!(InFlight::pop_queued(&socket), None);
+
+        // The window opens, the queued messages are released in order.
+        InFlight::release(&socket, 1);
+        assert_eq!(InFlight::pop_queued(&socket), Some((4, publish(3))));
+        assert_eq!(InFlight::pop_queued(&socket), None);
+        // A new message is queued behind the older ones.
+        InFlight::release(&socket, 3);
+        assert_eq!(InFlight::alloc_publish(socket, publish(5)), Ok(None));
+        assert_eq!(InFlight::pop_queued(&socket), Some((5, publish(4))));
+        InFlight::release(&socket, 4);
+        assert_eq!(InFlight::pop_queued(&socket), Some((6, publish(5))));
+        assert_eq!(InFlight::queued_len(&socket), 0);
+
+        InFlight::delete(&socket);
+    }
+}
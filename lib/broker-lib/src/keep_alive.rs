@@ -1,17 +1,15 @@
 use crate::{
     broker_lib::MqttSnClient, connection::Connection, connection::StateEnum2,
-    eformat, function,
+    eformat, function, session_expiry::SessionExpiry, PROTOCOL_ID_V2_0,
 };
 use core::fmt::Debug;
 use core::hash::Hash;
-use hashbrown::HashMap;
 use log::*;
 use std::net::SocketAddr;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::sync::Arc;
 use std::time::Duration;
-use trace_var::trace_var;
+use timing_wheel::{driver, MonotonicClock, ShardedWheel};
 
 #[derive(Debug, Clone, Hash)]
 pub struct KeepAliveKey {
@@ -20,250 +18,161 @@ pub struct KeepAliveKey {
 
 #[derive(Debug, Clone)]
 struct KeepAliveVal {
-    latest_counter: usize,
+    // The keep alive or sleep duration in seconds from the client.
     conn_duration: u16,
+    // The conn_duration with the grace factor.
+    timeout: Duration,
 }
 
-#[derive(Debug, Clone)]
-struct Slot {
-    pub entries: Arc<Mutex<Vec<SocketAddr>>>,
-}
+static SLEEP_DURATION: u64 = 100;
 
-impl Slot {
-    pub fn new() -> Self {
-        Slot {
-            entries: Arc::new(Mutex::new(Vec::new())),
-        }
-    }
-}
+/// Default grace factor of the keep alive and sleep durations,
+/// in percent of the duration.
+pub const GRACE_FACTOR_PERCENT: u64 = 150;
 
-static SLEEP_DURATION: usize = 100;
-static MAX_SLOT: usize = (1000 / SLEEP_DURATION) * 64 * 2;
+static GRACE_PERCENT: AtomicU64 = AtomicU64::new(GRACE_FACTOR_PERCENT);
 
-// TODO use lazy_static for easy access from any code without
-// attaching to a structure.
 lazy_static! {
-    static ref CURRENT_COUNTER: AtomicU64 = AtomicU64::new(0);
-    static ref SLOT_VEC: Mutex<Vec<Slot>> =
-        Mutex::new(Vec::with_capacity(MAX_SLOT));
-    static ref TIME_WHEEL_MAP: Mutex<HashMap<SocketAddr, KeepAliveVal>> =
-        Mutex::new(HashMap::new());
+    static ref TIME_WHEEL: Arc<ShardedWheel<SocketAddr, KeepAliveVal>> =
+        Arc::new(ShardedWheel::new(
+            Duration::from_millis(SLEEP_DURATION),
+            Arc::new(MonotonicClock::new()),
+        ));
 }
 
-// TODO only for retransmit timing wheel.
-// The initial duration is set to TIME_WHEEL_INIT_DURATION, but can be
-// changed to reflect the network the client is on, (LAN or WAN),
-// or the latency pattern.
-
-// clients use 100 milli seconds
-// brokers use 10 milli seconds
-/// static TIME_WHEEL_SLEEP_DURATION:usize = 100; // in milli seconds
-// For 64 seconds at 100 ms sleeping interval, we need 10 * 64 slots, (1000/100 = 10)
-// For 64 seconds at 10 ms sleeping interval, we need 100 * 64 slots, (1000/10 = 100)
+/// The timeout of a duration in seconds with the grace factor in percent,
+/// the u16 duration covers up to 18 hours.
+fn timeout_with(conn_duration: u16, grace_percent: u64) -> Duration {
+    Duration::from_millis(conn_duration as u64 * 10 * grace_percent)
+}
 
-// The maximum timeout duration is 64 seconds
-// The last one might be over 64, rounding up.
-/// static TIME_WHEEL_MAX_SLOTS:usize = (1000 / TIME_WHEEL_SLEEP_DURATION) * 64 * 2;
-// Initial timeout duration is 300 ms
-// static TIME_WHEEL_DEFAULT_DURATION_MS: usize = 300;
+/// The timeout of a duration with the configured grace factor.
+fn timeout(conn_duration: u16) -> Duration {
+    timeout_with(conn_duration, GRACE_PERCENT.load(Ordering::Relaxed))
+}
 
 /// Timing wheel for keep alive.
-/// The wheel is divided into MAX_SLOT slots.
-/// Each slot is a vector of SocketAddr.
-/// The data is stored in a HashMap indexed by the SocketAddr.
+/// The timers are in a timing_wheel::ShardedWheel indexed by the
+/// SocketAddr, a message from the client restarts its timer.
+/// A duration of 0 means no keep alive.
 pub struct KeepAliveTimeWheel {}
 
 impl KeepAliveTimeWheel {
-    pub fn init() {
-        let mut slot_vec = SLOT_VEC.lock().unwrap();
-        for _ in 0..MAX_SLOT {
-            slot_vec.push(Slot::new());
+    /// The client is lost after the keep alive or sleep duration
+    /// multiplied by the grace factor, 1.5 by default.
+    pub fn set_grace_factor(grace_factor: f64) -> Result<(), String> {
+        if !(1.0..=10.0).contains(&grace_factor) {
+            return Err(eformat!("grace factor out of range", grace_factor));
         }
+        let grace_percent = (grace_factor * 100.0).round() as u64;
+        GRACE_PERCENT.store(grace_percent, Ordering::Relaxed);
+        Ok(())
     }
     /// Schedule a keep alive event for a connection.
-    /// Insert the connection address(key) into the corresponding slot.
-    /// Insert data into the TIME_WHEEL_MAP.
     #[inline(always)]
-    // #[trace_var(index, slot, hash)]
     pub fn schedule(key: SocketAddr, conn_duration: u16) -> Result<(), String> {
-        // store the key in a slot of the timing wheel
-        // TODO XXX change value 10 to a constant
-        let conn_duration = conn_duration * 10;
-        let cur_counter = CURRENT_COUNTER.load(Ordering::Relaxed) as usize;
-        let index = (cur_counter + conn_duration as usize) % MAX_SLOT;
-        match TIME_WHEEL_MAP.try_lock() {
-            Ok(mut time_wheel_map) => {
-                time_wheel_map.insert(
-                    key,
-                    KeepAliveVal {
-                        latest_counter: cur_counter,
-                        conn_duration: conn_duration,
-                    },
-                );
-            }
-            Err(why) => {
-                return Err(eformat!(why.to_string()));
-            }
+        if conn_duration == 0 {
+            // No keep alive, the client is never lost.
+            return KeepAliveTimeWheel::cancel(&key);
         }
-        match SLOT_VEC.try_lock() {
-            Ok(mut slot_vec) => {
-                let slot = &mut slot_vec[index];
-                match slot.entries.try_lock() {
-                    Ok(mut entries) => {
-                        entries.push(key);
-                    }
-                    Err(why) => {
-                        // unwind: remove the inserted key from the time_wheel_map
-                        if let None =
-                            TIME_WHEEL_MAP.lock().unwrap().remove(&key)
-                        {
-                            return Err(eformat!("key not found"));
-                        }
-                        return Err(eformat!(why.to_string()));
-                    }
-                }
-            }
-            Err(why) => {
-                // unwind: remove the inserted key from the time_wheel_map
-                if let None = TIME_WHEEL_MAP.lock().unwrap().remove(&key) {
-                    return Err(eformat!("key not found"));
-                }
-                return Err(eformat!(why.to_string()));
-            }
-        }
-        return Ok(());
+        let timeout = timeout(conn_duration);
+        let val = KeepAliveVal {
+            conn_duration,
+            timeout,
+        };
+        let _old_val = TIME_WHEEL.insert(key, val, timeout);
+        Ok(())
     }
     /// Cancel a keep alive event.
     /// Call when it received a DISCONNECT message from the sender.
+    /// A connection without keep alive has nothing to cancel.
     #[inline(always)]
-    #[trace_var(index, slot, hash, vec)]
     pub fn cancel(socket_addr: &SocketAddr) -> Result<(), String> {
-        match TIME_WHEEL_MAP.try_lock() {
-            Ok(mut time_wheel_map) => {
-                match time_wheel_map.remove(socket_addr) {
-                    Some(_) => Ok(()),
-                    None => Err(eformat!(socket_addr)),
-                }
+        let _val = TIME_WHEEL.remove(socket_addr);
+        Ok(())
+    }
+    /// Move the keep alive event to the new socket_addr of the client.
+    pub fn rebind(
+        socket_addr: &SocketAddr,
+        new_socket_addr: SocketAddr,
+    ) -> Result<(), String> {
+        match TIME_WHEEL.remove(socket_addr) {
+            Some(val) => {
+                KeepAliveTimeWheel::schedule(new_socket_addr, val.conn_duration)
             }
-            Err(why) => Err(eformat!(socket_addr, why.to_string())),
+            None => Ok(()),
         }
     }
     /// Reschedule a keep alive event when it received a message from the sender.
-    /// Modify the latest_counter in the TIME_WHEEL_MAP to the current counter.
     #[inline(always)]
-    #[trace_var(index, slot, hash, vec)]
     pub fn reschedule(socket_addr: SocketAddr) -> Result<(), String> {
-        let latest_counter = CURRENT_COUNTER.load(Ordering::Relaxed) as usize;
-        match TIME_WHEEL_MAP.try_lock() {
-            Ok(mut time_wheel_map) => {
-                match time_wheel_map.get_mut(&socket_addr) {
-                    Some(conn) => {
-                        dbg!(&conn);
-                        dbg!(&latest_counter);
-                        conn.latest_counter = latest_counter;
-                        dbg!(&latest_counter);
-                        dbg!(&conn);
-                        Ok(())
-                    }
-                    None => Err(eformat!(socket_addr, "not found.")),
-                }
-            }
-            Err(why) => Err(eformat!(socket_addr, why.to_string())),
-        }
+        // Not found with duration 0, no keep alive.
+        let _found =
+            TIME_WHEEL.reschedule_with(&socket_addr, |val| val.timeout);
+        Ok(())
     }
-    /// When the address(key) is expired in the timing wheel, it compare the latest_counter
-    /// with the current counter. If the latest_counter is less than the current counter,
-    /// the address(key) is expired. Otherwise, put it back to a new slot.
+    /// The connection without any message within the timeout is lost.
     pub fn run(client: MqttSnClient) {
-        // When the keep_alive timing wheel entry is accessed,
-        // this code determines if the connection is expired.
-        // If the hash entry has been updated to a new counter,
-        // then reschedule the connection in the timing wheel.
-        //
-        // TODO replace lock with try_lock
-        let _keep_alive_expire_thread = thread::spawn(move || {
-            loop {
-                // The sleep() has to be outside of the mutex lock block for
-                // the lock to be unlocked while the thread is sleeping.
-                thread::sleep(Duration::from_millis(SLEEP_DURATION as u64));
-                {
-                    let cur_counter: usize;
-                    cur_counter = CURRENT_COUNTER
-                        .fetch_add(1, Ordering::Relaxed)
-                        as usize;
-                    let index = cur_counter % MAX_SLOT;
-                    // dbg!(&cur_slot);
-                    // dbg!(cur_counter);
-                    let slot_vec = SLOT_VEC.lock().unwrap();
-                    let mut slot = slot_vec[index].entries.lock().unwrap();
-                    let mut time_wheel_map = TIME_WHEEL_MAP.lock().unwrap();
-                    // process the expired connections
-                    while let Some(socket_addr) = slot.pop() {
-                        dbg!(index);
-                        dbg!(socket_addr);
-                        if let Some(conn) = time_wheel_map.get(&socket_addr) {
-                            dbg!(&conn);
-                            let new_counter = conn.latest_counter as usize
-                                + conn.conn_duration as usize;
-                            dbg!(&conn);
-                            if new_counter > cur_counter {
-                                // Not expired, reschedule
-                                // The new duration starts from the latest_counter,
-                                // not the cur_counter. Subtract cur_counter is needed.
-                                let mut new_index = new_counter % MAX_SLOT;
-                                dbg!(&conn);
-                                if new_index == index {
-                                    // Can't lock the same slot twice
-                                    // Even without lock, push() to the same slot will be popped
-                                    // in the while loop, so it's an infinite loop.
-                                    // Use the next slot instead.
-                                    new_index = (index + 1) % MAX_SLOT;
-                                }
-                                let mut new_slot =
-                                    slot_vec[new_index].entries.lock().unwrap();
-                                new_slot.push(socket_addr);
-                            } else {
-                                // Client timeout, move from ACTIVE to LOST state.
-                                // MQTT-SN 1.2 spec page 25
-                                // The entry was pop() from the timing wheel slot.
-                                //    client_reschedule.set_state(STATE_LOST);
-                                // Remove it from the hashmap.
-                                // TODO XXX change connection state to LOST.
-                                // remove socket_add from keep alive HashMap
-                                if let Some(conn) =
-                                    time_wheel_map.remove(&socket_addr)
-                                {
-                                    dbg!(&conn);
-                                    dbg!(&time_wheel_map);
-                                    dbg!(&socket_addr);
-                                    match Connection::update_state(
-                                        &socket_addr,
-                                        StateEnum2::LOST,
-                                    ) {
-                                        Ok(_) => {
-                                            let _result =
-                                                Connection::publish_will(
-                                                    &socket_addr,
-                                                    &client,
-                                                );
-                                        }
-                                        Err(why) => {
-                                            error!(
-                                                "{}",
-                                                eformat!(
-                                                    socket_addr,
-                                                    why.to_string()
-                                                )
-                                            );
-                                        }
-                                    }
-                                }
-                                info!("Connection Timeout: {:?}", socket_addr);
-                            }
+        // The will is published after the poll, it schedules
+        // retransmissions and may reschedule the keep alive.
+        let _handle = driver::spawn_tokio_with(
+            TIME_WHEEL.clone(),
+            |socket_addr, val, lost_vec: &mut Vec<SocketAddr>| {
+                // Client timeout, move from ACTIVE to LOST state.
+                // MQTT-SN 1.2 spec page 25
+                debug!("Keep Alive Timeout: {:?} {:?}", socket_addr, val);
+                match Connection::update_state(socket_addr, StateEnum2::LOST) {
+                    Ok(_) => lost_vec.push(*socket_addr),
+                    Err(why) => {
+                        error!("{}", eformat!(socket_addr, why.to_string()));
+                    }
+                }
+                info!("Connection Timeout: {:?}", socket_addr);
+                None
+            },
+            move |lost_vec| {
+                for socket_addr in lost_vec {
+                    let _result =
+                        Connection::publish_will(&socket_addr, &client);
+                    // The session of a lost MQTT-SN 2.0 client expires
+                    // like a disconnected one.
+                    if let Some(conn) = Connection::get(&socket_addr) {
+                        if conn.protocol_id == PROTOCOL_ID_V2_0
+                            && conn.session_expiry > 0
+                        {
+                            SessionExpiry::schedule(
+                                conn.client_id,
+                                conn.session_expiry,
+                            );
                         }
                     }
                 }
-            }
-        });
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_timeout() {
+        use super::{timeout_with, KeepAliveTimeWheel, GRACE_FACTOR_PERCENT};
+        use std::time::Duration;
+
+        assert_eq!(timeout_with(0, GRACE_FACTOR_PERCENT), Duration::ZERO);
+        // 1.5 x 60 seconds.
+        assert_eq!(
+            timeout_with(60, GRACE_FACTOR_PERCENT),
+            Duration::from_secs(90)
+        );
+        // The full u16 range doesn't overflow.
+        assert_eq!(
+            timeout_with(u16::MAX, GRACE_FACTOR_PERCENT),
+            Duration::from_millis(u16::MAX as u64 * 1500)
+        );
+        assert_eq!(timeout_with(60, 100), Duration::from_secs(60));
+        // Out of range, the grace factor is unchanged.
+        assert!(KeepAliveTimeWheel::set_grace_factor(0.5).is_err());
+        assert!(KeepAliveTimeWheel::set_grace_factor(10.5).is_err());
     }
 }
@@ -7,8 +7,10 @@ extern crate lazy_static;
 // TODO fix non_snake_case.
 pub mod advertise;
 pub mod asleep_msg_cache;
+pub mod auth;
 pub mod broker_lib;
 pub mod client_id;
+pub mod congestion;
 pub mod conn_ack;
 pub mod connect;
 pub mod connection;
@@ -22,15 +24,19 @@ pub mod SubscriberDb;
 #[allow(non_snake_case)]
 pub mod TopicDb;
 pub mod disconnect;
+pub mod encap_msg;
 pub mod filter;
 pub mod flags;
 pub mod gw_info;
 pub mod hub;
+pub mod in_flight;
 pub mod keep_alive;
 pub mod msg_hdr;
 pub mod multicast;
+pub mod offline_queue;
 pub mod ping_req;
 pub mod ping_resp;
+pub mod pre_defined_topic;
 pub mod pub_ack;
 pub mod pub_comp;
 pub mod pub_msg_cache;
@@ -40,13 +46,20 @@ pub mod publish;
 pub mod reg_ack;
 pub mod register;
 pub mod retain_cache;
+pub mod retain_store;
 pub mod retransmit;
+pub mod rtt;
 pub mod search_gw;
+pub mod session_expiry;
+pub mod session_store;
 pub mod sub_ack;
 pub mod subscribe;
+pub mod sys_stats;
 // pub mod tikv;
 pub mod influxdb;
+#[cfg(feature = "mongodb")]
 pub mod mongodb;
+pub mod topic_registry;
 pub mod unsub_ack;
 pub mod unsubscribe;
 pub mod will_msg;
@@ -95,6 +108,8 @@ pub const MSG_TYPE_PINGREQ: MsgTypeConst = 0x16;
 pub const MSG_TYPE_PINGRESP: MsgTypeConst = 0x17;
 pub const MSG_TYPE_REGISTER: MsgTypeConst = 0x0A;
 pub const MSG_TYPE_REGACK: MsgTypeConst = 0x0B;
+// MQTT-SN 2.0
+pub const MSG_TYPE_AUTH: MsgTypeConst = 0x03;
 
 // TODO fill in the rest
 pub const MSG_TYPE_WILLMSGRESP: MsgTypeConst = 0x1D; // 29
@@ -142,12 +157,37 @@ pub const MSG_LEN_PINGREQ_HEADER: MsgLenConst = 2;
 pub const MSG_LEN_SUBSCRIBE_HEADER: MsgLenConst = 7;
 pub const MSG_LEN_UNSUBSCRIBE_HEADER: MsgLenConst = 7;
 pub const MSG_LEN_REGISTER_HEADER: MsgLenConst = 6;
+pub const MSG_LEN_ENCAP_MSG_HEADER: MsgLenConst = 3;
+// MQTT-SN 2.0
+pub const MSG_LEN_CONNECT_V2_HEADER: MsgLenConst = 12;
+pub const MSG_LEN_CONNACK_V2: MsgLenConst = 7;
+pub const MSG_LEN_DISCONNECT_V2_REASON: MsgLenConst = 3;
+pub const MSG_LEN_DISCONNECT_V2_EXPIRY: MsgLenConst = 7;
+
+pub type ProtocolIdConst = u8;
+pub const PROTOCOL_ID_V1_2: ProtocolIdConst = 0x01;
+pub const PROTOCOL_ID_V2_0: ProtocolIdConst = 0x02;
 
 type ReturnCodeConst = u8;
 const RETURN_CODE_ACCEPTED: ReturnCodeConst = 0;
-// const RETURN_CODE_CONGESTION: ReturnCodeConst = 1;
+const RETURN_CODE_CONGESTION: ReturnCodeConst = 1;
 const RETURN_CODE_INVALID_TOPIC_ID: ReturnCodeConst = 2;
-// const RETURN_CODE_NOT_SUPPORTED: ReturnCodeConst = 3;
+const RETURN_CODE_NOT_SUPPORTED: ReturnCodeConst = 3;
+
+// MQTT-SN 2.0 reason codes, replace the return codes for 2.0 clients.
+type ReasonCodeConst = u8;
+const REASON_CODE_SUCCESS: ReasonCodeConst = 0x00;
+const REASON_CODE_DISCONNECT_WITH_WILL: ReasonCodeConst = 0x04;
+const REASON_CODE_CONTINUE_AUTHENTICATION: ReasonCodeConst = 0x18;
+const REASON_CODE_UNSPECIFIED_ERROR: ReasonCodeConst = 0x80;
+const REASON_CODE_PROTOCOL_ERROR: ReasonCodeConst = 0x82;
+const REASON_CODE_IMPLEMENTATION_SPECIFIC_ERROR: ReasonCodeConst = 0x83;
+const REASON_CODE_CLIENT_ID_NOT_VALID: ReasonCodeConst = 0x85;
+const REASON_CODE_NOT_AUTHORIZED: ReasonCodeConst = 0x87;
+const REASON_CODE_SERVER_BUSY: ReasonCodeConst = 0x89;
+const REASON_CODE_BAD_AUTHENTICATION_METHOD: ReasonCodeConst = 0x8C;
+const REASON_CODE_SESSION_TAKEN_OVER: ReasonCodeConst = 0x8E;
+const REASON_CODE_TOPIC_NAME_INVALID: ReasonCodeConst = 0x90;
 
 #[macro_export]
 macro_rules! function {
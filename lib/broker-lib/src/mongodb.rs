@@ -1,8 +1,11 @@
-use crate::{flags::QoSConst, MsgIdType, TopicIdType};
+use crate::{
+    filter::get_topic_name_with_topic_id, flags::QoSConst,
+    retain_cache::Retain, retain_store::RetainStore, MsgIdType, TopicIdType,
+};
 use log::*;
 use mongodb::{
-    bson::doc, options::UpdateOptions, sync::Client, sync::Collection,
-    sync::Database,
+    bson::doc, bson::spec::BinarySubtype, bson::Binary, bson::Bson,
+    options::UpdateOptions, sync::Client, sync::Collection, sync::Database,
 };
 use serde::{Deserialize as Ser_Deserialize, Serialize as Ser_Serialize};
 use serde_bytes::{ByteBuf, Bytes};
@@ -64,14 +67,15 @@ impl RetainDb {
             }
         }
     }
-    pub fn upsert(
+    /// The msg is stored as binary, it doesn't have to be UTF-8.
+    pub fn upsert_msg(
         &self,
         qos: QoSConst,
         topic_id: TopicIdType,
         topic_name: String,
         msg_id: MsgIdType,
         msg: &[u8],
-    ) {
+    ) -> Result<(), String> {
         let options = UpdateOptions::builder().upsert(true).build();
         let result = self.collection.update_one(
             doc! {
@@ -80,14 +84,21 @@ impl RetainDb {
             doc! {
                 "$set": {
                     "msg_id": msg_id as u32,
-                    "msg": std::str::from_utf8(msg).unwrap(),
+                    "msg": Binary {
+                        subtype: BinarySubtype::Generic,
+                        bytes: msg.to_vec(),
+                    },
                     "qos": qos as u32,
                     "topic_name": topic_name,
                 },
             },
             options,
         );
-        dbg!(result);
+        dbg!(&result);
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => Err(eformat!(topic_id, e)),
+        }
     }
     pub fn upsert2(
         &self,
@@ -164,6 +175,36 @@ impl RetainDb {
             )),
         }
     }
+    /// All the retained messages, for matching a wildcard filter.
+    pub fn get_all_docs(&self) -> Result<Vec<RetainDoc>, String> {
+        match self.collection.find(None, None) {
+            Ok(cursor) => {
+                let mut retain_vec = Vec::new();
+                for result in cursor {
+                    match result {
+                        Ok(retain) => retain_vec.push(retain),
+                        Err(e) => return Err(eformat!(e)),
+                    }
+                }
+                Ok(retain_vec)
+            }
+            Err(e) => Err(eformat!(e)),
+        }
+    }
+    /// A zero-length retained message deletes the retained message.
+    pub fn delete_with_topic_id(
+        &self,
+        topic_id: TopicIdType,
+    ) -> Result<(), String> {
+        let filter = doc! { "topic_id": topic_id as u32 };
+        match self.collection.delete_one(filter, None) {
+            Ok(result) => {
+                dbg!(result);
+                Ok(())
+            }
+            Err(e) => Err(eformat!(topic_id, e)),
+        }
+    }
     pub fn get_with_msg_id(&self, msg_id: &String) -> Result<ByteBuf, String> {
         let filter = doc! { "msg_id": msg_id };
         match self.collection.find_one(filter, None) {
@@ -180,6 +221,63 @@ impl RetainDb {
     }
 }
 
+impl From<RetainDoc> for Retain {
+    fn from(retain_doc: RetainDoc) -> Self {
+        Retain {
+            qos: retain_doc.qos,
+            topic_id: retain_doc.topic_id,
+            msg_id: retain_doc.msg_id,
+            payload: bytes::Bytes::copy_from_slice(retain_doc.msg.as_slice()),
+        }
+    }
+}
+
+impl RetainStore for RetainDb {
+    fn upsert(&self, retain: &Retain) -> Result<(), String> {
+        let topic_name =
+            get_topic_name_with_topic_id(retain.topic_id).unwrap_or_default();
+        self.upsert_msg(
+            retain.qos,
+            retain.topic_id,
+            topic_name,
+            retain.msg_id,
+            &retain.payload[..],
+        )
+    }
+    fn get(&self, topic_id: TopicIdType) -> Result<Option<Retain>, String> {
+        let filter = doc! { "topic_id": topic_id as u32 };
+        match self.collection.find_one(filter, None) {
+            Ok(retain_doc) => Ok(retain_doc.map(Retain::from)),
+            Err(e) => Err(eformat!(topic_id, e)),
+        }
+    }
+    fn get_all(&self) -> Result<Vec<Retain>, String> {
+        Ok(self.get_all_docs()?.into_iter().map(Retain::from).collect())
+    }
+    fn topic_ids(&self) -> Result<Vec<TopicIdType>, String> {
+        match self.collection.distinct("topic_id", None, None) {
+            Ok(bson_vec) => Ok(bson_vec
+                .iter()
+                .filter_map(|topic_id| match topic_id {
+                    Bson::Int32(topic_id) => Some(*topic_id as TopicIdType),
+                    Bson::Int64(topic_id) => Some(*topic_id as TopicIdType),
+                    _ => None,
+                })
+                .collect()),
+            Err(e) => Err(eformat!(e)),
+        }
+    }
+    fn delete(&self, topic_id: TopicIdType) -> Result<(), String> {
+        self.delete_with_topic_id(topic_id)
+    }
+    fn len(&self) -> Result<usize, String> {
+        match self.collection.count_documents(None, None) {
+            Ok(count) => Ok(count as usize),
+            Err(e) => Err(eformat!(e)),
+        }
+    }
+}
+
 #[derive(Debug, Ser_Serialize, Ser_Deserialize)]
 struct Subscription {
     subscriber: String,
@@ -0,0 +1,266 @@
+//! Queue of the QoS 1 & 2 messages published to a persistent session
+//! (CleanSession=false) while the client is LOST or DISCONNECTED.
+//! The messages are replayed in the published order when the client
+//! connects again with CleanSession=false, a clean session deletes them.
+//! The queue of each client is limited by the number of messages and the
+//! payload bytes, when it's full either the oldest or the newest message
+//! is dropped.
+//! With OfflineQueue::open() the queues are also written to a sled
+//! database and survive a broker restart.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use hashbrown::HashMap;
+use log::*;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::{
+    eformat, flags::QoSConst, function, sys_stats::SysStats, TopicIdType,
+};
+
+/// Default maximum number of messages queued for a client.
+pub const OFFLINE_QUEUE_DEPTH: usize = 1000;
+/// Default maximum payload bytes queued for a client.
+pub const OFFLINE_QUEUE_BYTES: usize = 1024 * 1024;
+
+static MAX_DEPTH: AtomicUsize = AtomicUsize::new(OFFLINE_QUEUE_DEPTH);
+static MAX_BYTES: AtomicUsize = AtomicUsize::new(OFFLINE_QUEUE_BYTES);
+static DROP_NEWEST: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DropPolicy {
+    DropOldest,
+    DropNewest,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OfflineMsg {
+    pub topic_id: TopicIdType,
+    pub qos: QoSConst,
+    pub data: Bytes,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    next_seq: u64,
+    bytes: usize,
+    // The sequence number is the key of the message in the database.
+    msg_deque: VecDeque<(u64, OfflineMsg)>,
+}
+
+lazy_static! {
+    // client_id -> queue, the client can reconnect from another address.
+    static ref QUEUES: Mutex<HashMap<Bytes, Queue>> =
+        Mutex::new(HashMap::new());
+    static ref QUEUE_DB: Mutex<Option<sled::Db>> = Mutex::new(None);
+}
+
+#[derive(Debug, Clone)]
+pub struct OfflineQueue {}
+
+impl OfflineQueue {
+    /// Set the limits of the queue of each client.
+    pub fn set_limits(depth: usize, bytes: usize, policy: DropPolicy) {
+        MAX_DEPTH.store(depth, Ordering::Relaxed);
+        MAX_BYTES.store(bytes, Ordering::Relaxed);
+        DROP_NEWEST.store(policy == DropPolicy::DropNewest, Ordering::Relaxed);
+    }
+
+    /// Open the database and load the queued messages.
+    /// Returns the number of messages loaded.
+    pub fn open(path: &str) -> Result<usize, String> {
+        let db = match sled::open(path) {
+            Ok(db) => db,
+            Err(why) => return Err(eformat!(path, why)),
+        };
+        let mut queues = QUEUES.lock().unwrap();
+        let mut count = 0;
+        // The keys are sorted, so the messages are in the published order.
+        for result in db.iter() {
+            let (key, value) = match result {
+                Ok(entry) => entry,
+                Err(why) => return Err(eformat!(path, why)),
+            };
+            let (client_id, seq, msg) = OfflineQueue::decode(&key, &value)?;
+            let queue = queues.entry(client_id).or_insert_with(Queue::default);
+            queue.next_seq = seq + 1;
+            queue.bytes += msg.data.len();
+            queue.msg_deque.push_back((seq, msg));
+            count += 1;
+        }
+        *QUEUE_DB.lock().unwrap() = Some(db);
+        Ok(count)
+    }
+
+    /// Queue a message for the client, returns false if it's dropped.
+    pub fn push(client_id: &Bytes, msg: OfflineMsg) -> bool {
+        let max_depth = MAX_DEPTH.load(Ordering::Relaxed);
+        let max_bytes = MAX_BYTES.load(Ordering::Relaxed);
+        if max_depth == 0 || msg.data.len() > max_bytes {
+            warn!("offline queue drop: {:?} {:?}", client_id, msg);
+            SysStats::dropped();
+            return false;
+        }
+        let mut queues = QUEUES.lock().unwrap();
+        let queue = queues
+            .entry(client_id.clone())
+            .or_insert_with(Queue::default);
+        let full = queue.msg_deque.len() + 1 > max_depth
+            || queue.bytes + msg.data.len() > max_bytes;
+        if full && DROP_NEWEST.load(Ordering::Relaxed) {
+            warn!("offline queue full: {:?} {:?}", client_id, msg);
+            SysStats::dropped();
+            return false;
+        }
+        let seq = queue.next_seq;
+        queue.next_seq += 1;
+        queue.bytes += msg.data.len();
+        OfflineQueue::db_insert(client_id, seq, &msg);
+        queue.msg_deque.push_back((seq, msg));
+        while queue.msg_deque.len() > max_depth || queue.bytes > max_bytes {
+            if let Some((seq, msg)) = queue.msg_deque.pop_front() {
+                warn!("offline queue full: {:?} {:?}", client_id, msg);
+                SysStats::dropped();
+                queue.bytes -= msg.data.len();
+                OfflineQueue::db_remove(client_id, seq);
+            }
+        }
+        true
+    }
+
+    /// Remove and return all the messages of the client, oldest first.
+    pub fn take(client_id: &Bytes) -> Vec<OfflineMsg> {
+        match QUEUES.lock().unwrap().remove(client_id) {
+            Some(queue) => queue
+                .msg_deque
+                .into_iter()
+                .map(|(seq, msg)| {
+                    OfflineQueue::db_remove(client_id, seq);
+                    msg
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Delete the queue when the client starts a clean session.
+    pub fn delete(client_id: &Bytes) {
+        let _msg_vec = OfflineQueue::take(client_id);
+    }
+
+    pub fn len(client_id: &Bytes) -> usize {
+        match QUEUES.lock().unwrap().get(client_id) {
+            Some(queue) => queue.msg_deque.len(),
+            None => 0,
+        }
+    }
+
+    /// Key: ClientIdLen(2) ClientId(n) Seq(8)
+    /// Value: TopicId(2) QoS(1) Data(n)
+    fn encode_key(client_id: &Bytes, seq: u64) -> BytesMut {
+        let mut key = BytesMut::with_capacity(10 + client_id.len());
+        key.put_u16(client_id.len() as u16);
+        key.put(client_id.clone());
+        key.put_u64(seq);
+        key
+    }
+
+    fn decode(
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<(Bytes, u64, OfflineMsg), String> {
+        if key.len() < 2 || value.len() < 3 {
+            return Err(eformat!(key, "len err", value.len()));
+        }
+        let id_len = u16::from_be_bytes([key[0], key[1]]) as usize;
+        if key.len() != 2 + id_len + 8 {
+            return Err(eformat!(key, "len err", id_len));
+        }
+        let client_id = Bytes::copy_from_slice(&key[2..2 + id_len]);
+        let seq = u64::from_be_bytes(*array_ref![key, 2 + id_len, 8]);
+        let msg = OfflineMsg {
+            topic_id: u16::from_be_bytes([value[0], value[1]]),
+            qos: value[2],
+            data: Bytes::copy_from_slice(&value[3..]),
+        };
+        Ok((client_id, seq, msg))
+    }
+
+    fn db_insert(client_id: &Bytes, seq: u64, msg: &OfflineMsg) {
+        if let Some(db) = QUEUE_DB.lock().unwrap().as_ref() {
+            let mut value = BytesMut::with_capacity(3 + msg.data.len());
+            value.put_u16(msg.topic_id);
+            value.put_u8(msg.qos);
+            value.put(msg.data.clone());
+            let key = OfflineQueue::encode_key(client_id, seq);
+            if let Err(why) = db.insert(&key[..], &value[..]) {
+                error!("{}", eformat!(client_id, why));
+            }
+        }
+    }
+
+    fn db_remove(client_id: &Bytes, seq: u64) {
+        if let Some(db) = QUEUE_DB.lock().unwrap().as_ref() {
+            let key = OfflineQueue::encode_key(client_id, seq);
+            if let Err(why) = db.remove(&key[..]) {
+                error!("{}", eformat!(client_id, why));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_offline_queue() {
+        use super::{DropPolicy, OfflineMsg, OfflineQueue};
+        use bytes::Bytes;
+
+        let client_id = Bytes::from("offline_queue_client");
+        let msg = |topic_id| OfflineMsg {
+            topic_id,
+            qos: 0b_0_01_0_0_0_00,
+            data: Bytes::from_static(b"hello"),
+        };
+        OfflineQueue::set_limits(3, 1024, DropPolicy::DropOldest);
+        for topic_id in 1..=5 {
+            assert!(OfflineQueue::push(&client_id, msg(topic_id)));
+        }
+        assert_eq!(OfflineQueue::len(&client_id), 3);
+        // The two oldest messages are dropped.
+        assert_eq!(
+            OfflineQueue::take(&client_id),
+            vec![msg(3), msg(4), msg(5)]
+        );
+        assert!(OfflineQueue::take(&client_id).is_empty());
+
+        // 5 bytes each, only 2 messages fit in 12 bytes.
+        OfflineQueue::set_limits(3, 12, DropPolicy::DropNewest);
+        assert!(OfflineQueue::push(&client_id, msg(1)));
+        assert!(OfflineQueue::push(&client_id, msg(2)));
+        assert!(!OfflineQueue::push(&client_id, msg(3)));
+        assert_eq!(OfflineQueue::take(&client_id), vec![msg(1), msg(2)]);
+
+        // Durable mode, reload the queue from the database.
+        let path = std::env::temp_dir().join("test_offline_queue");
+        let _ = std::fs::remove_dir_all(&path);
+        OfflineQueue::set_limits(3, 1024, DropPolicy::DropOldest);
+        assert_eq!(OfflineQueue::open(path.to_str().unwrap()), Ok(0));
+        for topic_id in 1..=4 {
+            assert!(OfflineQueue::push(&client_id, msg(topic_id)));
+        }
+        super::QUEUES.lock().unwrap().clear();
+        *super::QUEUE_DB.lock().unwrap() = None;
+        assert_eq!(OfflineQueue::open(path.to_str().unwrap()), Ok(3));
+        assert_eq!(
+            OfflineQueue::take(&client_id),
+            vec![msg(2), msg(3), msg(4)]
+        );
+        OfflineQueue::set_limits(
+            super::OFFLINE_QUEUE_DEPTH,
+            super::OFFLINE_QUEUE_BYTES,
+            DropPolicy::DropOldest,
+        );
+    }
+}
@@ -21,15 +21,18 @@ PINGRESP message, returns the client back to the asleep state, and restarts the
 
 */
 
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use custom_debug::Debug;
 use getset::{CopyGetters, Getters, MutGetters};
 use std::mem;
 use std::str; // NOTE: needed for MutGetters
 
 use crate::{
-    broker_lib::MqttSnClient, eformat, function, msg_hdr::MsgHeader,
-    msg_hdr::*, ping_resp::PingResp, MSG_LEN_PINGREQ_HEADER, MSG_TYPE_PINGREQ,
+    asleep_msg_cache::AsleepMsgCache, broker_lib::MqttSnClient,
+    client_id::ClientId, connection::Connection, connection::StateEnum2,
+    eformat, function, keep_alive::KeepAliveTimeWheel, msg_hdr::MsgHeader,
+    msg_hdr::*, ping_resp::PingResp, publish::Publish, MsgIdType,
+    MSG_LEN_PINGREQ_HEADER, MSG_TYPE_PINGREQ,
 };
 
 #[derive(Debug, Clone, Getters, MutGetters, CopyGetters, Default)]
@@ -60,19 +63,90 @@ impl PingReq {
         client: &MqttSnClient,
         msg_header: MsgHeader,
     ) -> Result<(), String> {
-        match msg_header.header_len {
+        let remote_socket_addr = msg_header.remote_socket_addr;
+        let client_id = match msg_header.header_len {
             MsgHeaderLenEnum::Short => {
-                // TODO update ping timer.
-                let (_ping_req, _read_fixed_len) =
+                // TODO replace unwrap
+                let (ping_req, _read_fixed_len) =
                     PingReq::try_read(buf, size).unwrap();
+                ping_req.client_id
             }
             MsgHeaderLenEnum::Long => {
-                // TODO update ping timer.
-                let (_ping_req, _read_fixed_len) =
+                // TODO replace unwrap
+                let (ping_req, _read_fixed_len) =
                     PingReq4::try_read(buf, size).unwrap();
+                ping_req.client_id
+            }
+        };
+        // A sleeping client includes its client id, it must match the
+        // client id of the connection. The session follows a client that
+        // wakes up at a new address, e.g. after a NAT rebinding.
+        let client_id = Bytes::from(client_id);
+        if !client_id.is_empty()
+            && !ClientId::contains(&client_id, &remote_socket_addr)
+        {
+            if Connection::get_with_client_id(&client_id).is_none()
+                || Connection::contains_key(remote_socket_addr)
+            {
+                return Err(eformat!(
+                    remote_socket_addr,
+                    "client id not found",
+                    client_id
+                ));
             }
+            Connection::rebind(&client_id, remote_socket_addr)?;
+        }
+        match Connection::get_state(&remote_socket_addr) {
+            Ok(StateEnum2::ASLEEP) | Ok(StateEnum2::AWAKE) => {
+                PingReq::awake(client, msg_header)
+            }
+            _ => PingResp::send(client, msg_header),
+        }
+    }
+    /// MQTT-SN 1.2 spec section 6.14.
+    /// The client is awake, send the cached messages. The PINGRESP
+    /// closes the transfer after the QoS 1 & 2 messages are acknowledged,
+    /// then the client is back to asleep.
+    fn awake(
+        client: &MqttSnClient,
+        msg_header: MsgHeader,
+    ) -> Result<(), String> {
+        let remote_socket_addr = msg_header.remote_socket_addr;
+        Connection::update_state(&remote_socket_addr, StateEnum2::AWAKE)?;
+        let msg_id_vec = Publish::send_asleep_msgs(client, remote_socket_addr);
+        if msg_id_vec.is_empty() {
+            PingReq::asleep(client, msg_header)
+        } else {
+            AsleepMsgCache::insert_in_flight(remote_socket_addr, msg_id_vec);
+            Ok(())
         }
+    }
+    /// Send PINGRESP, return the client to asleep and restart the
+    /// sleep timer.
+    fn asleep(
+        client: &MqttSnClient,
+        msg_header: MsgHeader,
+    ) -> Result<(), String> {
+        let remote_socket_addr = msg_header.remote_socket_addr;
         PingResp::send(client, msg_header)?;
+        Connection::update_state(&remote_socket_addr, StateEnum2::ASLEEP)?;
+        KeepAliveTimeWheel::reschedule(remote_socket_addr)
+    }
+    /// Called for PUBACK and PUBCOMP, the last acknowledgement of the
+    /// cached messages returns the awake client to asleep.
+    pub fn recv_awake_ack(
+        msg_id: MsgIdType,
+        client: &MqttSnClient,
+        msg_header: MsgHeader,
+    ) -> Result<(), String> {
+        let remote_socket_addr = msg_header.remote_socket_addr;
+        if let Ok(StateEnum2::AWAKE) =
+            Connection::get_state(&remote_socket_addr)
+        {
+            if AsleepMsgCache::remove_in_flight(remote_socket_addr, msg_id) {
+                return PingReq::asleep(client, msg_header);
+            }
+        }
         Ok(())
     }
     #[inline(always)]
@@ -0,0 +1,115 @@
+//! Pre-defined topic ids.
+//! The topic id and topic name pairs are agreed with the clients in advance,
+//! the clients can PUBLISH and SUBSCRIBE with the topic id without REGISTER.
+//! The table is loaded from a JSON file at broker startup:
+//!     [{"topic_id": 1, "topic_name": "sensors/temp"}, ...]
+//! The pairs are also inserted into TOPIC_NAME_TO_IDS, so a PUBLISH to a
+//! pre-defined topic id reaches the subscribers of the topic name.
+//! The topic ids must be in the pre-defined range, from
+//! PRE_DEFINED_TOPIC_ID_MIN to TOPIC_ID_MAX.
+
+use hashbrown::HashMap;
+use serde::Deserialize;
+use std::fs;
+use std::sync::Mutex;
+
+use crate::{
+    eformat,
+    filter::try_register_topic_name,
+    function,
+    topic_registry::{PRE_DEFINED_TOPIC_ID_MIN, TOPIC_ID_MAX},
+    TopicIdType,
+};
+
+#[derive(Debug, Clone, Deserialize)]
+struct PreDefinedTopicEntry {
+    topic_id: TopicIdType,
+    topic_name: String,
+}
+
+lazy_static! {
+    static ref PRE_DEFINED_TOPICS: Mutex<HashMap<TopicIdType, String>> =
+        Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone)]
+pub struct PreDefinedTopics {}
+
+impl PreDefinedTopics {
+    /// Load the table from a JSON file, returns the number of entries.
+    pub fn load(path: &str) -> Result<usize, String> {
+        match fs::read_to_string(path) {
+            Ok(json) => PreDefinedTopics::load_str(&json),
+            Err(why) => Err(eformat!(path, why)),
+        }
+    }
+    /// Load the table from a JSON string, returns the number of entries.
+    pub fn load_str(json: &str) -> Result<usize, String> {
+        let entries: Vec<PreDefinedTopicEntry> =
+            match serde_json::from_str(json) {
+                Ok(entries) => entries,
+                Err(why) => return Err(eformat!(why)),
+            };
+        let len = entries.len();
+        for entry in entries {
+            PreDefinedTopics::insert(entry.topic_id, entry.topic_name)?;
+        }
+        Ok(len)
+    }
+    pub fn insert(
+        topic_id: TopicIdType,
+        topic_name: String,
+    ) -> Result<(), String> {
+        if topic_id < PRE_DEFINED_TOPIC_ID_MIN || topic_id > TOPIC_ID_MAX {
+            return Err(eformat!("not a pre-defined topic id", topic_id));
+        }
+        try_register_topic_name(topic_name.clone(), topic_id)?;
+        PRE_DEFINED_TOPICS
+            .lock()
+            .unwrap()
+            .insert(topic_id, topic_name);
+        Ok(())
+    }
+    pub fn contains(topic_id: &TopicIdType) -> bool {
+        PRE_DEFINED_TOPICS.lock().unwrap().contains_key(topic_id)
+    }
+    pub fn get(topic_id: &TopicIdType) -> Option<String> {
+        PRE_DEFINED_TOPICS.lock().unwrap().get(topic_id).cloned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_pre_defined_topics() {
+        use super::PreDefinedTopics;
+        use crate::filter::get_topic_id_with_topic_name;
+        let json = r#"[
+            {"topic_id": 61441, "topic_name": "pre/defined/1"},
+            {"topic_id": 61442, "topic_name": "pre/defined/2"}
+        ]"#;
+        assert_eq!(PreDefinedTopics::load_str(json).unwrap(), 2);
+        assert!(PreDefinedTopics::contains(&61441));
+        assert!(!PreDefinedTopics::contains(&61443));
+        assert_eq!(
+            PreDefinedTopics::get(&61442),
+            Some("pre/defined/2".to_string())
+        );
+        assert_eq!(
+            get_topic_id_with_topic_name("pre/defined/1".to_string()),
+            Some(61441)
+        );
+        // The topic name is already pre-defined with another topic id.
+        assert!(PreDefinedTopics::insert(61443, "pre/defined/1".to_string())
+            .is_err());
+        assert!(PreDefinedTopics::load_str("not json").is_err());
+        // Out of the pre-defined range.
+        assert!(
+            PreDefinedTopics::insert(5, "pre/defined/5".to_string()).is_err()
+        );
+        assert!(
+            PreDefinedTopics::insert(0xFFFF, "pre/defined/6".to_string())
+                .is_err()
+        );
+    }
+}
@@ -19,9 +19,13 @@ use std::mem;
 
 use crate::{
     broker_lib::MqttSnClient,
+    connection::Connection,
     eformat,
     function,
+    in_flight::InFlight,
     msg_hdr::MsgHeader,
+    ping_req::PingReq,
+    publish::Publish,
     retransmit::RetransTimeWheel,
     // flags::{flags_set, flag_qos_level, },
     MSG_LEN_PUBACK,
@@ -81,12 +85,16 @@ impl PubAck {
         let (pub_ack, read_len) = PubAck::try_read(buf, size).unwrap();
         dbg!(pub_ack.clone());
         if read_len == MSG_LEN_PUBACK as usize {
+            // Publish::send() schedules the retransmit with topic id 0.
             RetransTimeWheel::cancel_timer(
                 remote_socket_addr,
                 pub_ack.msg_type,
-                pub_ack.topic_id,
+                0,
                 pub_ack.msg_id,
             )?;
+            InFlight::release(&remote_socket_addr, pub_ack.msg_id);
+            let _msg_id_vec = Publish::send_queued(client, remote_socket_addr);
+            PingReq::recv_awake_ack(pub_ack.msg_id, client, msg_header)?;
             Ok(())
         } else {
             Err(eformat!(remote_socket_addr, "len err", read_len))
@@ -101,6 +109,8 @@ impl PubAck {
         msg_header: MsgHeader,
     ) -> Result<(), String> {
         let remote_socket_addr = msg_header.remote_socket_addr;
+        let return_code =
+            Connection::return_code(&remote_socket_addr, return_code);
         // faster implementation
         // TODO verify big-endian or little-endian for u16 numbers
         let msg_id_byte_1 = msg_id as u8;
@@ -17,7 +17,10 @@ use crate::{
     broker_lib::MqttSnClient,
     eformat,
     function,
+    in_flight::InFlight,
     msg_hdr::MsgHeader,
+    ping_req::PingReq,
+    publish::Publish,
     retransmit::RetransTimeWheel,
     // flags::{flags_set, flag_qos_level, },
     MSG_LEN_PUBCOMP,
@@ -95,6 +98,9 @@ impl PubComp {
                 0,
                 msg_id,
             )?;
+            InFlight::release(&remote_socket_addr, msg_id);
+            let _msg_id_vec = Publish::send_queued(client, remote_socket_addr);
+            PingReq::recv_awake_ack(msg_id, client, msg_header)?;
             Ok(())
         } else {
             Err(eformat!(remote_socket_addr, "size", buf[0]))
@@ -1,6 +1,9 @@
 /// Cache for published messages
 use hashbrown::HashMap;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use crate::MsgIdType;
 
@@ -10,9 +13,42 @@ use crate::publish::Publish;
 use crate::{eformat, function};
 use std::net::SocketAddr;
 
+/// Default seconds a QoS 1 msg_id is remembered for duplicate detection.
+/// Covers the retransmissions of the publisher, Tretry * Nretry.
+pub const QOS_1_DUP_WINDOW: u64 = 60;
+
+static DUP_WINDOW: AtomicU64 = AtomicU64::new(QOS_1_DUP_WINDOW);
+
 lazy_static! {
     static ref PUB_MSG_CACHE: Mutex<HashMap<(SocketAddr, MsgIdType), PubMsgCache>> =
         Mutex::new(HashMap::new());
+    // QoS 1 msg_ids received from each publisher and when.
+    static ref QOS_1_MSG_IDS: Mutex<HashMap<SocketAddr, Qos1MsgIds>> =
+        Mutex::new(HashMap::new());
+}
+
+/// The QoS 1 msg_ids of a publisher, the queue is in the order received
+/// and the expired msg_ids are pruned from its front.
+#[derive(Debug, Default)]
+struct Qos1MsgIds {
+    queue: VecDeque<(MsgIdType, Instant)>,
+    // The last time each msg_id was received.
+    received: HashMap<MsgIdType, Instant>,
+}
+
+impl Qos1MsgIds {
+    fn prune(&mut self, now: Instant, window: Duration) {
+        while let Some((msg_id, instant)) = self.queue.front().copied() {
+            if now.duration_since(instant) < window {
+                break;
+            }
+            self.queue.pop_front();
+            // The msg_id received again has a later entry in the queue.
+            if self.received.get(&msg_id) == Some(&instant) {
+                self.received.remove(&msg_id);
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -52,4 +88,103 @@ impl PubMsgCache {
         // need to clone the value because the value is borrowed.
         Some(val.clone())
     }
+
+    /// Move the messages of the publisher to its new socket_addr.
+    pub fn rebind(old_socket_addr: &SocketAddr, new_socket_addr: SocketAddr) {
+        let mut pub_cache = PUB_MSG_CACHE.lock().unwrap();
+        let key_vec: Vec<(SocketAddr, MsgIdType)> = pub_cache
+            .keys()
+            .filter(|(socket_addr, _)| socket_addr == old_socket_addr)
+            .copied()
+            .collect();
+        for key in key_vec {
+            if let Some(val) = pub_cache.remove(&key) {
+                pub_cache.insert((new_socket_addr, key.1), val);
+            }
+        }
+        let mut msg_ids = QOS_1_MSG_IDS.lock().unwrap();
+        if let Some(val) = msg_ids.remove(old_socket_addr) {
+            msg_ids.insert(new_socket_addr, val);
+        }
+    }
+
+    /// Forget the QoS 1 msg_ids of the publisher, its session is deleted.
+    pub fn delete_msg_ids(socket_addr: &SocketAddr) {
+        QOS_1_MSG_IDS.lock().unwrap().remove(socket_addr);
+    }
+
+    /// QoS 2 PUBLISH received, PUBREC sent and waiting for the PUBREL.
+    pub fn contains(key: (SocketAddr, MsgIdType)) -> bool {
+        PUB_MSG_CACHE.lock().unwrap().contains_key(&key)
+    }
+
+    /// Set the seconds a QoS 1 msg_id is remembered.
+    pub fn set_dup_window(seconds: u64) {
+        DUP_WINDOW.store(seconds, Ordering::Relaxed);
+    }
+
+    /// Record a QoS 1 PUBLISH, returns true if it is a retransmission of
+    /// a PUBLISH received within the window. Only a PUBLISH with the DUP
+    /// flag can be a retransmission, without it the publisher reuses the
+    /// msg_id for a new message.
+    pub fn is_qos_1_dup(key: (SocketAddr, MsgIdType), dup: bool) -> bool {
+        let window = Duration::from_secs(DUP_WINDOW.load(Ordering::Relaxed));
+        let now = Instant::now();
+        let (socket_addr, msg_id) = key;
+        let mut msg_ids = QOS_1_MSG_IDS.lock().unwrap();
+        let msg_ids = msg_ids.entry(socket_addr).or_default();
+        // Only the msg_ids of this publisher are pruned.
+        msg_ids.prune(now, window);
+        if dup && msg_ids.received.contains_key(&msg_id) {
+            return true;
+        }
+        msg_ids.received.insert(msg_id, now);
+        msg_ids.queue.push_back((msg_id, now));
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_qos_1_dup() {
+        use super::PubMsgCache;
+        use std::net::SocketAddr;
+
+        let socket = "127.0.0.101:1200".parse::<SocketAddr>().unwrap();
+        let socket2 = "127.0.0.102:1200".parse::<SocketAddr>().unwrap();
+        assert!(!PubMsgCache::is_qos_1_dup((socket, 1), false));
+        // Retransmission with the DUP flag.
+        assert!(PubMsgCache::is_qos_1_dup((socket, 1), true));
+        // New message with the same msg_id.
+        assert!(!PubMsgCache::is_qos_1_dup((socket, 1), false));
+        // Same msg_id from another publisher.
+        assert!(!PubMsgCache::is_qos_1_dup((socket2, 1), true));
+        assert!(!PubMsgCache::is_qos_1_dup((socket, 2), true));
+        PubMsgCache::delete_msg_ids(&socket);
+        assert!(!PubMsgCache::is_qos_1_dup((socket, 1), true));
+    }
+    #[test]
+    fn test_prune() {
+        use super::Qos1MsgIds;
+        use std::time::{Duration, Instant};
+
+        let window = Duration::from_secs(60);
+        let start = Instant::now();
+        let mut msg_ids = Qos1MsgIds::default();
+        for (msg_id, secs) in [(1, 0), (2, 10), (1, 20)] {
+            let instant = start + Duration::from_secs(secs);
+            msg_ids.received.insert(msg_id, instant);
+            msg_ids.queue.push_back((msg_id, instant));
+        }
+        // The msg_id 1 received again is still in the window.
+        msg_ids.prune(start + Duration::from_secs(65), window);
+        assert_eq!(msg_ids.queue.len(), 2);
+        assert!(msg_ids.received.contains_key(&1));
+        msg_ids.prune(start + Duration::from_secs(75), window);
+        assert_eq!(msg_ids.queue.len(), 1);
+        assert!(!msg_ids.received.contains_key(&2));
+        msg_ids.prune(start + Duration::from_secs(80), window);
+        assert!(msg_ids.queue.is_empty() && msg_ids.received.is_empty());
+    }
 }
@@ -33,13 +33,29 @@ use std::sync::Mutex;
 use trace_caller::trace;
 
 use crate::{
-    asleep_msg_cache::AsleepMsgCache, broker_lib::MqttSnClient, connection::*,
-    eformat, filter::*, flags::*, function, msg_hdr::*, pub_ack::PubAck,
-    pub_msg_cache::PubMsgCache, pub_rec::PubRec, retain_cache::*,
-    retransmit::RetransTimeWheel, MSG_LEN_PUBACK, MSG_LEN_PUBLISH_HEADER,
-    MSG_LEN_PUBREC, MSG_TYPE_CONNACK, MSG_TYPE_CONNECT, MSG_TYPE_PUBACK,
-    MSG_TYPE_PUBCOMP, MSG_TYPE_PUBLISH, MSG_TYPE_PUBREC, MSG_TYPE_PUBREL,
-    MSG_TYPE_SUBACK, MSG_TYPE_SUBSCRIBE, RETURN_CODE_ACCEPTED,
+    asleep_msg_cache::AsleepMsgCache,
+    broker_lib::MqttSnClient,
+    congestion::Congestion,
+    connection::*,
+    eformat,
+    filter::*,
+    flags::*,
+    function,
+    in_flight::{InFlight, QueuedPublish},
+    msg_hdr::*,
+    offline_queue::{OfflineMsg, OfflineQueue},
+    pre_defined_topic::PreDefinedTopics,
+    pub_ack::PubAck,
+    pub_msg_cache::PubMsgCache,
+    pub_rec::PubRec,
+    register::{DeferredPublish, Register},
+    retain_cache::*,
+    retransmit::RetransTimeWheel,
+    MsgIdType, MSG_LEN_PUBACK, MSG_LEN_PUBLISH_HEADER, MSG_LEN_PUBREC,
+    MSG_TYPE_CONNACK, MSG_TYPE_CONNECT, MSG_TYPE_PUBACK, MSG_TYPE_PUBCOMP,
+    MSG_TYPE_PUBLISH, MSG_TYPE_PUBREC, MSG_TYPE_PUBREL, MSG_TYPE_SUBACK,
+    MSG_TYPE_SUBSCRIBE, RETURN_CODE_ACCEPTED, RETURN_CODE_CONGESTION,
+    RETURN_CODE_INVALID_TOPIC_ID,
 };
 
 #[derive(Debug, Clone, Default)]
@@ -157,6 +173,87 @@ impl Publish {
         let remote_socket_addr = msg_header.remote_socket_addr;
         dbg!((size, _read_fixed_len));
         dbg!(publish.clone());
+        // The topic id field is echoed in the PUBACK.
+        let wire_topic_id = publish.topic_id;
+        if Congestion::detect(client, &remote_socket_addr, MSG_TYPE_PUBLISH) {
+            // No reply to a QoS -1 sender, it may not have a connection.
+            if flag_qos_level(publish.flags) != QOS_LEVEL_3 {
+                PubAck::send(
+                    wire_topic_id,
+                    publish.msg_id,
+                    RETURN_CODE_CONGESTION,
+                    client,
+                    msg_header,
+                )?;
+            }
+            return Err(eformat!(remote_socket_addr, "congestion"));
+        }
+        match flag_topic_id_type(publish.flags) {
+            TOPIC_ID_TYPE_SHORT => {
+                // The topic id field contains the short topic name.
+                // Route it with the topic id of the name, so the wildcard
+                // filters and the retained messages work as for a normal
+                // topic name.
+                let topic_name = short_topic_id_to_name(publish.topic_id)?;
+                if flag_qos_level(publish.flags) == QOS_LEVEL_3 {
+                    // The QoS -1 sender may be spoofed, it doesn't
+                    // allocate a topic id for the name.
+                    match get_topic_id_with_topic_name(topic_name.clone()) {
+                        Some(topic_id) => publish.topic_id = topic_id,
+                        None => {
+                            return Publish::send_short_to_subscribers(
+                                &topic_name,
+                                publish,
+                                client,
+                            )
+                        }
+                    }
+                } else {
+                    publish.topic_id = match try_insert_topic_name(topic_name) {
+                        Ok(topic_id) => topic_id,
+                        Err(why) => {
+                            // The topic ids are exhausted.
+                            if flag_qos_level(publish.flags) != QOS_LEVEL_0 {
+                                PubAck::send(
+                                    wire_topic_id,
+                                    publish.msg_id,
+                                    RETURN_CODE_CONGESTION,
+                                    client,
+                                    msg_header,
+                                )?;
+                            }
+                            return Err(eformat!(remote_socket_addr, why));
+                        }
+                    };
+                }
+            }
+            TOPIC_ID_TYPE_PRE_DEFINED
+                if !PreDefinedTopics::contains(&publish.topic_id) =>
+            {
+                // No reply to a QoS -1 sender, it may not have a connection.
+                if flag_qos_level(publish.flags) != QOS_LEVEL_3 {
+                    PubAck::send(
+                        publish.topic_id,
+                        publish.msg_id,
+                        RETURN_CODE_INVALID_TOPIC_ID,
+                        client,
+                        msg_header,
+                    )?;
+                }
+                return Err(eformat!(
+                    remote_socket_addr,
+                    "unknown pre-defined topic id",
+                    publish.topic_id
+                ));
+            }
+            TOPIC_ID_TYPE_RESERVED => {
+                return Err(eformat!(
+                    remote_socket_addr,
+                    "topic Id reserved type"
+                ));
+            }
+            _ => {}
+        }
         let subscriber_vec = get_subscribers_with_topic_id(publish.topic_id);
         dbg!(&subscriber_vec);
         // TODO check QoS, https://www.hivemq.com/blog/mqtt-essentials-
@@ -174,6 +271,20 @@ impl Publish {
                 // 4. Send PUBLISH message to subscribers from PUBREL.rx.
 
                 //dbg!(&client);
+                let key = (remote_socket_addr, publish.msg_id);
+                if PubMsgCache::contains(key) {
+                    if flag_is_dup(publish.flags) {
+                        // PUBREC lost, ack again without delivering,
+                        // the retransmit of PUBREC is still scheduled.
+                        PubRec::send(publish.msg_id, client, msg_header)?;
+                        return Ok(());
+                    }
+                    return Err(eformat!(
+                        remote_socket_addr,
+                        "msg_id in use",
+                        publish.msg_id
+                    ));
+                }
                 let bytes = PubRec::send(publish.msg_id, client, msg_header)?;
                 // PUBREL message doesn't have topic id.
                 // For the time wheel hash, default to 0.
@@ -182,36 +293,51 @@ impl Publish {
                     MSG_TYPE_PUBREL,
                     0,
                     publish.msg_id,
-                    1,
                     bytes,
                 )?;
                 // cache the publish message and the subscribers to send when PUBREL is received
                 // from the publisher. The remote_addr and msg_id are used as the key because they
                 // are part the message.
-                let msg_id = publish.msg_id; // copy the msg_id so publish can be used in the hash
                 let cache = PubMsgCache {
                     publish,
                     subscriber_vec,
                 };
-                PubMsgCache::try_insert((remote_socket_addr, msg_id), cache)?;
+                PubMsgCache::try_insert(key, cache)?;
                 return Ok(());
             }
             QOS_LEVEL_1 => {
                 // send PUBACK to PUBLISH client
                 PubAck::send(
-                    publish.topic_id,
+                    wire_topic_id,
                     publish.msg_id,
                     RETURN_CODE_ACCEPTED,
                     client,
                     msg_header,
                 )?;
+                // PUBACK lost, acked again without fanning out.
+                if PubMsgCache::is_qos_1_dup(
+                    (remote_socket_addr, publish.msg_id),
+                    flag_is_dup(publish.flags),
+                ) {
+                    return Ok(());
+                }
             }
             QOS_LEVEL_0 => {}
             QOS_LEVEL_3 => {
-                return Err(eformat!(
-                    remote_socket_addr,
-                    "QoS level 3 is not supported"
-                ));
+                // QoS -1, MQTT-SN 1.2 spec section 6.8.
+                // The sender may not have a connection, no acknowledgement.
+                // Only pre-defined topic ids and short topic names are
+                // allowed, because the sender can't REGISTER.
+                match flag_topic_id_type(publish.flags) {
+                    TOPIC_ID_TYPE_PRE_DEFINED | TOPIC_ID_TYPE_SHORT => {}
+                    _ => {
+                        return Err(eformat!(
+                            remote_socket_addr,
+                            "QoS -1 topic Id type not supported",
+                            flag_topic_id_type(publish.flags)
+                        ));
+                    }
+                }
             }
             _ => {
                 // Should never happen because flag_qos_level() filters for 4 cases only.
@@ -230,9 +356,6 @@ impl Publish {
             }
         }
         Publish::send_msg_to_subscribers(subscriber_vec, publish, client)?;
-
-        // TODO check dup, likely not dup
-        //
         Ok(())
     }
 
@@ -241,18 +364,139 @@ impl Publish {
     /// 2. Serialize into a byte stream.
     /// 3. Send it to the channel.
     /// 4. Schedule retransmit for QoS Level 1 & 2.
+    /// The msg_id is allocated from the session of the subscriber,
+    /// returns the msg_id, 0x0000 for QoS 0 & -1.
     #[inline(always)]
     #[trace]
     pub fn send(
         topic_id: u16,
-        msg_id: u16,
         qos: u8,
         retain: u8,
         // data: BytesMut,
         data: Bytes,
         client: &MqttSnClient, // contains the address of the publisher
         remote_addr: SocketAddr, // address of the subscriber
-    ) -> Result<(), String> {
+    ) -> Result<MsgIdType, String> {
+        // A topic with a short topic name is sent with the name in the
+        // topic id field, no REGISTER is needed.
+        let short_topic_id = get_topic_name_with_topic_id(topic_id)
+            .and_then(|topic_name| short_topic_name_to_id(&topic_name));
+        let (topic_id_type, topic_id) = match short_topic_id {
+            Some(short_topic_id) => (TOPIC_ID_TYPE_SHORT, short_topic_id),
+            // The clients know the pre-defined topic ids, no REGISTER.
+            None if PreDefinedTopics::contains(&topic_id) => {
+                (TOPIC_ID_TYPE_PRE_DEFINED, topic_id)
+            }
+            None => {
+                // A subscriber of a wildcard filter learns the topic id
+                // from a REGISTER before the first PUBLISH.
+                if !Register::send_if_unregistered(
+                    topic_id,
+                    client,
+                    remote_addr,
+                )? {
+                    let publish = DeferredPublish { qos, retain, data };
+                    // The msg_id is allocated when the PUBLISH is sent.
+                    Register::defer_publish(remote_addr, topic_id, publish)?;
+                    return Ok(0);
+                }
+                (TOPIC_ID_TYPE_NORMAL, topic_id)
+            }
+        };
+        Publish::send_with_type(
+            topic_id_type,
+            topic_id,
+            qos,
+            retain,
+            data,
+            client,
+            remote_addr,
+        )
+    }
+
+    /// Send a PUBLISH with the topic id field of the topic id type, the
+    /// QoS 1 & 2 messages wait for a window in flight.
+    fn send_with_type(
+        topic_id_type: u8,
+        topic_id: u16,
+        qos: u8,
+        retain: u8,
+        data: Bytes,
+        client: &MqttSnClient,
+        remote_addr: SocketAddr,
+    ) -> Result<MsgIdType, String> {
+        let msg_id = match qos {
+            QOS_LEVEL_1 | QOS_LEVEL_2 => {
+                let publish = QueuedPublish {
+                    topic_id_type,
+                    topic_id,
+                    qos,
+                    retain,
+                    data: data.clone(),
+                };
+                match InFlight::alloc_publish(remote_addr, publish)? {
+                    Some(msg_id) => msg_id,
+                    // Sent when a PUBACK or PUBCOMP opens the window.
+                    None => return Ok(0),
+                }
+            }
+            _ => 0,
+        };
+        Publish::transmit(
+            topic_id_type,
+            topic_id,
+            qos,
+            retain,
+            msg_id,
+            data,
+            client,
+            remote_addr,
+        )
+    }
+    /// Send the PUBLISH messages queued for the in-flight window of the
+    /// client, when a PUBACK or PUBCOMP closes an exchange.
+    /// Returns the msg_ids sent.
+    pub fn send_queued(
+        client: &MqttSnClient,
+        remote_addr: SocketAddr,
+    ) -> Vec<MsgIdType> {
+        let mut msg_id_vec = Vec::new();
+        let state = Connection::get_state(&remote_addr);
+        if !matches!(state, Ok(StateEnum2::ACTIVE) | Ok(StateEnum2::AWAKE)) {
+            return msg_id_vec;
+        }
+        while let Some((msg_id, publish)) = InFlight::pop_queued(&remote_addr) {
+            match Publish::transmit(
+                publish.topic_id_type,
+                publish.topic_id,
+                publish.qos,
+                publish.retain,
+                msg_id,
+                publish.data,
+                client,
+                remote_addr,
+            ) {
+                Ok(msg_id) => msg_id_vec.push(msg_id),
+                Err(why) => error!("{}", why),
+            }
+        }
+        if matches!(state, Ok(StateEnum2::AWAKE)) {
+            // The awake client is back to asleep after these too.
+            AsleepMsgCache::add_in_flight(remote_addr, &msg_id_vec);
+        }
+        msg_id_vec
+    }
+    /// Format and send a PUBLISH with the msg_id allocated.
+    fn transmit(
+        topic_id_type: u8,
+        topic_id: u16,
+        qos: u8,
+        retain: u8,
+        msg_id: MsgIdType,
+        data: Bytes,
+        client: &MqttSnClient,
+        remote_addr: SocketAddr,
+    ) -> Result<MsgIdType, String> {
         let len = data.len() + MSG_LEN_PUBLISH_HEADER as usize;
         let mut bytes_buf = BytesMut::with_capacity(len);
         // TODO verify that this is correct
@@ -262,8 +506,8 @@ impl Publish {
             retain,
             WILL_FALSE,          // not used
             CLEAN_SESSION_FALSE, // not used
-            TOPIC_ID_TYPE_NORMAL,
-        ); // default for now
+            topic_id_type,
+        );
 
         // TODO verify big-endian or little-endian for u16 numbers
         // XXX order of statements performance
@@ -297,6 +541,7 @@ impl Publish {
             ];
             bytes_buf.put(buf);
         } else {
+            InFlight::release(&remote_addr, msg_id);
             return Err(eformat!(remote_addr, "len too long", len));
         }
         bytes_buf.put(data);
@@ -313,7 +558,6 @@ impl Publish {
                     MSG_TYPE_PUBACK,
                     0,
                     msg_id,
-                    10,
                     bytes_buf.clone(),
                 )?;
             }
@@ -337,7 +581,6 @@ impl Publish {
                     MSG_TYPE_PUBREC,
                     0,
                     msg_id,
-                    1,
                     bytes_buf.clone(),
                 )?;
             }
@@ -349,10 +592,62 @@ impl Publish {
         }
         // transmit message to remote address
         match client.egress_tx.try_send((remote_addr, bytes_buf)) {
-            Ok(_) => Ok(()),
+            Ok(_) => Ok(msg_id),
             Err(why) => Err(eformat!(remote_addr, why)),
         }
     }
+    /// Send the messages cached for a sleeping client when it is awake.
+    /// Returns the msg_ids of the QoS 1 & 2 messages, the client is
+    /// back to asleep after they are acknowledged.
+    pub fn send_asleep_msgs(
+        client: &MqttSnClient,
+        remote_addr: SocketAddr,
+    ) -> Vec<MsgIdType> {
+        // The messages queued for the window before the client fell asleep.
+        let mut msg_id_vec = Publish::send_queued(client, remote_addr);
+        for publish in AsleepMsgCache::delete(remote_addr) {
+            let qos = flag_qos_level(publish.flags);
+            match Publish::send(
+                publish.topic_id,
+                qos,
+                RETAIN_FALSE,
+                publish.data.freeze(),
+                client,
+                remote_addr,
+            ) {
+                Ok(msg_id) => {
+                    // msg_id 0 for QoS 0 or a PUBLISH waiting for REGACK.
+                    if msg_id != 0 {
+                        msg_id_vec.push(msg_id);
+                    }
+                }
+                Err(why) => error!("{}", why),
+            }
+        }
+        msg_id_vec
+    }
+    /// Send the messages queued while the client was offline, after the
+    /// CONNACK of a persistent session.
+    pub fn send_offline_msgs(client: &MqttSnClient, remote_addr: SocketAddr) {
+        let client_id = match Connection::get(&remote_addr) {
+            Some(conn) => conn.client_id,
+            None => return,
+        };
+        // The messages queued for the window before the client was lost.
+        let _msg_id_vec = Publish::send_queued(client, remote_addr);
+        for msg in OfflineQueue::take(&client_id) {
+            if let Err(why) = Publish::send(
+                msg.topic_id,
+                msg.qos,
+                RETAIN_FALSE,
+                msg.data,
+                client,
+                remote_addr,
+            ) {
+                error!("{}", why);
+            }
+        }
+    }
     /// send PUBLISH messages to subscribers
     pub fn send_msg_to_subscribers(
         subscriber_vec: Vec<Subscriber>,
@@ -370,7 +665,6 @@ impl Publish {
                         // Send now
                         let _result = Publish::send(
                             publish.topic_id,
-                            publish.msg_id,
                             subscriber.qos,
                             RETAIN_FALSE,
                             publish.data.clone().freeze(),
@@ -378,15 +672,42 @@ impl Publish {
                             subscriber.socket_addr,
                         );
                     }
-                    StateEnum2::ASLEEP => {
-                        // Cache the publish instance,
-                        // send it when the client sends a PingRequest.
-                        AsleepMsgCache::insert(
-                            subscriber.socket_addr,
-                            publish.clone(),
+                    StateEnum2::ASLEEP | StateEnum2::AWAKE => {
+                        // Cache the publish instance with the QoS of the
+                        // subscriber, send it when the client sends a
+                        // PingRequest.
+                        let mut publish = publish.clone();
+                        publish.flags = flags_set(
+                            DUP_FALSE,
+                            subscriber.qos,
+                            RETAIN_FALSE,
+                            WILL_FALSE,          // not used
+                            CLEAN_SESSION_FALSE, // not used
+                            TOPIC_ID_TYPE_NORMAL,
                         );
+                        AsleepMsgCache::insert(subscriber.socket_addr, publish);
+                    }
+                    StateEnum2::LOST | StateEnum2::DISCONNECTED => {
+                        // Queue the QoS 1 & 2 messages of a persistent
+                        // session, send them when the client reconnects.
+                        if subscriber.qos != QOS_LEVEL_1
+                            && subscriber.qos != QOS_LEVEL_2
+                        {
+                            continue;
+                        }
+                        if let Some(conn) =
+                            Connection::get(&subscriber.socket_addr)
+                        {
+                            if conn.is_persistent() {
+                                let msg = OfflineMsg {
+                                    topic_id: publish.topic_id,
+                                    qos: subscriber.qos,
+                                    data: publish.data.clone().freeze(),
+                                };
+                                OfflineQueue::push(&conn.client_id, msg);
+                            }
+                        }
                     }
-                    _ => {}
                 },
                 Err(why) => {
                     error!("{}", why);
@@ -397,4 +718,52 @@ impl Publish {
         }
         Ok(())
     }
+    /// Send a PUBLISH with a short topic name without a topic id to the
+    /// subscribers of the matching wildcard filters, with the name in the
+    /// topic id field. Only the ACTIVE subscribers get it, the sleeping
+    /// sessions cache the messages by topic id. The message isn't
+    /// retained.
+    fn send_short_to_subscribers(
+        topic_name: &String,
+        publish: Publish,
+        client: &MqttSnClient,
+    ) -> Result<(), String> {
+        let mut subscriber_vec = get_subscribers_with_topic_name(topic_name);
+        let shared_vec =
+            get_shared_subscribers_with_topic_name(topic_name, &subscriber_vec);
+        for subscriber in shared_vec {
+            if !subscriber_vec
+                .iter()
+                .any(|sub| sub.socket_addr == subscriber.socket_addr)
+            {
+                subscriber_vec.push(subscriber);
+            }
+        }
+        // Delivered with the lower of the publisher and subscriber QoS,
+        // a QoS -1 message goes out with QoS 0.
+        let publish_qos = match flag_qos_level(publish.flags) {
+            QOS_LEVEL_3 => QOS_LEVEL_0,
+            qos => qos,
+        };
+        for subscriber in subscriber_vec {
+            if !matches!(
+                Connection::get_state(&subscriber.socket_addr),
+                Ok(StateEnum2::ACTIVE)
+            ) {
+                continue;
+            }
+            if let Err(why) = Publish::send_with_type(
+                TOPIC_ID_TYPE_SHORT,
+                publish.topic_id,
+                subscriber.qos.min(publish_qos),
+                RETAIN_FALSE,
+                publish.data.clone().freeze(),
+                client,
+                subscriber.socket_addr,
+            ) {
+                error!("{}", why);
+            }
+        }
+        Ok(())
+    }
 }
@@ -17,7 +17,8 @@ use getset::{CopyGetters, Getters, MutGetters};
 use std::mem;
 
 use crate::{
-    broker_lib::MqttSnClient, eformat, function, msg_hdr::MsgHeader,
+    broker_lib::MqttSnClient, connection::Connection, eformat, function,
+    msg_hdr::MsgHeader, publish::Publish, register::Register,
     retransmit::RetransTimeWheel, MSG_LEN_REGACK, MSG_TYPE_REGACK,
 };
 
@@ -43,15 +44,30 @@ impl RegAck {
 
         let remote_socket_addr = msg_header.remote_socket_addr;
         if read_len == MSG_LEN_REGACK as usize {
-            match RetransTimeWheel::cancel_timer(
+            let cancel_result = RetransTimeWheel::cancel_timer(
                 remote_socket_addr,
                 reg_ack.msg_type,
                 reg_ack.topic_id,
                 reg_ack.msg_id,
-            ) {
-                Ok(()) => Ok(()),
-                Err(err) => Err(err),
+            );
+            // Deliver the PUBLISH messages waiting for the topic id.
+            let publish_vec = Register::recv_ack(
+                remote_socket_addr,
+                reg_ack.topic_id,
+                reg_ack.msg_id,
+                reg_ack.return_code,
+            )?;
+            for publish in publish_vec {
+                Publish::send(
+                    reg_ack.topic_id,
+                    publish.qos,
+                    publish.retain,
+                    publish.data,
+                    client,
+                    remote_socket_addr,
+                )?;
             }
+            cancel_result
         } else {
             Err(eformat!(remote_socket_addr, "size", buf[0]))
         }
@@ -69,7 +85,10 @@ impl RegAck {
             msg_type: MSG_TYPE_REGACK,
             topic_id,
             msg_id,
-            return_code,
+            return_code: Connection::return_code(
+                &remote_socket_addr,
+                return_code,
+            ),
         };
         let mut bytes_buf = BytesMut::with_capacity(MSG_LEN_REGACK as usize);
         dbg!(reg_ack.clone());
@@ -13,19 +13,43 @@ Length    MsgType TopicId MsgId TopicName
 (octet 0) (1)     (2,3)   (4:5) (6:n)
 Table 14: REGISTER Message
 */
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use custom_debug::Debug;
 use getset::{CopyGetters, Getters, MutGetters};
+use hashbrown::HashMap;
 use log::*;
 use std::mem;
+use std::net::SocketAddr;
 use std::str;
+use std::sync::Mutex;
 
 use crate::{
-    broker_lib::MqttSnClient, eformat, filter::get_topic_id_with_topic_name,
-    function, msg_hdr::*, reg_ack::RegAck, retransmit::RetransTimeWheel,
+    broker_lib::MqttSnClient, congestion::Congestion, eformat, filter::*,
+    function, in_flight::InFlight, msg_hdr::*, reg_ack::RegAck,
+    retransmit::RetransTimeWheel, MsgIdType, TopicIdType,
     MSG_LEN_REGISTER_HEADER, MSG_TYPE_REGACK, MSG_TYPE_REGISTER,
-    RETURN_CODE_ACCEPTED, RETURN_CODE_INVALID_TOPIC_ID,
+    RETURN_CODE_ACCEPTED, RETURN_CODE_CONGESTION, RETURN_CODE_INVALID_TOPIC_ID,
 };
+
+/// A PUBLISH to a subscriber, waiting for the REGACK of its topic id.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeferredPublish {
+    pub qos: u8,
+    pub retain: u8,
+    pub data: Bytes,
+}
+
+#[derive(Debug, Clone)]
+struct PendingRegister {
+    msg_id: MsgIdType,
+    publish_vec: Vec<DeferredPublish>,
+}
+
+lazy_static! {
+    // REGISTER messages sent by the gateway, waiting for the REGACK.
+    static ref PENDING_REGISTER: Mutex<HashMap<(SocketAddr, TopicIdType), PendingRegister>> =
+        Mutex::new(HashMap::new());
+}
 #[derive(Debug, Clone, Getters, MutGetters, CopyGetters, Default)]
 #[getset(get, set)]
 pub struct Register {
@@ -78,8 +102,39 @@ impl Register {
                     Register::try_read(&buf[3..], size).unwrap();
             }
         }
-        match get_topic_id_with_topic_name(register.topic_name) {
-            Some(topic_id) => {
+        dbg!(&register);
+        let remote_socket_addr = msg_header.remote_socket_addr;
+        if Congestion::detect(client, &remote_socket_addr, MSG_TYPE_REGISTER) {
+            RegAck::send(
+                0,
+                register.msg_id,
+                RETURN_CODE_CONGESTION,
+                client,
+                msg_header,
+            )?;
+            return Err(eformat!(remote_socket_addr, "congestion"));
+        }
+        // Topic names with wildcards can't be published to.
+        if register.topic_name.is_empty() || has_wildcards(&register.topic_name)
+        {
+            RegAck::send(
+                0,
+                register.msg_id,
+                RETURN_CODE_INVALID_TOPIC_ID,
+                client,
+                msg_header,
+            )?;
+            return Err(eformat!(
+                remote_socket_addr,
+                "invalid topic name",
+                register.topic_name
+            ));
+        }
+        // Existing topic names get their topic id, new ones are assigned
+        // the next free topic id.
+        match try_insert_topic_name(register.topic_name) {
+            Ok(topic_id) => {
+                insert_registered_topic_id(remote_socket_addr, topic_id);
                 RegAck::send(
                     topic_id,
                     register.msg_id,
@@ -87,25 +142,136 @@ impl Register {
                     client,
                     msg_header,
                 )?;
+                Ok(())
             }
-            None => {
+            Err(why) => {
                 RegAck::send(
                     0,
                     register.msg_id,
-                    RETURN_CODE_INVALID_TOPIC_ID,
+                    RETURN_CODE_CONGESTION,
                     client,
                     msg_header,
                 )?;
+                Err(eformat!(remote_socket_addr, why))
             }
+        }
+    }
+    /// Inform a subscriber about the topic id of a topic name before the
+    /// first PUBLISH on it. Needed for topics matched by a wildcard filter,
+    /// because the SUBACK of a wildcard subscription has topic id 0x0000.
+    /// Returns true if the subscriber knows the topic id, otherwise the
+    /// PUBLISH must wait for the REGACK, see defer_publish().
+    pub fn send_if_unregistered(
+        topic_id: TopicIdType,
+        client: &MqttSnClient,
+        remote_socket_addr: SocketAddr,
+    ) -> Result<bool, String> {
+        if is_topic_id_known(&remote_socket_addr, &topic_id) {
+            return Ok(true);
+        }
+        // Without a topic name, e.g. pre-defined topic id, there is
+        // nothing to register.
+        let topic_name = match get_topic_name_with_topic_id(topic_id) {
+            Some(topic_name) => topic_name,
+            None => return Ok(true),
         };
-        Ok(())
+        let key = (remote_socket_addr, topic_id);
+        if PENDING_REGISTER.lock().unwrap().contains_key(&key) {
+            // REGISTER already sent, waiting for the REGACK.
+            return Ok(false);
+        }
+        let msg_id = InFlight::alloc(remote_socket_addr, MSG_TYPE_REGISTER)?;
+        Register::send(
+            topic_id,
+            msg_id,
+            topic_name,
+            client,
+            remote_socket_addr,
+        )?;
+        PENDING_REGISTER.lock().unwrap().insert(
+            key,
+            PendingRegister {
+                msg_id,
+                publish_vec: Vec::new(),
+            },
+        );
+        Ok(false)
+    }
+    /// Hold a PUBLISH to a subscriber until it acknowledged the REGISTER
+    /// of the topic id.
+    pub fn defer_publish(
+        remote_socket_addr: SocketAddr,
+        topic_id: TopicIdType,
+        publish: DeferredPublish,
+    ) -> Result<(), String> {
+        match PENDING_REGISTER
+            .lock()
+            .unwrap()
+            .get_mut(&(remote_socket_addr, topic_id))
+        {
+            Some(pending) => {
+                pending.publish_vec.push(publish);
+                Ok(())
+            }
+            None => Err(eformat!(remote_socket_addr, "no REGISTER", topic_id)),
+        }
+    }
+    /// Called for a REGACK from a client. Returns the PUBLISH messages
+    /// waiting for the REGACK, empty if the client rejected the topic id.
+    pub fn recv_ack(
+        remote_socket_addr: SocketAddr,
+        topic_id: TopicIdType,
+        msg_id: MsgIdType,
+        return_code: u8,
+    ) -> Result<Vec<DeferredPublish>, String> {
+        let key = (remote_socket_addr, topic_id);
+        let mut pending_map = PENDING_REGISTER.lock().unwrap();
+        match pending_map.get(&key) {
+            Some(pending) if pending.msg_id == msg_id => {}
+            _ => {
+                return Err(eformat!(remote_socket_addr, topic_id, msg_id));
+            }
+        }
+        let pending = pending_map.remove(&key).unwrap();
+        InFlight::release(&remote_socket_addr, msg_id);
+        if return_code != RETURN_CODE_ACCEPTED {
+            warn!(
+                "REGISTER rejected: {} {} {}",
+                remote_socket_addr, topic_id, return_code
+            );
+            return Ok(Vec::new());
+        }
+        insert_registered_topic_id(remote_socket_addr, topic_id);
+        Ok(pending.publish_vec)
+    }
+    /// The REGISTER messages pending on the previous connection are
+    /// dropped when the client connects again.
+    pub fn delete_pending(remote_socket_addr: &SocketAddr) {
+        PENDING_REGISTER
+            .lock()
+            .unwrap()
+            .retain(|(socket_addr, _), _| socket_addr != remote_socket_addr);
+    }
+    /// Move the pending REGISTERs to the new socket_addr of the client.
+    pub fn rebind(old_socket_addr: &SocketAddr, new_socket_addr: SocketAddr) {
+        let mut pending_map = PENDING_REGISTER.lock().unwrap();
+        let key_vec: Vec<(SocketAddr, TopicIdType)> = pending_map
+            .keys()
+            .filter(|(socket_addr, _)| socket_addr == old_socket_addr)
+            .copied()
+            .collect();
+        for key in key_vec {
+            if let Some(pending) = pending_map.remove(&key) {
+                pending_map.insert((new_socket_addr, key.1), pending);
+            }
+        }
     }
     pub fn send(
         topic_id: u16,
         msg_id: u16,
         topic_name: String,
         client: &MqttSnClient,
-        msg_header: MsgHeader,
+        remote_socket_addr: SocketAddr,
     ) -> Result<(), String> {
         // new way to format a message
         let len = MSG_LEN_REGISTER_HEADER as usize + topic_name.len() as usize;
@@ -122,7 +288,6 @@ impl Register {
         } else {
             return Err(eformat!("len is too big", len));
         }
-        let remote_socket_addr = msg_header.remote_socket_addr;
         buf.put_u8(MSG_TYPE_REGISTER);
         buf.put_u16(topic_id);
         buf.put_u16(msg_id);
@@ -140,7 +305,6 @@ impl Register {
             MSG_TYPE_REGACK,
             topic_id,
             msg_id,
-            1,
             buf,
         ) {
             Ok(()) => Ok(()),
@@ -148,3 +312,47 @@ impl Register {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_pending_register() {
+        use super::PENDING_REGISTER;
+        use super::{DeferredPublish, PendingRegister, Register};
+        use bytes::Bytes;
+        use std::net::SocketAddr;
+
+        let socket = "127.0.0.81:1200".parse::<SocketAddr>().unwrap();
+        let publish = DeferredPublish {
+            qos: 1,
+            retain: 0,
+            data: Bytes::from_static(b"hello"),
+        };
+        // No REGISTER sent for the topic id.
+        assert!(Register::defer_publish(socket, 7, publish.clone()).is_err());
+        PENDING_REGISTER.lock().unwrap().insert(
+            (socket, 7),
+            PendingRegister {
+                msg_id: 100,
+                publish_vec: Vec::new(),
+            },
+        );
+        Register::defer_publish(socket, 7, publish.clone()).unwrap();
+        // REGACK with a wrong msg_id.
+        assert!(Register::recv_ack(socket, 7, 101, 0).is_err());
+        assert_eq!(Register::recv_ack(socket, 7, 100, 0), Ok(vec![publish]));
+        assert!(crate::filter::is_topic_id_known(&socket, &7));
+        // Already acknowledged.
+        assert!(Register::recv_ack(socket, 7, 100, 0).is_err());
+
+        PENDING_REGISTER.lock().unwrap().insert(
+            (socket, 8),
+            PendingRegister {
+                msg_id: 102,
+                publish_vec: Vec::new(),
+            },
+        );
+        Register::delete_pending(&socket);
+        assert!(Register::recv_ack(socket, 8, 102, 0).is_err());
+    }
+}
@@ -1,28 +1,38 @@
 /// Cache the retained messages.
 /// If the retain flag is set, cache the message in hashmap and save it to the database.
 /// The cache is used to send retained messages when a client subscribes to the topic.
-/// The messages are also saved and retrieved from MongoDB.
+/// The messages are also saved and retrieved from a RetainStore.
 use bytes::Bytes;
 use crossbeam::channel::*;
 use hashbrown::HashMap;
 use log::*;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
 
 use crate::{
     broker_lib::MqttSnClient,
+    filter::{get_topic_name_with_topic_id, match_topic},
     flags::QoSConst,
     flags::*,
-    mongodb::*,
     publish::Publish,
+    retain_store::RetainStore,
     MsgIdType,
     // eformat,
     // function,
     TopicIdType,
 };
 
+/// The subscription of a new subscriber, for sending the retained messages.
 #[derive(Debug, Clone)]
+pub enum RetainSub {
+    TopicId(TopicIdType),
+    // Topic filter with wildcards.
+    Filter(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Retain {
     pub qos: QoSConst,
     pub topic_id: TopicIdType,
@@ -33,26 +43,39 @@ pub struct Retain {
 #[derive(Debug, Clone)]
 pub struct RetainCache {
     hash_map: Arc<Mutex<HashMap<TopicIdType, Retain>>>,
-    db: Option<RetainDb>,
+    db: Arc<dyn RetainStore>,
 }
 impl RetainCache {
-    pub fn new(url: &str) -> Self {
+    /// The store is opened with retain_store::open_retain_store().
+    pub fn new(db: Arc<dyn RetainStore>) -> Self {
         Self {
             hash_map: Arc::new(Mutex::new(HashMap::new())),
-            db: RetainDb::new(url),
+            db,
+        }
+    }
+    /// Number of retained messages, the cache if the store fails.
+    pub fn count(&self) -> usize {
+        match self.db.len() {
+            Ok(len) => len,
+            Err(why) => {
+                error!("{}", why);
+                self.hash_map.lock().unwrap().len()
+            }
         }
     }
     fn insert(&mut self, retain: Retain) {
         let mut hash_map = self.hash_map.lock().unwrap();
+        if retain.payload.is_empty() {
+            // A zero-length retained message deletes the retained message.
+            hash_map.remove(&retain.topic_id);
+            if let Err(why) = self.db.delete(retain.topic_id) {
+                error!("{}", why);
+            }
+            return;
+        }
         hash_map.insert(retain.topic_id, retain.clone());
-        if let Some(db) = &self.db {
-            db.upsert(
-                retain.qos,
-                retain.topic_id,
-                "msg_id".to_string(),
-                retain.msg_id,
-                &retain.payload[..],
-            );
+        if let Err(why) = self.db.upsert(&retain) {
+            error!("{}", why);
         }
     }
     fn get(&mut self, topic_id: &TopicIdType) -> Option<Retain> {
@@ -63,35 +86,77 @@ impl RetainCache {
                 dbg!(&retain);
                 Some(retain.clone())
             }
-            None => {
-                // not in the cache, get from MongoDB.
-                if let Some(db) = &self.db {
-                    match db.get_with_topic_id(*topic_id) {
-                        Ok(retain_doc) => {
-                            dbg!(&retain_doc);
-                            // Convert the serde_bytes into slice.
-                            let msg = retain_doc.msg.as_slice();
-                            dbg!(&msg);
-                            let retain = Retain {
-                                qos: retain_doc.qos,
-                                topic_id: retain_doc.topic_id,
-                                msg_id: retain_doc.msg_id,
-                                // Convert slice into Bytes.
-                                payload: Bytes::copy_from_slice(&msg[..]),
-                            };
-                            // insert the value into the cache.
-                            hash_map.insert(*topic_id, retain.clone());
-                            return Some(retain);
-                        }
-                        Err(_) => {
-                            // Not in MongoDB, return None.
-                            ();
-                        }
-                    }
+            // not in the cache, get from the store.
+            None => match self.db.get(*topic_id) {
+                Ok(Some(retain)) => {
+                    dbg!(&retain);
+                    // insert the value into the cache.
+                    hash_map.insert(*topic_id, retain.clone());
+                    Some(retain)
+                }
+                Ok(None) => None,
+                Err(why) => {
+                    error!("{}", why);
+                    None
+                }
+            },
+        }
+    }
+    /// The retained messages of the topics matching a wildcard filter.
+    /// Only the topic ids are read from the store, the payloads of the
+    /// matching topics are read when they aren't in the cache.
+    fn get_with_filter(&mut self, filter: &str) -> Vec<Retain> {
+        let mut hash_map = self.hash_map.lock().unwrap();
+        let topic_id_vec = match self.db.topic_ids() {
+            Ok(topic_id_vec) => topic_id_vec,
+            Err(why) => {
+                error!("{}", why);
+                hash_map.keys().copied().collect()
+            }
+        };
+        let mut retain_vec = Vec::new();
+        for topic_id in topic_id_vec {
+            match get_topic_name_with_topic_id(topic_id) {
+                Some(topic_name) if match_topic(&topic_name, filter) => (),
+                _ => continue,
+            }
+            if let Some(retain) = hash_map.get(&topic_id) {
+                retain_vec.push(retain.clone());
+                continue;
+            }
+            match self.db.get(topic_id) {
+                Ok(Some(retain)) => {
+                    hash_map.insert(topic_id, retain.clone());
+                    retain_vec.push(retain);
                 }
-                None
+                Ok(None) => (),
+                Err(why) => error!("{}", why),
             }
         }
+        retain_vec
+    }
+    /// Send a retained message to a new subscriber, with the lower QoS of
+    /// the message and the subscription. QoS -1 messages are sent as QoS 0.
+    fn send(
+        retain: &Retain,
+        qos: QoSConst,
+        client: &MqttSnClient,
+        socket_addr: SocketAddr,
+    ) {
+        let qos = match (retain.qos, qos) {
+            (QOS_LEVEL_3, _) | (_, QOS_LEVEL_3) => QOS_LEVEL_0,
+            (retain_qos, qos) => retain_qos.min(qos),
+        };
+        if let Err(why) = Publish::send(
+            retain.topic_id,
+            qos,
+            RETAIN_TRUE,
+            retain.payload.clone(),
+            client,
+            socket_addr,
+        ) {
+            error!("{}", why);
+        }
     }
     pub fn run(&mut self, client: MqttSnClient) {
         let client2 = client.clone();
@@ -103,15 +168,25 @@ impl RetainCache {
                 recv(&client2.sub_retain_rx) -> msg => {
                     dbg!(&msg);
                     match msg {
-                        Ok((socket_addr, topic_id)) => {
+                        Ok((socket_addr, RetainSub::TopicId(topic_id), qos)) => {
                             if let Some(retain) = self2.get(&topic_id) {
                                 dbg!(&retain);
-                                let _result = Publish::send(
-                                    retain.topic_id,
-                                    retain.msg_id,
-                                    retain.qos,
-                                    RETAIN_FALSE,
-                                    retain.payload.clone(),
+                                RetainCache::send(
+                                    &retain,
+                                    qos,
+                                    &client2,
+                                    socket_addr,
+                                );
+                            }
+                        }
+                        // Publish::send() sends the REGISTER of the topic
+                        // id before the PUBLISH.
+                        Ok((socket_addr, RetainSub::Filter(filter), qos)) => {
+                            for retain in self2.get_with_filter(&filter) {
+                                dbg!(&retain);
+                                RetainCache::send(
+                                    &retain,
+                                    qos,
                                     &client2,
                                     socket_addr,
                                 );
@@ -162,7 +237,6 @@ impl RetainCache {
                     if let Some(retain) = self2.hash_map.get(&topic_id) {
                         let _result = Publish::send(
                             retain.topic_id,
-                            retain.msg_id,
                             retain.qos,
                             RETAIN_FALSE,
                             retain.payload.clone(),
@@ -182,6 +256,40 @@ impl RetainCache {
 
 #[cfg(test)]
 mod test {
+    #[test]
+    fn test_retain_with_filter() {
+        use super::{Retain, RetainCache};
+        use crate::filter::try_insert_topic_name;
+        use crate::flags::QOS_LEVEL_1;
+        use crate::retain_store::open_retain_store;
+        use bytes::Bytes;
+
+        let mut cache = RetainCache::new(open_retain_store("memory").unwrap());
+        let mut topic_id_vec = Vec::new();
+        for topic_name in ["retain/a/1", "retain/b/1", "retain/a/2"] {
+            let topic_id =
+                try_insert_topic_name(topic_name.to_string()).unwrap();
+            cache.insert(Retain {
+                qos: QOS_LEVEL_1,
+                topic_id,
+                msg_id: 1,
+                payload: Bytes::from_static(b"hello"),
+            });
+            topic_id_vec.push(topic_id);
+        }
+        assert_eq!(cache.get_with_filter("retain/+/1").len(), 2);
+        assert_eq!(cache.get_with_filter("retain/#").len(), 3);
+        assert!(cache.get_with_filter("other/#").is_empty());
+        // A zero-length retained message deletes the retained message.
+        cache.insert(Retain {
+            qos: QOS_LEVEL_1,
+            topic_id: topic_id_vec[0],
+            msg_id: 2,
+            payload: Bytes::new(),
+        });
+        assert!(cache.get(&topic_id_vec[0]).is_none());
+        assert_eq!(cache.get_with_filter("retain/+/1").len(), 1);
+    }
     /*
         #[test]
         fn test_retain() {
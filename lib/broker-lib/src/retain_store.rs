@@ -0,0 +1,211 @@
+//! Storage backends of the retained messages behind the RetainCache.
+//! The backend is chosen with a configuration string:
+//!     "memory"                    no persistence, the default.
+//!     "sled:<path>"               sled database in the path.
+//!     "mongodb://..." or "mongodb+srv://..."
+//!                                 MongoDB, needs the "mongodb" feature.
+//! The payload is stored as bytes, it doesn't have to be UTF-8.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use core::fmt::Debug;
+use hashbrown::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::{eformat, function, retain_cache::Retain, TopicIdType};
+
+pub trait RetainStore: Debug + Send + Sync {
+    fn upsert(&self, retain: &Retain) -> Result<(), String>;
+    fn get(&self, topic_id: TopicIdType) -> Result<Option<Retain>, String>;
+    fn get_all(&self) -> Result<Vec<Retain>, String>;
+    /// The topic ids of the retained messages, without the payloads.
+    fn topic_ids(&self) -> Result<Vec<TopicIdType>, String>;
+    fn delete(&self, topic_id: TopicIdType) -> Result<(), String>;
+    /// Number of retained messages, without reading them.
+    fn len(&self) -> Result<usize, String>;
+    fn is_empty(&self) -> Result<bool, String> {
+        Ok(self.len()? == 0)
+    }
+}
+
+/// Open the backend of the configuration string.
+pub fn open_retain_store(config: &str) -> Result<Arc<dyn RetainStore>, String> {
+    if config.is_empty() || config == "memory" {
+        return Ok(Arc::new(MemRetainStore::new()));
+    }
+    if let Some(path) = config.strip_prefix("sled:") {
+        return Ok(Arc::new(SledRetainStore::new(path)?));
+    }
+    if config.starts_with("mongodb://") || config.starts_with("mongodb+srv://")
+    {
+        #[cfg(feature = "mongodb")]
+        match crate::mongodb::RetainDb::new(config) {
+            Some(db) => return Ok(Arc::new(db)),
+            None => return Err(eformat!("can't connect to MongoDB")),
+        }
+        #[cfg(not(feature = "mongodb"))]
+        return Err(eformat!("built without the mongodb feature"));
+    }
+    Err(eformat!("unknown retain store", config))
+}
+
+#[derive(Debug, Clone)]
+pub struct MemRetainStore {
+    hash_map: Arc<Mutex<HashMap<TopicIdType, Retain>>>,
+}
+
+impl MemRetainStore {
+    pub fn new() -> Self {
+        MemRetainStore {
+            hash_map: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl RetainStore for MemRetainStore {
+    fn upsert(&self, retain: &Retain) -> Result<(), String> {
+        let mut hash_map = self.hash_map.lock().unwrap();
+        hash_map.insert(retain.topic_id, retain.clone());
+        Ok(())
+    }
+    fn get(&self, topic_id: TopicIdType) -> Result<Option<Retain>, String> {
+        Ok(self.hash_map.lock().unwrap().get(&topic_id).cloned())
+    }
+    fn get_all(&self) -> Result<Vec<Retain>, String> {
+        Ok(self.hash_map.lock().unwrap().values().cloned().collect())
+    }
+    fn topic_ids(&self) -> Result<Vec<TopicIdType>, String> {
+        Ok(self.hash_map.lock().unwrap().keys().copied().collect())
+    }
+    fn delete(&self, topic_id: TopicIdType) -> Result<(), String> {
+        self.hash_map.lock().unwrap().remove(&topic_id);
+        Ok(())
+    }
+    fn len(&self) -> Result<usize, String> {
+        Ok(self.hash_map.lock().unwrap().len())
+    }
+}
+
+/// The key is the topic id, the value is QoS(1) MsgId(2) Payload(n).
+#[derive(Debug, Clone)]
+pub struct SledRetainStore {
+    db: sled::Db,
+}
+
+impl SledRetainStore {
+    pub fn new(path: &str) -> Result<Self, String> {
+        match sled::open(path) {
+            Ok(db) => Ok(SledRetainStore { db }),
+            Err(why) => Err(eformat!(path, why)),
+        }
+    }
+    fn encode(retain: &Retain) -> BytesMut {
+        let mut buf = BytesMut::with_capacity(3 + retain.payload.len());
+        buf.put_u8(retain.qos);
+        buf.put_u16(retain.msg_id);
+        buf.put(retain.payload.clone());
+        buf
+    }
+    fn decode(key: &[u8], value: &[u8]) -> Result<Retain, String> {
+        if key.len() != 2 || value.len() < 3 {
+            return Err(eformat!(key, "len err", value.len()));
+        }
+        Ok(Retain {
+            qos: value[0],
+            topic_id: u16::from_be_bytes([key[0], key[1]]),
+            msg_id: u16::from_be_bytes([value[1], value[2]]),
+            payload: Bytes::copy_from_slice(&value[3..]),
+        })
+    }
+}
+
+impl RetainStore for SledRetainStore {
+    fn upsert(&self, retain: &Retain) -> Result<(), String> {
+        let key = retain.topic_id.to_be_bytes();
+        match self.db.insert(key, &SledRetainStore::encode(retain)[..]) {
+            Ok(_) => Ok(()),
+            Err(why) => Err(eformat!(retain.topic_id, why)),
+        }
+    }
+    fn get(&self, topic_id: TopicIdType) -> Result<Option<Retain>, String> {
+        let key = topic_id.to_be_bytes();
+        match self.db.get(key) {
+            Ok(Some(value)) => Ok(Some(SledRetainStore::decode(&key, &value)?)),
+            Ok(None) => Ok(None),
+            Err(why) => Err(eformat!(topic_id, why)),
+        }
+    }
+    fn get_all(&self) -> Result<Vec<Retain>, String> {
+        let mut retain_vec = Vec::new();
+        for result in self.db.iter() {
+            match result {
+                Ok((key, value)) => {
+                    retain_vec.push(SledRetainStore::decode(&key, &value)?)
+                }
+                Err(why) => return Err(eformat!(why)),
+            }
+        }
+        Ok(retain_vec)
+    }
+    fn topic_ids(&self) -> Result<Vec<TopicIdType>, String> {
+        let mut topic_id_vec = Vec::new();
+        for result in self.db.iter().keys() {
+            match result {
+                Ok(key) if key.len() == 2 => {
+                    topic_id_vec.push(u16::from_be_bytes([key[0], key[1]]))
+                }
+                Ok(key) => return Err(eformat!(key, "len err")),
+                Err(why) => return Err(eformat!(why)),
+            }
+        }
+        Ok(topic_id_vec)
+    }
+    fn delete(&self, topic_id: TopicIdType) -> Result<(), String> {
+        match self.db.remove(topic_id.to_be_bytes()) {
+            Ok(_) => Ok(()),
+            Err(why) => Err(eformat!(topic_id, why)),
+        }
+    }
+    fn len(&self) -> Result<usize, String> {
+        Ok(self.db.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    fn check_store(store: &dyn super::RetainStore) {
+        use crate::retain_cache::Retain;
+        use bytes::Bytes;
+
+        // Not UTF-8.
+        let retain = Retain {
+            qos: 0b_0_01_0_0_0_00,
+            topic_id: 0x1234,
+            msg_id: 7,
+            payload: Bytes::from_static(&[0xff, 0x00, 0xfe]),
+        };
+        store.upsert(&retain).unwrap();
+        assert_eq!(store.get(0x1234).unwrap(), Some(retain.clone()));
+        assert_eq!(store.get(0x1235).unwrap(), None);
+        assert_eq!(store.get_all().unwrap(), vec![retain]);
+        assert_eq!(store.topic_ids().unwrap(), vec![0x1234]);
+        assert_eq!(store.len().unwrap(), 1);
+        store.delete(0x1234).unwrap();
+        assert_eq!(store.get(0x1234).unwrap(), None);
+        assert!(store.get_all().unwrap().is_empty());
+        assert_eq!(store.len().unwrap(), 0);
+    }
+    #[test]
+    fn test_mem_retain_store() {
+        let store = super::open_retain_store("memory").unwrap();
+        check_store(store.as_ref());
+    }
+    #[test]
+    fn test_sled_retain_store() {
+        let path = std::env::temp_dir().join("test_sled_retain_store");
+        let _ = std::fs::remove_dir_all(&path);
+        let config = format!("sled:{}", path.display());
+        let store = super::open_retain_store(&config).unwrap();
+        check_store(store.as_ref());
+        assert!(super::open_retain_store("redis://localhost").is_err());
+    }
+}
@@ -1,28 +1,26 @@
-use crate::{broker_lib::MqttSnClient, connection::*, eformat, function};
+use crate::{
+    broker_lib::MqttSnClient, connection::*, eformat, flags::DUP_TRUE,
+    function, in_flight::InFlight, keep_alive::KeepAliveTimeWheel, rtt::Rtt,
+    rtt::MAX_RTO, session_expiry::SessionExpiry, sys_stats::SysStats,
+    MSG_TYPE_PUBACK, MSG_TYPE_PUBCOMP, MSG_TYPE_PUBLISH, MSG_TYPE_PUBREC,
+    MSG_TYPE_REGACK, PROTOCOL_ID_V2_0,
+};
 use bytes::BytesMut;
 // use core::fmt::Debug;
 use core::hash::Hash;
 use custom_debug::Debug;
-use hashbrown::HashMap;
 use log::*;
 use std::net::SocketAddr;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
-use trace_var::trace_var;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use timing_wheel::{driver, MonotonicClock, ShardedWheel};
 
-/// RetransmitHeader is stored in:
-/// (1) HashMap for cancellation from an ACK
-/// (2) timing wheel slots for timeouts.
-/// When the wheel reads a slot, it iterates all entries in the vector.
-/// Using the RetransmitHeader to get/remove the RetransmitData
-/// in the HashMap.
-/// If the new duration is greater that the maximum timeout period
-/// the HashMap entry will be removed.
-/// To cancel a scheduled event, remove the HashMap entry with the
-/// RetransmitHeader. Don't need to remove the entry in the slot
-/// because the slot entry lookup ignores missing HashMap entries.
+/// RetransmitHeader is the key of the timer in the timing wheel,
+/// an ACK cancels the timer with the RetransmitHeader.
+/// On a timeout the message is retransmitted and the timer restarts
+/// with the doubled timeout, up to MAX_RTO. After Nretry
+/// retransmissions the client is lost.
 #[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
 struct RetransmitHeader {
     pub addr: SocketAddr,
@@ -35,131 +33,88 @@ struct RetransmitHeader {
 #[derive(Debug, Clone)]
 struct RetransmitData {
     pub bytes: BytesMut, // TODO use Bytes instead.
+    pub timeout: Duration,
+    // The time of the first transmission, for the RTT sample.
+    pub sent: Instant,
+    pub retries: u8,
 }
 
-#[derive(Debug, Clone)]
-struct Slot {
-    pub entries: Arc<Mutex<Vec<(RetransmitHeader, u16)>>>,
-}
+static SLEEP_DURATION: u64 = 100;
 
-impl Slot {
-    pub fn new() -> Self {
-        Slot {
-            entries: Arc::new(Mutex::new(Vec::new())),
-        }
-    }
-}
+/// MQTT-SN 1.2 spec section 6.13 Nretry, 3-5.
+pub const DEFAULT_N_RETRY: u8 = 5;
 
-static SLEEP_DURATION: usize = 100;
-static MAX_SLOT: usize = (1000 / SLEEP_DURATION) * 64 * 2;
+static N_RETRY: AtomicU8 = AtomicU8::new(DEFAULT_N_RETRY);
 
-// TODO use lazy_static for easy access from any code without
-// attaching to a structure.
 lazy_static! {
-    static ref CURRENT_COUNTER: AtomicU64 = AtomicU64::new(0);
-    static ref SLOT_VEC: Mutex<Vec<Slot>> =
-        Mutex::new(Vec::with_capacity(MAX_SLOT));
-    static ref TIME_WHEEL_MAP: Mutex<HashMap<RetransmitHeader, RetransmitData>> =
-        Mutex::new(HashMap::new());
+    static ref TIME_WHEEL: Arc<ShardedWheel<RetransmitHeader, RetransmitData>> =
+        Arc::new(ShardedWheel::new(
+            Duration::from_millis(SLEEP_DURATION),
+            Arc::new(MonotonicClock::new()),
+        ));
 }
 
-// TODO only for retransmit timing wheel.
-// The initial duration is set to TIME_WHEEL_INIT_DURATION, but can be
-// changed to reflect the network the client is on, (LAN or WAN),
-// or the latency pattern.
-
-// clients use 100 milli seconds
-// brokers use 10 milli seconds
-/// static TIME_WHEEL_SLEEP_DURATION:usize = 100; // in milli seconds
-// For 64 seconds at 100 ms sleeping interval, we need 10 * 64 slots, (1000/100 = 10)
-// For 64 seconds at 10 ms sleeping interval, we need 100 * 64 slots, (1000/10 = 100)
+/// The exchange is closed without the ACK, the msg_id allocated by
+/// the broker can be reused.
+fn release_msg_id(retrans_hdr: &RetransmitHeader) {
+    match retrans_hdr.msg_type {
+        MSG_TYPE_PUBACK | MSG_TYPE_PUBREC | MSG_TYPE_PUBCOMP
+        | MSG_TYPE_REGACK => {
+            InFlight::release(&retrans_hdr.addr, retrans_hdr.msg_id);
+        }
+        _ => {}
+    }
+}
 
-// The maximum timeout duration is 64 seconds
-// The last one might be over 64, rounding up.
-/// static TIME_WHEEL_MAX_SLOTS:usize = (1000 / TIME_WHEEL_SLEEP_DURATION) * 64 * 2;
-// Initial timeout duration is 300 ms
-// static TIME_WHEEL_DEFAULT_DURATION_MS: usize = 300;
+/// Set the DUP flag of a retransmitted PUBLISH, the other messages
+/// are retransmitted unchanged.
+/// MQTT-SN 1.2 spec section 5.3.4
+fn set_dup(bytes: &mut BytesMut) {
+    // The 3 bytes length starts with 0x01.
+    let offset = if bytes.first() == Some(&1) { 3 } else { 1 };
+    if bytes.len() > offset + 1 && bytes[offset] == MSG_TYPE_PUBLISH {
+        bytes[offset + 1] |= DUP_TRUE;
+    }
+}
 
-/// Timing wheel for keep alive.
-/// The wheel is divided into MAX_SLOT slots.
-/// Each slot is a vector of SocketAddr.
-/// The data is stored in a HashMap indexed by the SocketAddr.
+/// Timing wheel for retransmission.
+/// The timers are in a timing_wheel::ShardedWheel indexed by the
+/// RetransmitHeader.
 pub struct RetransTimeWheel {}
 
 impl RetransTimeWheel {
-    pub fn init() {
-        let mut slot_vec = SLOT_VEC.lock().unwrap();
-        for _ in 0..MAX_SLOT {
-            slot_vec.push(Slot::new());
-        }
+    /// The number of retransmissions before the client is lost.
+    pub fn set_n_retry(n_retry: u8) {
+        N_RETRY.store(n_retry, Ordering::Relaxed);
     }
-
-    // The initial duration is set to TIME_WHEEL_INIT_DURATION, but can be
-    // changed to reflect the network the client is on, (LAN or WAN),
-    // or the latency pattern.
+    /// The initial timeout is the RTO of the client, see Rtt.
     #[inline(always)]
     pub fn schedule_timer(
         addr: SocketAddr,
         msg_type: u8,
         topic_id: u16,
         msg_id: u16,
-        duration: u16,
         bytes: BytesMut,
     ) -> Result<(), String> {
-        // store the retrans_hdr in a slot of the timing wheel
-        // TODO XXX change value 10 to a constant
         let retrans_hdr = RetransmitHeader {
             addr,
             msg_type,
             topic_id,
             msg_id,
         };
-        let val = RetransmitData { bytes };
-        let duration = duration * 10;
-        let cur_counter = CURRENT_COUNTER.load(Ordering::Relaxed) as usize;
-        let index = (cur_counter + duration as usize) % MAX_SLOT;
-        match TIME_WHEEL_MAP.try_lock() {
-            Ok(mut map) => {
-                map.insert(retrans_hdr, val);
-            }
-            Err(why) => {
-                return Err(eformat!(retrans_hdr, why.to_string()));
-            }
-        }
-        match SLOT_VEC.try_lock() {
-            Ok(mut slot_vec) => {
-                let slot = &mut slot_vec[index];
-                match slot.entries.try_lock() {
-                    Ok(mut entries) => {
-                        entries.push((retrans_hdr, duration));
-                    }
-                    Err(why) => {
-                        // unwind: remove the inserted retrans_hdr from the map
-                        if let None =
-                            TIME_WHEEL_MAP.lock().unwrap().remove(&retrans_hdr)
-                        {
-                            return Err(eformat!(retrans_hdr, "key not found"));
-                        }
-                        return Err(eformat!(retrans_hdr, why.to_string()));
-                    }
-                }
-            }
-            Err(why) => {
-                // unwind: remove the inserted retrans_hdr from the map
-                if let None =
-                    TIME_WHEEL_MAP.lock().unwrap().remove(&retrans_hdr)
-                {
-                    return Err(eformat!("key not found"));
-                }
-                return Err(eformat!(why.to_string()));
-            }
-        }
-        return Ok(());
+        let timeout = Rtt::rto(&addr);
+        let val = RetransmitData {
+            bytes,
+            timeout,
+            sent: Instant::now(),
+            retries: 0,
+        };
+        let _old_val = TIME_WHEEL.insert(retrans_hdr, val, timeout);
+        Ok(())
     }
-    /// Reschedule a keep alive event when it received a message from the sender.
-    /// Modify the latest_counter in the TIME_WHEEL_MAP to the current counter.
+    /// Cancel the retransmission when it received the ACK from the
+    /// receiver.
     #[inline(always)]
-    #[trace_var(index, slot, hash, vec)]
     pub fn cancel_timer(
         addr: SocketAddr,
         msg_type: u8,
@@ -172,101 +127,165 @@ impl RetransTimeWheel {
             topic_id,
             msg_id,
         };
-        match TIME_WHEEL_MAP.try_lock() {
-            Ok(mut map) => {
-                if let None = map.remove(&retrans_hdr) {
-                    return Err(eformat!(retrans_hdr, "not found."));
+        match TIME_WHEEL.remove(&retrans_hdr) {
+            Some(val) => {
+                // Karn's algorithm, no sample from a retransmitted message.
+                if val.retries == 0 {
+                    Rtt::sample(addr, val.sent.elapsed());
                 }
                 Ok(())
             }
-            Err(why) => Err(eformat!(retrans_hdr, why.to_string())),
+            None => Err(eformat!(retrans_hdr, "not found.")),
         }
     }
 
-    /// When the address(key) is expired in the timing wheel, it compare the latest_counter
-    /// with the current counter. If the latest_counter is less than the current counter,
-    /// the address(key) is expired. Otherwise, put it back to a new slot.
+    /// Move the pending retransmissions to the new socket_addr of the
+    /// client, they are retransmitted to the new socket_addr after the
+    /// RTO.
+    pub fn rebind(
+        addr: &SocketAddr,
+        new_addr: SocketAddr,
+    ) -> Result<(), String> {
+        let moved_vec =
+            TIME_WHEEL.remove_if(|retrans_hdr, _| retrans_hdr.addr == *addr);
+        for (retrans_hdr, mut val) in moved_vec {
+            let retrans_hdr = RetransmitHeader {
+                addr: new_addr,
+                ..retrans_hdr
+            };
+            val.timeout = Rtt::rto(&new_addr);
+            let timeout = val.timeout;
+            TIME_WHEEL.insert(retrans_hdr, val, timeout);
+        }
+        Ok(())
+    }
+
+    /// Cancel all the pending retransmissions to the addr, e.g. the
+    /// session at the addr is closed.
+    pub fn cancel_addr(addr: &SocketAddr) -> Result<(), String> {
+        let _removed_vec =
+            TIME_WHEEL.remove_if(|retrans_hdr, _| retrans_hdr.addr == *addr);
+        Ok(())
+    }
+
+    /// Retries exhausted, the client is lost and its will is published.
+    /// MQTT-SN 1.2 spec section 6.13
+    fn lost(socket_addr: &SocketAddr, client: &MqttSnClient) {
+        info!("Retransmit Timeout: {:?}", socket_addr);
+        match Connection::update_state(socket_addr, StateEnum2::LOST) {
+            Ok(_) => {
+                let _result = KeepAliveTimeWheel::cancel(socket_addr);
+                let _result = Connection::publish_will(socket_addr, client);
+                // The session of a lost MQTT-SN 2.0 client expires
+                // like a disconnected one.
+                if let Some(conn) = Connection::get(socket_addr) {
+                    if conn.protocol_id == PROTOCOL_ID_V2_0
+                        && conn.session_expiry > 0
+                    {
+                        SessionExpiry::schedule(
+                            conn.client_id,
+                            conn.session_expiry,
+                        );
+                    }
+                }
+            }
+            Err(why) => {
+                error!("{}", eformat!(socket_addr, why));
+            }
+        }
+    }
+
+    /// On a timeout, retransmit the message with the doubled timeout,
+    /// after Nretry retransmissions the client is lost.
     pub fn run(client: MqttSnClient) {
-        // When the keep_alive timing wheel entry is accessed,
-        // this code determines if the connection is expired.
-        // If the hash entry has been updated to a new counter,
-        // then reschedule the connection in the timing wheel.
-        //
-        // TODO replace lock with try_lock
-        let _retrans_expire_thread = thread::spawn(move || {
-            loop {
-                // The sleep() has to be outside of the mutex lock block for
-                // the lock to be unlocked while the thread is sleeping.
-                thread::sleep(Duration::from_millis(SLEEP_DURATION as u64));
-                {
-                    let cur_counter: usize;
-                    cur_counter = CURRENT_COUNTER
-                        .fetch_add(1, Ordering::Relaxed)
-                        as usize;
-                    // dbg!(&cur_slot);
-                    // dbg!(cur_counter);
-                    let slot_vec = SLOT_VEC.lock().unwrap();
-                    let index = cur_counter % MAX_SLOT;
-                    let mut slot = slot_vec[index].entries.lock().unwrap();
-                    let mut map = TIME_WHEEL_MAP.lock().unwrap();
-                    // process the expired connections
-                    while let Some((retrans_hdr, mut duration)) = slot.pop() {
-                        match Connection::get_state(&retrans_hdr.addr) {
-                            Ok(state) => match state {
-                                StateEnum2::ACTIVE => (), // drop through
-                                _ => {
-                                    map.remove(&retrans_hdr);
-                                    info!("Retransmit Timer Cancel: incorrect state: {:?} {:?}",
-                                    state, retrans_hdr);
-                                }
-                            },
-                            Err(why) => {
-                                map.remove(&retrans_hdr);
-                                error!(
-                                    "Retransmit Timer Cancel: {} {:?}",
-                                    why, retrans_hdr
-                                );
-                            }
-                        }
-                        dbg!(index);
-                        duration *= 2;
-                        dbg!((duration, MAX_SLOT));
-                        if duration < (MAX_SLOT as u16) {
-                            // not expired, reschedule to new slot, don't remove hash entry
-                            if let Some(retrans_data) = map.get(&retrans_hdr) {
-                                let mut new_index = (cur_counter
-                                    + duration as usize)
-                                    % MAX_SLOT;
-                                dbg!((new_index, index));
-                                if new_index == index {
-                                    // Can't lock the same slot twice
-                                    // Even without lock, push() to the same slot will be popped
-                                    // in the while loop, so it's an infinite loop.
-                                    // Use the next slot instead.
-                                    new_index = (index + 1) % MAX_SLOT;
-                                }
-                                dbg!((new_index, index));
-                                let mut new_slot =
-                                    slot_vec[new_index].entries.lock().unwrap();
-                                new_slot.push((retrans_hdr, duration));
-                                // Retransmit the message to the receiver.
-                                if let Err(err) = client.egress_tx.send((
-                                    retrans_hdr.addr,
-                                    retrans_data.bytes.clone(),
-                                )) {
-                                    error!("{:?} {:?}", err, retrans_hdr);
-                                    dbg!((new_index, index));
-                                }
-                                dbg!(retrans_hdr);
-                            }
-                        } else {
-                            // The connection is expired, remove the hash entry
-                            map.remove(&retrans_hdr);
-                            info!("Retransmit Timeout: {:?}", retrans_hdr);
+        let lost_client = client.clone();
+        // The will is published after the poll, it schedules
+        // retransmissions in the locked wheel.
+        let _handle = driver::spawn_tokio_with(
+            TIME_WHEEL.clone(),
+            move |retrans_hdr, val, lost_vec: &mut Vec<SocketAddr>| {
+                match Connection::get_state(&retrans_hdr.addr) {
+                    Ok(state) => match state {
+                        // drop through, an awake client receives
+                        // the messages cached while asleep.
+                        StateEnum2::ACTIVE | StateEnum2::AWAKE => (),
+                        _ => {
+                            release_msg_id(retrans_hdr);
+                            info!(
+                                "Retransmit Timer Cancel: incorrect state: {:?} {:?}",
+                                state, retrans_hdr
+                            );
+                            return None;
                         }
+                    },
+                    Err(why) => {
+                        release_msg_id(retrans_hdr);
+                        error!(
+                            "Retransmit Timer Cancel: {} {:?}",
+                            why, retrans_hdr
+                        );
+                        return None;
                     }
                 }
-            }
-        });
+                if val.retries >= N_RETRY.load(Ordering::Relaxed) {
+                    release_msg_id(retrans_hdr);
+                    SysStats::dropped();
+                    if !lost_vec.contains(&retrans_hdr.addr) {
+                        lost_vec.push(retrans_hdr.addr);
+                    }
+                    return None;
+                }
+                if val.retries == 0 {
+                    set_dup(&mut val.bytes);
+                }
+                val.retries += 1;
+                val.timeout = (val.timeout * 2).min(MAX_RTO);
+                debug!(
+                    "Retransmit: {:?} {} {:?}",
+                    retrans_hdr, val.retries, val.timeout
+                );
+                // Retransmit the message to the receiver.
+                match client
+                    .egress_tx
+                    .send((retrans_hdr.addr, val.bytes.clone()))
+                {
+                    Ok(()) => SysStats::retransmitted(),
+                    Err(err) => error!("{:?} {:?}", err, retrans_hdr),
+                }
+                Some(val.timeout)
+            },
+            move |lost_vec| {
+                for socket_addr in lost_vec {
+                    RetransTimeWheel::lost(&socket_addr, &lost_client);
+                }
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_set_dup() {
+        use super::set_dup;
+        use crate::{flags::*, MSG_TYPE_PUBLISH, MSG_TYPE_PUBREL};
+        use bytes::BytesMut;
+
+        let mut bytes =
+            BytesMut::from(&[7, MSG_TYPE_PUBLISH, QOS_LEVEL_1, 0, 1, 0, 2][..]);
+        set_dup(&mut bytes);
+        assert!(flag_is_dup(bytes[2]));
+        assert_eq!(flag_qos_level(bytes[2]), QOS_LEVEL_1);
+
+        let mut bytes = BytesMut::from(
+            &[1, 0, 9, MSG_TYPE_PUBLISH, QOS_LEVEL_2, 0, 1, 0, 2][..],
+        );
+        set_dup(&mut bytes);
+        assert!(flag_is_dup(bytes[4]));
+
+        // PUBREL has no flags.
+        let mut bytes = BytesMut::from(&[4, MSG_TYPE_PUBREL, 0, 1][..]);
+        set_dup(&mut bytes);
+        assert_eq!(&bytes[..], &[4, MSG_TYPE_PUBREL, 0, 1]);
     }
 }
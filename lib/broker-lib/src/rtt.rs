@@ -0,0 +1,146 @@
+//! Round trip time estimation per client for the retransmission timeout,
+//! the SRTT/RTTVAR estimator of RFC 6298.
+//! A sample is taken from a message acknowledged without retransmission
+//! only (Karn's algorithm), the ACK of a retransmitted message might be
+//! for any of the copies.
+//! The clients without a sample use the initial RTO.
+
+use hashbrown::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::{eformat, function};
+
+/// The wheel tick is 100 ms, a shorter RTO only rounds up.
+pub const MIN_RTO: Duration = Duration::from_millis(200);
+pub const MAX_RTO: Duration = Duration::from_secs(60);
+/// MQTT-SN 1.2 spec section 6.13 Tretry.
+pub const INITIAL_RTO_MS: u64 = 1000;
+
+static INITIAL_RTO: AtomicU64 = AtomicU64::new(INITIAL_RTO_MS);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Estimator {
+    srtt: Duration,
+    rttvar: Duration,
+}
+
+impl Estimator {
+    fn new(sample: Duration) -> Self {
+        Estimator {
+            srtt: sample,
+            rttvar: sample / 2,
+        }
+    }
+    /// RTTVAR = 3/4 * RTTVAR + 1/4 * |SRTT - R'|
+    /// SRTT = 7/8 * SRTT + 1/8 * R'
+    fn update(&mut self, sample: Duration) {
+        let delta = if self.srtt > sample {
+            self.srtt - sample
+        } else {
+            sample - self.srtt
+        };
+        self.rttvar = self.rttvar * 3 / 4 + delta / 4;
+        self.srtt = self.srtt * 7 / 8 + sample / 8;
+    }
+    /// RTO = SRTT + 4 * RTTVAR
+    fn rto(&self) -> Duration {
+        (self.srtt + self.rttvar * 4).clamp(MIN_RTO, MAX_RTO)
+    }
+}
+
+lazy_static! {
+    static ref ESTIMATORS: Mutex<HashMap<SocketAddr, Estimator>> =
+        Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone)]
+pub struct Rtt {}
+
+impl Rtt {
+    /// The RTO of the clients without a sample, 1 second by default.
+    pub fn set_initial_rto(initial_rto: Duration) -> Result<(), String> {
+        if !(MIN_RTO..=MAX_RTO).contains(&initial_rto) {
+            return Err(eformat!("initial RTO out of range", initial_rto));
+        }
+        INITIAL_RTO.store(initial_rto.as_millis() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+    /// Add a round trip time sample of the client.
+    pub fn sample(socket_addr: SocketAddr, sample: Duration) {
+        let mut estimators = ESTIMATORS.lock().unwrap();
+        match estimators.get_mut(&socket_addr) {
+            Some(estimator) => estimator.update(sample),
+            None => {
+                estimators.insert(socket_addr, Estimator::new(sample));
+            }
+        }
+    }
+    /// The retransmission timeout of the client.
+    pub fn rto(socket_addr: &SocketAddr) -> Duration {
+        match ESTIMATORS.lock().unwrap().get(socket_addr) {
+            Some(estimator) => estimator.rto(),
+            None => Duration::from_millis(INITIAL_RTO.load(Ordering::Relaxed)),
+        }
+    }
+    /// Smoothed round trip time, None without a sample.
+    pub fn srtt(socket_addr: &SocketAddr) -> Option<Duration> {
+        ESTIMATORS
+            .lock()
+            .unwrap()
+            .get(socket_addr)
+            .map(|estimator| estimator.srtt)
+    }
+    /// Move the estimation to the new socket_addr of the client.
+    pub fn rebind(old_socket_addr: &SocketAddr, new_socket_addr: SocketAddr) {
+        let mut estimators = ESTIMATORS.lock().unwrap();
+        if let Some(estimator) = estimators.remove(old_socket_addr) {
+            estimators.insert(new_socket_addr, estimator);
+        }
+    }
+    pub fn delete(socket_addr: &SocketAddr) {
+        ESTIMATORS.lock().unwrap().remove(socket_addr);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_rtt() {
+        use super::{Rtt, INITIAL_RTO_MS, MAX_RTO, MIN_RTO};
+        use std::net::SocketAddr;
+        use std::time::Duration;
+
+        let socket = "127.0.0.95:1200".parse::<SocketAddr>().unwrap();
+        assert_eq!(Rtt::rto(&socket), Duration::from_millis(INITIAL_RTO_MS));
+        assert_eq!(Rtt::srtt(&socket), None);
+        // First sample, RTO = R + 4 * R/2.
+        Rtt::sample(socket, Duration::from_millis(100));
+        assert_eq!(Rtt::srtt(&socket), Some(Duration::from_millis(100)));
+        assert_eq!(Rtt::rto(&socket), Duration::from_millis(300));
+        // RTTVAR = 3/4 * 50 + 1/4 * 100, SRTT = 7/8 * 100 + 1/8 * 200.
+        Rtt::sample(socket, Duration::from_millis(200));
+        assert_eq!(Rtt::srtt(&socket), Some(Duration::from_micros(112_500)));
+        assert_eq!(Rtt::rto(&socket), Duration::from_micros(362_500));
+        // A LAN client is clamped to MIN_RTO, a cellular one to MAX_RTO.
+        for _ in 0..100 {
+            Rtt::sample(socket, Duration::from_millis(1));
+        }
+        assert_eq!(Rtt::rto(&socket), MIN_RTO);
+        for _ in 0..100 {
+            Rtt::sample(socket, Duration::from_secs(61));
+        }
+        assert_eq!(Rtt::rto(&socket), MAX_RTO);
+
+        let socket2 = "127.0.0.96:1200".parse::<SocketAddr>().unwrap();
+        Rtt::rebind(&socket, socket2);
+        assert_eq!(Rtt::srtt(&socket), None);
+        assert_eq!(Rtt::rto(&socket2), MAX_RTO);
+        Rtt::delete(&socket2);
+        assert_eq!(Rtt::srtt(&socket2), None);
+
+        assert!(Rtt::set_initial_rto(Duration::from_millis(10)).is_err());
+    }
+}
@@ -0,0 +1,88 @@
+//! MQTT-SN 2.0 session expiry.
+//! The session of a client disconnected with a non-zero Session Expiry
+//! Interval is kept DISCONNECTED, the messages to its subscriptions are
+//! queued. When the interval ends without a reconnect, the session is
+//! deleted with its subscriptions, offline queue and saved record.
+//! The timers are in a timing_wheel::ShardedWheel indexed by the client
+//! id, a CONNECT of the client cancels its timer.
+
+use bytes::Bytes;
+use log::*;
+use std::sync::Arc;
+use std::time::Duration;
+use timing_wheel::{driver, MonotonicClock, ShardedWheel};
+
+use crate::connection::Connection;
+
+/// The session doesn't expire.
+pub const SESSION_EXPIRY_NEVER: u32 = u32::MAX;
+
+static TICK_MILLIS: u64 = 1000;
+
+lazy_static! {
+    static ref TIME_WHEEL: Arc<ShardedWheel<Bytes, u32>> =
+        Arc::new(ShardedWheel::new(
+            Duration::from_millis(TICK_MILLIS),
+            Arc::new(MonotonicClock::new()),
+        ));
+}
+
+pub struct SessionExpiry {}
+
+impl SessionExpiry {
+    /// Start the expiry timer of a disconnected session, the session
+    /// expiry interval is in seconds.
+    pub fn schedule(client_id: Bytes, session_expiry: u32) {
+        if session_expiry == SESSION_EXPIRY_NEVER {
+            SessionExpiry::cancel(&client_id);
+            return;
+        }
+        let delay = Duration::from_secs(session_expiry as u64);
+        let _old_val = TIME_WHEEL.insert(client_id, session_expiry, delay);
+    }
+    /// Cancel the expiry timer when the client connects again.
+    pub fn cancel(client_id: &Bytes) {
+        let _val = TIME_WHEEL.remove(client_id);
+    }
+    pub fn contains(client_id: &Bytes) -> bool {
+        TIME_WHEEL.contains_key(client_id)
+    }
+    /// The expired sessions are deleted after the poll, deleting a
+    /// session cancels its timers.
+    pub fn run() {
+        let _handle = driver::spawn_tokio_with(
+            TIME_WHEEL.clone(),
+            |client_id, session_expiry, expired_vec: &mut Vec<Bytes>| {
+                debug!("Session Expired: {:?} {}", client_id, session_expiry);
+                expired_vec.push(client_id.clone());
+                None
+            },
+            |expired_vec| {
+                for client_id in expired_vec {
+                    if let Err(why) = Connection::expire(&client_id) {
+                        error!("{}", why);
+                    }
+                }
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_session_expiry() {
+        use super::{SessionExpiry, SESSION_EXPIRY_NEVER};
+        use bytes::Bytes;
+
+        let client_id = Bytes::from_static(b"session_expiry");
+        SessionExpiry::schedule(client_id.clone(), 60);
+        assert!(SessionExpiry::contains(&client_id));
+        SessionExpiry::cancel(&client_id);
+        assert!(!SessionExpiry::contains(&client_id));
+        // A session without expiry has no timer.
+        SessionExpiry::schedule(client_id.clone(), 60);
+        SessionExpiry::schedule(client_id.clone(), SESSION_EXPIRY_NEVER);
+        assert!(!SessionExpiry::contains(&client_id));
+    }
+}
@@ -0,0 +1,221 @@
+//! Persistent sessions of the clients connected with CleanSession=false,
+//! or with a non-zero Session Expiry Interval with MQTT-SN 2.0.
+//! The connection, will and subscriptions are saved in a sled database
+//! keyed by the client id, and restored when the broker restarts.
+//! A restored session is DISCONNECTED until the client connects again
+//! with CleanSession=false, a clean session deletes it.
+//! Without SessionStore::open() the sessions are only kept in memory.
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use crate::{
+    connection::Connection,
+    eformat,
+    filter::{
+        get_filters_with_socket_addr, get_topic_ids_with_socket_addr,
+        get_topic_name_with_topic_id, subscribe_with_filter,
+        subscribe_with_topic_id, try_register_topic_name,
+    },
+    flags::QoSConst,
+    function,
+    session_expiry::SessionExpiry,
+    TopicIdType, PROTOCOL_ID_V2_0,
+};
+
+lazy_static! {
+    static ref SESSION_DB: Mutex<Option<sled::Db>> = Mutex::new(None);
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct SessionDoc {
+    socket_addr: SocketAddr,
+    flags: u8,
+    protocol_id: u8,
+    duration: u16,
+    session_expiry: u32,
+    will_topic_id: Option<TopicIdType>,
+    will_topic: Vec<u8>,
+    will_message: Vec<u8>,
+    // The topic name is None for pre-defined and short topic ids.
+    topics: Vec<(TopicIdType, Option<String>, QoSConst)>,
+    filters: Vec<(String, QoSConst)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionStore {}
+
+impl SessionStore {
+    /// Open the database and restore the saved sessions.
+    /// Returns the number of sessions restored.
+    pub fn open(path: &str) -> Result<usize, String> {
+        let db = match sled::open(path) {
+            Ok(db) => db,
+            Err(why) => return Err(eformat!(path, why)),
+        };
+        let mut count = 0;
+        for result in db.iter() {
+            let (key, value) = match result {
+                Ok(entry) => entry,
+                Err(why) => return Err(eformat!(path, why)),
+            };
+            let doc: SessionDoc = match serde_json::from_slice(&value) {
+                Ok(doc) => doc,
+                Err(why) => return Err(eformat!(key, why)),
+            };
+            SessionStore::restore(Bytes::copy_from_slice(&key), doc)?;
+            count += 1;
+        }
+        *SESSION_DB.lock().unwrap() = Some(db);
+        Ok(count)
+    }
+
+    fn restore(client_id: Bytes, doc: SessionDoc) -> Result<(), String> {
+        // Two saved sessions with the same socket_addr, the client of the
+        // other one took it over before it was saved.
+        let socket_addr = if Connection::contains_key(doc.socket_addr) {
+            Connection::parked_socket_addr()
+        } else {
+            doc.socket_addr
+        };
+        for (topic_id, topic_name, qos) in doc.topics {
+            if let Some(topic_name) = topic_name {
+                try_register_topic_name(topic_name, topic_id)?;
+            }
+            subscribe_with_topic_id(socket_addr, topic_id, qos)?;
+        }
+        for (filter, qos) in doc.filters {
+            subscribe_with_filter(socket_addr, filter, qos)?;
+        }
+        if let (Some(topic_id), Ok(topic_name)) =
+            (doc.will_topic_id, String::from_utf8(doc.will_topic.clone()))
+        {
+            try_register_topic_name(topic_name, topic_id)?;
+        }
+        let mut conn = Connection::new(
+            socket_addr,
+            doc.flags,
+            doc.protocol_id,
+            doc.duration,
+            client_id,
+        );
+        conn.session_expiry = doc.session_expiry;
+        conn.will_topic_id = doc.will_topic_id;
+        conn.will_topic = Bytes::from(doc.will_topic);
+        conn.will_message = Bytes::from(doc.will_message);
+        if doc.protocol_id == PROTOCOL_ID_V2_0 && doc.session_expiry > 0 {
+            // The interval starts again with the broker.
+            SessionExpiry::schedule(conn.client_id.clone(), doc.session_expiry);
+        }
+        Connection::insert_restored(conn)
+    }
+
+    /// Save the session of the client after its state changed.
+    /// The session of a clean session client is deleted instead.
+    pub fn save(socket_addr: &SocketAddr) -> Result<(), String> {
+        let db = SESSION_DB.lock().unwrap();
+        let db = match db.as_ref() {
+            Some(db) => db,
+            None => return Ok(()),
+        };
+        let conn = match Connection::get(socket_addr) {
+            Some(conn) => conn,
+            None => return Err(eformat!(socket_addr, "not connected")),
+        };
+        if !conn.is_persistent() {
+            return match db.remove(&conn.client_id[..]) {
+                Ok(_) => Ok(()),
+                Err(why) => Err(eformat!(socket_addr, why)),
+            };
+        }
+        let topics = get_topic_ids_with_socket_addr(socket_addr)
+            .into_iter()
+            .map(|(topic_id, qos)| {
+                (topic_id, get_topic_name_with_topic_id(topic_id), qos)
+            })
+            .collect();
+        let doc = SessionDoc {
+            socket_addr: *socket_addr,
+            flags: conn.flags,
+            protocol_id: conn.protocol_id,
+            duration: conn.duration,
+            session_expiry: conn.session_expiry,
+            will_topic_id: conn.will_topic_id,
+            will_topic: conn.will_topic.to_vec(),
+            will_message: conn.will_message.to_vec(),
+            topics,
+            filters: get_filters_with_socket_addr(socket_addr),
+        };
+        let value = match serde_json::to_vec(&doc) {
+            Ok(value) => value,
+            Err(why) => return Err(eformat!(socket_addr, why)),
+        };
+        match db.insert(&conn.client_id[..], value) {
+            Ok(_) => Ok(()),
+            Err(why) => Err(eformat!(socket_addr, why)),
+        }
+    }
+
+    /// Delete the session when it ends, e.g. DISCONNECT without expiry.
+    pub fn delete(client_id: &Bytes) -> Result<(), String> {
+        if let Some(db) = SESSION_DB.lock().unwrap().as_ref() {
+            if let Err(why) = db.remove(&client_id[..]) {
+                return Err(eformat!(client_id, why));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_session_store() {
+        use super::SessionDoc;
+        use std::net::SocketAddr;
+
+        let socket = "127.0.0.93:1200".parse::<SocketAddr>().unwrap();
+        let doc = SessionDoc {
+            socket_addr: socket,
+            flags: 0,
+            protocol_id: 1,
+            duration: 30,
+            session_expiry: 0,
+            will_topic_id: None,
+            will_topic: Vec::new(),
+            will_message: vec![0xff, 0x00],
+            topics: vec![(
+                7,
+                Some("session/store".to_string()),
+                0b_0_01_0_0_0_00,
+            )],
+            filters: vec![("session/+".to_string(), 0)],
+        };
+        let value = serde_json::to_vec(&doc).unwrap();
+        let doc2: SessionDoc = serde_json::from_slice(&value).unwrap();
+        assert_eq!(doc, doc2);
+
+        let path = std::env::temp_dir().join("test_session_store");
+        let _ = std::fs::remove_dir_all(&path);
+        {
+            let db = sled::open(&path).unwrap();
+            db.insert(b"session_client", value).unwrap();
+            db.flush().unwrap();
+        }
+        assert_eq!(super::SessionStore::open(path.to_str().unwrap()), Ok(1));
+        assert!(crate::connection::Connection::contains_key(socket));
+        assert_eq!(
+            crate::filter::get_topic_ids_with_socket_addr(&socket),
+            vec![(7, 0b_0_01_0_0_0_00)]
+        );
+        assert_eq!(
+            crate::filter::get_filters_with_socket_addr(&socket),
+            vec![("session/+".to_string(), 0)]
+        );
+        super::SessionStore::save(&socket).unwrap();
+        super::SessionStore::delete(&bytes::Bytes::from("session_client"))
+            .unwrap();
+    }
+}
@@ -20,8 +20,9 @@ contains wildcard characters)
 • ReturnCode: “accepted”, or rejection reason.
 */
 use crate::{
-    broker_lib::MqttSnClient, eformat, function, msg_hdr::MsgHeader,
-    retransmit::RetransTimeWheel, MSG_LEN_SUBACK, MSG_TYPE_SUBACK,
+    broker_lib::MqttSnClient, connection::Connection, eformat, function,
+    msg_hdr::MsgHeader, retransmit::RetransTimeWheel, MSG_LEN_SUBACK,
+    MSG_TYPE_SUBACK,
 };
 use bytes::{BufMut, BytesMut};
 use custom_debug::Debug;
@@ -110,15 +111,18 @@ impl SubAck {
         msg_id: u16,
         return_code: u8,
     ) -> Result<(), String> {
+        let remote_socket_addr = msg_header.remote_socket_addr;
         let sub_ack = SubAck {
             len: MSG_LEN_SUBACK,
             msg_type: MSG_TYPE_SUBACK,
             flags,
             topic_id,
             msg_id,
-            return_code,
+            return_code: Connection::return_code(
+                &remote_socket_addr,
+                return_code,
+            ),
         };
-        let remote_socket_addr = msg_header.remote_socket_addr;
         let mut bytes_buf = BytesMut::with_capacity(MSG_LEN_SUBACK as usize);
         dbg!(sub_ack.clone());
         sub_ack.try_write(&mut bytes_buf);
@@ -31,10 +31,21 @@ extern crate trace_caller;
 use trace_caller::trace;
 
 use crate::{
-    broker_lib::MqttSnClient, eformat, filter::*, flags::*, function,
-    msg_hdr::*, publish::Publish, retain_cache::RetainCache,
-    retransmit::RetransTimeWheel, sub_ack::SubAck, MSG_TYPE_SUBACK,
-    MSG_TYPE_SUBSCRIBE, RETURN_CODE_ACCEPTED,
+    broker_lib::MqttSnClient,
+    congestion::Congestion,
+    eformat,
+    filter::*,
+    flags::*,
+    function,
+    msg_hdr::*,
+    pre_defined_topic::PreDefinedTopics,
+    publish::Publish,
+    retain_cache::{RetainCache, RetainSub},
+    retransmit::RetransTimeWheel,
+    sub_ack::SubAck,
+    MSG_TYPE_SUBACK, MSG_TYPE_SUBSCRIBE, RETURN_CODE_ACCEPTED,
+    RETURN_CODE_CONGESTION, RETURN_CODE_INVALID_TOPIC_ID,
+    RETURN_CODE_NOT_SUPPORTED,
 };
 
 #[derive(
@@ -130,7 +141,6 @@ impl Subscribe {
             MSG_TYPE_SUBACK,
             0,
             0,
-            1,
             bytes_buf,
         ) {
             Ok(()) => Ok(()),
@@ -161,14 +171,86 @@ impl Subscribe {
         dbg!((size, read_len));
         dbg!(flag_topic_id_type(subscribe.flags));
 
+        if Congestion::detect(client, &remote_socket_addr, MSG_TYPE_SUBSCRIBE) {
+            SubAck::send(
+                client,
+                msg_header,
+                subscribe.flags,
+                0,
+                subscribe.msg_id,
+                RETURN_CODE_CONGESTION,
+            )?;
+            return Err(eformat!(remote_socket_addr, "congestion"));
+        }
         // TODO check QoS, https://www.hivemq.com/blog/mqtt-essentials-
         // part-6-mqtt-quality-of-service-levels/
         if read_len == size {
             match flag_topic_id_type(subscribe.flags) {
+                TOPIC_ID_TYPE_NORMAL
+                    if has_wildcards(&subscribe.topic_name)
+                        || is_shared(&subscribe.topic_name) =>
+                {
+                    // Topic filter with wildcards or shared subscription: the
+                    // topic ids of the matching topics are sent with REGISTER
+                    // before the first PUBLISH, so the SUBACK has topic id
+                    // 0x0000.
+                    if let Err(why) = subscribe_with_filter(
+                        remote_socket_addr,
+                        subscribe.topic_name.clone(),
+                        flag_qos_level(subscribe.flags),
+                    ) {
+                        SubAck::send(
+                            client,
+                            msg_header,
+                            subscribe.flags,
+                            0,
+                            subscribe.msg_id,
+                            RETURN_CODE_NOT_SUPPORTED,
+                        )?;
+                        return Err(why);
+                    }
+                    SubAck::send(
+                        client,
+                        msg_header,
+                        subscribe.flags,
+                        0,
+                        subscribe.msg_id,
+                        RETURN_CODE_ACCEPTED,
+                    )?;
+                    // No retained messages for a shared subscription,
+                    // MQTT 5.0 spec section 4.8.2.
+                    if is_shared(&subscribe.topic_name) {
+                        return Ok(());
+                    }
+                    // retained messages of all the matching topics
+                    if let Err(err) = client.sub_retain_tx.try_send((
+                        remote_socket_addr,
+                        RetainSub::Filter(subscribe.topic_name),
+                        flag_qos_level(subscribe.flags),
+                    )) {
+                        error!("{}", err);
+                    }
+                    return Ok(());
+                }
                 TOPIC_ID_TYPE_NORMAL => {
                     // Normal topic type(string): assign topic_id from existing
                     // or new.
-                    let topic_id = try_insert_topic_name(subscribe.topic_name)?;
+                    let topic_id =
+                        match try_insert_topic_name(subscribe.topic_name) {
+                            Ok(topic_id) => topic_id,
+                            Err(why) => {
+                                // The topic ids are exhausted.
+                                SubAck::send(
+                                    client,
+                                    msg_header,
+                                    subscribe.flags,
+                                    0,
+                                    subscribe.msg_id,
+                                    RETURN_CODE_CONGESTION,
+                                )?;
+                                return Err(eformat!(remote_socket_addr, why));
+                            }
+                        };
                     subscribe_with_topic_id(
                         remote_socket_addr,
                         topic_id,
@@ -185,6 +267,14 @@ impl Subscribe {
                         subscribe.msg_id,
                         RETURN_CODE_ACCEPTED,
                     )?;
+                    // check for retained topic
+                    if let Err(err) = client.sub_retain_tx.try_send((
+                        remote_socket_addr,
+                        RetainSub::TopicId(topic_id),
+                        flag_qos_level(subscribe.flags),
+                    )) {
+                        error!("{}", err);
+                    }
                     return Ok(());
                 }
                 TOPIC_ID_TYPE_PRE_DEFINED => {
@@ -206,6 +296,23 @@ impl Subscribe {
                         topic_id = (topic_id << 8) + char as u16;
                     }
                     dbg!(topic_id);
+                    // The topic id must be in the table agreed with the
+                    // clients.
+                    if !PreDefinedTopics::contains(&topic_id) {
+                        SubAck::send(
+                            client,
+                            msg_header,
+                            subscribe.flags,
+                            topic_id,
+                            subscribe.msg_id,
+                            RETURN_CODE_INVALID_TOPIC_ID,
+                        )?;
+                        return Err(eformat!(
+                            remote_socket_addr,
+                            "unknown pre-defined topic id",
+                            topic_id
+                        ));
+                    }
                     // Pre-defined topic type(integer): save remote_addr and
                     // topic_id to the hash map.
                     subscribe_with_topic_id(
@@ -224,20 +331,48 @@ impl Subscribe {
                     )?;
                     dbg!(topic_id);
                     // check for retained topic
-                    if let Err(err) = client
-                        .sub_retain_tx
-                        .try_send((remote_socket_addr, topic_id))
-                    {
+                    if let Err(err) = client.sub_retain_tx.try_send((
+                        remote_socket_addr,
+                        RetainSub::TopicId(topic_id),
+                        flag_qos_level(subscribe.flags),
+                    )) {
                         error!("{}", err); // TODO log it
                     }
                     return Ok(());
                 }
                 TOPIC_ID_TYPE_SHORT => {
-                    dbg!(flag_topic_id_type(subscribe.flags));
-                    return Err(eformat!(
+                    // Short topic name(2 octets) is subscribed as a topic
+                    // name, the PUBLISH messages carry the name in the
+                    // topic id field, so the SUBACK has topic id 0x0000.
+                    if !is_short_topic_name(&subscribe.topic_name) {
+                        return Err(eformat!(
+                            remote_socket_addr,
+                            "invalid short topic name",
+                            subscribe.topic_name
+                        ));
+                    }
+                    let topic_id = subscribe_with_topic_name(
                         remote_socket_addr,
-                        "topic Id short topic name not supported"
-                    ));
+                        subscribe.topic_name,
+                        flag_qos_level(subscribe.flags),
+                    )?;
+                    SubAck::send(
+                        client,
+                        msg_header,
+                        subscribe.flags,
+                        0,
+                        subscribe.msg_id,
+                        RETURN_CODE_ACCEPTED,
+                    )?;
+                    // check for retained topic
+                    if let Err(err) = client.sub_retain_tx.try_send((
+                        remote_socket_addr,
+                        RetainSub::TopicId(topic_id),
+                        flag_qos_level(subscribe.flags),
+                    )) {
+                        error!("{}", err);
+                    }
+                    return Ok(());
                 }
                 TOPIC_ID_TYPE_RESERVED => {
                     dbg!(flag_topic_id_type(subscribe.flags));
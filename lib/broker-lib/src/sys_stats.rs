@@ -0,0 +1,199 @@
+//! Broker statistics published every interval under $SYS/broker/.
+//! A client subscribes to a statistic with its topic name, the SUBACK has
+//! its topic id like any other topic. The wildcard filters don't match the
+//! topics starting with '$', see match_topic().
+//! The messages received and sent are all the MQTT-SN messages, the
+//! dropped messages are the ones discarded by the full offline queues and
+//! asleep caches, and the ones abandoned after Nretry retransmissions.
+//! The congestion is the number of messages rejected with congestion.
+
+use bytes::BytesMut;
+use log::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{
+    broker_lib::MqttSnClient,
+    congestion::Congestion,
+    connection::{Connection, StateEnum2},
+    eformat,
+    filter::{get_subscribers_with_topic_id, try_insert_topic_name},
+    flags::{QOS_LEVEL_0, RETAIN_FALSE},
+    function,
+    publish::Publish,
+    retain_cache::RetainCache,
+};
+
+/// Default interval of the publications, 0 to disable them.
+pub const SYS_INTERVAL_SECS: u64 = 10;
+
+pub const SYS_CLIENTS_CONNECTED: &str = "$SYS/broker/clients/connected";
+pub const SYS_CLIENTS_SLEEPING: &str = "$SYS/broker/clients/sleeping";
+pub const SYS_CLIENTS_LOST: &str = "$SYS/broker/clients/lost";
+pub const SYS_MESSAGES_RECEIVED: &str = "$SYS/broker/messages/received";
+pub const SYS_MESSAGES_SENT: &str = "$SYS/broker/messages/sent";
+pub const SYS_MESSAGES_RETAINED: &str = "$SYS/broker/messages/retained";
+pub const SYS_MESSAGES_RETRANSMITTED: &str =
+    "$SYS/broker/messages/retransmitted";
+pub const SYS_MESSAGES_DROPPED: &str = "$SYS/broker/messages/dropped";
+pub const SYS_CONGESTION: &str = "$SYS/broker/congestion";
+pub const SYS_UPTIME: &str = "$SYS/broker/uptime";
+
+static RECEIVED: AtomicU64 = AtomicU64::new(0);
+static SENT: AtomicU64 = AtomicU64::new(0);
+static RETRANSMITTED: AtomicU64 = AtomicU64::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref START: Instant = Instant::now();
+}
+
+#[derive(Debug, Clone)]
+pub struct SysStats {}
+
+impl SysStats {
+    pub fn received() {
+        RECEIVED.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn sent() {
+        SENT.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn retransmitted() {
+        RETRANSMITTED.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn dropped() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+    /// The topic names and payloads of the statistics, from the states of
+    /// the sessions and the number of retained messages.
+    fn stats(
+        state_vec: &[StateEnum2],
+        retained: usize,
+    ) -> Vec<(&'static str, String)> {
+        let count = |state_fn: fn(&StateEnum2) -> bool| {
+            state_vec.iter().filter(|state| state_fn(state)).count()
+        };
+        let connected = count(|state| {
+            matches!(state, StateEnum2::ACTIVE | StateEnum2::AWAKE)
+        });
+        let sleeping = count(|state| matches!(state, StateEnum2::ASLEEP));
+        let lost = count(|state| matches!(state, StateEnum2::LOST));
+        vec![
+            (SYS_CLIENTS_CONNECTED, connected.to_string()),
+            (SYS_CLIENTS_SLEEPING, sleeping.to_string()),
+            (SYS_CLIENTS_LOST, lost.to_string()),
+            (
+                SYS_MESSAGES_RECEIVED,
+                RECEIVED.load(Ordering::Relaxed).to_string(),
+            ),
+            (SYS_MESSAGES_SENT, SENT.load(Ordering::Relaxed).to_string()),
+            (SYS_MESSAGES_RETAINED, retained.to_string()),
+            (
+                SYS_MESSAGES_RETRANSMITTED,
+                RETRANSMITTED.load(Ordering::Relaxed).to_string(),
+            ),
+            (
+                SYS_MESSAGES_DROPPED,
+                DROPPED.load(Ordering::Relaxed).to_string(),
+            ),
+            (SYS_CONGESTION, Congestion::signalled_total().to_string()),
+            (SYS_UPTIME, START.elapsed().as_secs().to_string()),
+        ]
+    }
+    /// Publish the statistics to their subscribers, the topic ids are
+    /// assigned on the first publication.
+    fn publish(client: &MqttSnClient, retain_cache: &RetainCache) {
+        let stats =
+            SysStats::stats(&Connection::states(), retain_cache.count());
+        for (topic_name, payload) in stats {
+            let topic_id = match try_insert_topic_name(topic_name.to_string()) {
+                Ok(topic_id) => topic_id,
+                Err(why) => {
+                    error!("{}", why);
+                    continue;
+                }
+            };
+            let subscriber_vec = get_subscribers_with_topic_id(topic_id);
+            if subscriber_vec.is_empty() {
+                continue;
+            }
+            // Each subscriber gets the message with its QoS.
+            let publish = Publish::new(
+                topic_id,
+                0,
+                QOS_LEVEL_0,
+                RETAIN_FALSE,
+                BytesMut::from(payload.as_bytes()),
+            );
+            if let Err(why) = Publish::send_msg_to_subscribers(
+                subscriber_vec,
+                publish,
+                client,
+            ) {
+                error!("{}", why);
+            }
+        }
+    }
+    /// Publish the statistics every interval, a zero interval disables
+    /// them.
+    pub fn run(
+        client: MqttSnClient,
+        retain_cache: RetainCache,
+        interval: Duration,
+    ) {
+        // The uptime starts now.
+        lazy_static::initialize(&START);
+        if interval.is_zero() {
+            return;
+        }
+        let builder = thread::Builder::new().name("sys_stats".into());
+        let result = builder.spawn(move || loop {
+            thread::sleep(interval);
+            SysStats::publish(&client, &retain_cache);
+        });
+        if let Err(why) = result {
+            error!("{}", eformat!(why.to_string()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_sys_stats() {
+        use super::*;
+
+        let state_vec = vec![
+            StateEnum2::ACTIVE,
+            StateEnum2::AWAKE,
+            StateEnum2::ASLEEP,
+            StateEnum2::LOST,
+            StateEnum2::LOST,
+            StateEnum2::DISCONNECTED,
+        ];
+        let received = RECEIVED.load(Ordering::Relaxed);
+        SysStats::received();
+        SysStats::received();
+        SysStats::dropped();
+        let stats = SysStats::stats(&state_vec, 3);
+        let value = |topic_name: &str| {
+            stats
+                .iter()
+                .find(|(name, _payload)| *name == topic_name)
+                .map(|(_name, payload)| payload.parse::<u64>().unwrap())
+                .unwrap()
+        };
+        assert_eq!(stats.len(), 10);
+        assert_eq!(value(SYS_CLIENTS_CONNECTED), 2);
+        assert_eq!(value(SYS_CLIENTS_SLEEPING), 1);
+        assert_eq!(value(SYS_CLIENTS_LOST), 2);
+        assert_eq!(value(SYS_MESSAGES_RETAINED), 3);
+        assert!(value(SYS_MESSAGES_RECEIVED) >= received + 2);
+        assert!(value(SYS_MESSAGES_DROPPED) >= 1);
+        assert!(value(SYS_CONGESTION) <= Congestion::signalled_total());
+        // Subscribed with the topic name only.
+        assert!(!crate::filter::match_topic(SYS_UPTIME, "#"));
+        assert!(!crate::filter::match_topic(SYS_UPTIME, "+/broker/uptime"));
+    }
+}
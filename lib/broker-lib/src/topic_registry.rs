@@ -0,0 +1,318 @@
+//! Registry of the topic name <-> topic id assignments.
+//! The topic ids 0x0000 and 0xFFFF are reserved by the spec and never
+//! assigned. The ids from PRE_DEFINED_TOPIC_ID_MIN are kept for the
+//! pre-defined topics, the broker assigns the ids below.
+//! When all the ids are taken, the ids not used by any client are
+//! reclaimed if the ReclaimPolicy allows it, otherwise the assignment
+//! fails and the client gets RETURN_CODE_CONGESTION.
+//! With open_topic_registry() the assignments are written to a sled
+//! database, so a restart doesn't renumber the topics that sleeping
+//! clients have cached.
+
+use hashbrown::HashMap;
+use log::*;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{eformat, function, TopicIdType};
+
+/// First topic id assigned by the broker, 0x0000 is reserved.
+pub const TOPIC_ID_MIN: TopicIdType = 0x0001;
+/// First topic id of the pre-defined topics.
+pub const PRE_DEFINED_TOPIC_ID_MIN: TopicIdType = 0xF000;
+/// Last valid topic id, 0xFFFF is reserved.
+pub const TOPIC_ID_MAX: TopicIdType = 0xFFFE;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReclaimPolicy {
+    /// Assigned topic ids are never reused.
+    Never,
+    /// Reuse the topic ids not used by any client for the seconds.
+    Idle(u64),
+}
+
+#[derive(Debug)]
+pub struct TopicRegistry {
+    names: HashMap<String, TopicIdType>,
+    ids: HashMap<TopicIdType, String>,
+    // Seconds since the epoch of the last lookup of the topic id.
+    last_used: HashMap<TopicIdType, u64>,
+    next_id: TopicIdType,
+    // The range of the topic ids assigned by insert().
+    min_id: TopicIdType,
+    max_id: TopicIdType,
+    policy: ReclaimPolicy,
+    db: Option<sled::Db>,
+}
+
+lazy_static! {
+    pub static ref TOPIC_REGISTRY: Mutex<TopicRegistry> = Mutex::new(
+        TopicRegistry::new(TOPIC_ID_MIN, PRE_DEFINED_TOPIC_ID_MIN - 1)
+    );
+}
+
+/// Persist the assignments of the global registry in the path.
+/// Returns the number of assignments loaded.
+pub fn open_topic_registry(path: &str) -> Result<usize, String> {
+    TOPIC_REGISTRY.lock().unwrap().open(path)
+}
+
+pub fn set_reclaim_policy(policy: ReclaimPolicy) {
+    TOPIC_REGISTRY.lock().unwrap().policy = policy;
+}
+
+fn now_secs() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+impl TopicRegistry {
+    pub fn new(min_id: TopicIdType, max_id: TopicIdType) -> Self {
+        TopicRegistry {
+            names: HashMap::new(),
+            ids: HashMap::new(),
+            last_used: HashMap::new(),
+            next_id: min_id,
+            min_id: min_id.max(TOPIC_ID_MIN),
+            max_id: max_id.min(TOPIC_ID_MAX),
+            policy: ReclaimPolicy::Never,
+            db: None,
+        }
+    }
+
+    fn open(&mut self, path: &str) -> Result<usize, String> {
+        let db = match sled::open(path) {
+            Ok(db) => db,
+            Err(why) => return Err(eformat!(path, why)),
+        };
+        let mut count = 0;
+        for result in db.iter() {
+            let (key, value) = match result {
+                Ok(entry) => entry,
+                Err(why) => return Err(eformat!(path, why)),
+            };
+            if key.len() != 2 {
+                return Err(eformat!(path, "key len err", key));
+            }
+            let topic_id = u16::from_be_bytes([key[0], key[1]]);
+            let topic_name = match String::from_utf8(value.to_vec()) {
+                Ok(topic_name) => topic_name,
+                Err(why) => return Err(eformat!(topic_id, why)),
+            };
+            self.register(topic_name, topic_id)?;
+            // Continue after the last assigned topic id.
+            if topic_id >= self.next_id && topic_id < self.max_id {
+                self.next_id = topic_id + 1;
+            }
+            count += 1;
+        }
+        self.db = Some(db);
+        Ok(count)
+    }
+
+    pub fn set_policy(&mut self, policy: ReclaimPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn get_id(&mut self, topic_name: &str) -> Option<TopicIdType> {
+        let topic_id = *self.names.get(topic_name)?;
+        self.last_used.insert(topic_id, now_secs());
+        Some(topic_id)
+    }
+
+    pub fn get_name(&mut self, topic_id: TopicIdType) -> Option<String> {
+        let topic_name = self.ids.get(&topic_id)?.clone();
+        self.last_used.insert(topic_id, now_secs());
+        Some(topic_name)
+    }
+
+    /// Register the topic name with a given topic id, e.g. a pre-defined
+    /// topic or a topic restored from a persistent session.
+    pub fn register(
+        &mut self,
+        topic_name: String,
+        topic_id: TopicIdType,
+    ) -> Result<TopicIdType, String> {
+        if topic_id < TOPIC_ID_MIN || topic_id > TOPIC_ID_MAX {
+            return Err(eformat!("reserved topic id", topic_id, topic_name));
+        }
+        match (self.names.get(&topic_name), self.ids.get(&topic_id)) {
+            (None, None) => {
+                self.insert_pair(topic_name, topic_id);
+                Ok(topic_id)
+            }
+            // Topic name is already in the map with the topic id.
+            (Some(id), _) if *id == topic_id => Ok(topic_id),
+            (id, name) => Err(eformat!(
+                "topic name/id pair already exists",
+                topic_name,
+                topic_id,
+                (id, name)
+            )),
+        }
+    }
+
+    /// Return the topic id of the topic name, a new topic name is assigned
+    /// the next free topic id. in_use() tells if a client still uses a
+    /// topic id, the other ones can be reclaimed.
+    pub fn insert(
+        &mut self,
+        topic_name: String,
+        in_use: impl Fn(TopicIdType) -> bool,
+    ) -> Result<TopicIdType, String> {
+        if let Some(topic_id) = self.get_id(&topic_name) {
+            return Ok(topic_id);
+        }
+        let topic_id = match self.next_free_id() {
+            Some(topic_id) => topic_id,
+            None => {
+                if self.reclaim(in_use) == 0 {
+                    return Err(eformat!("topic ids exhausted", topic_name));
+                }
+                match self.next_free_id() {
+                    Some(topic_id) => topic_id,
+                    None => {
+                        return Err(eformat!("topic ids exhausted", topic_name))
+                    }
+                }
+            }
+        };
+        self.next_id = if topic_id >= self.max_id {
+            self.min_id
+        } else {
+            topic_id + 1
+        };
+        self.insert_pair(topic_name, topic_id);
+        Ok(topic_id)
+    }
+
+    /// Remove the topic ids idle longer than the ReclaimPolicy and not in
+    /// use. Pre-defined topics are out of the range and never reclaimed.
+    /// Returns the number of topic ids reclaimed.
+    pub fn reclaim(&mut self, in_use: impl Fn(TopicIdType) -> bool) -> usize {
+        let idle_secs = match self.policy {
+            ReclaimPolicy::Never => return 0,
+            ReclaimPolicy::Idle(idle_secs) => idle_secs,
+        };
+        let now = now_secs();
+        let idle_vec: Vec<TopicIdType> = self
+            .ids
+            .keys()
+            .copied()
+            .filter(|topic_id| {
+                *topic_id >= self.min_id
+                    && *topic_id <= self.max_id
+                    && now.saturating_sub(self.last_used[topic_id]) >= idle_secs
+                    && !in_use(*topic_id)
+            })
+            .collect();
+        for topic_id in idle_vec.iter() {
+            self.remove(*topic_id);
+        }
+        idle_vec.len()
+    }
+
+    pub fn remove(&mut self, topic_id: TopicIdType) -> Option<String> {
+        let topic_name = self.ids.remove(&topic_id)?;
+        self.names.remove(&topic_name);
+        self.last_used.remove(&topic_id);
+        if let Some(db) = &self.db {
+            if let Err(why) = db.remove(topic_id.to_be_bytes()) {
+                error!("{}", eformat!(topic_id, why));
+            }
+        }
+        Some(topic_name)
+    }
+
+    fn next_free_id(&self) -> Option<TopicIdType> {
+        if self.min_id > self.max_id {
+            return None;
+        }
+        let range_len = (self.max_id - self.min_id) as usize + 1;
+        let mut topic_id = self.next_id.clamp(self.min_id, self.max_id);
+        for _ in 0..range_len {
+            if !self.ids.contains_key(&topic_id) {
+                return Some(topic_id);
+            }
+            topic_id = if topic_id >= self.max_id {
+                self.min_id
+            } else {
+                topic_id + 1
+            };
+        }
+        None
+    }
+
+    fn insert_pair(&mut self, topic_name: String, topic_id: TopicIdType) {
+        // Only the assigned topic ids are persisted, the pre-defined topics
+        // are loaded from their own file.
+        if topic_id >= self.min_id && topic_id <= self.max_id {
+            if let Some(db) = &self.db {
+                if let Err(why) =
+                    db.insert(topic_id.to_be_bytes(), topic_name.as_bytes())
+                {
+                    error!("{}", eformat!(topic_id, topic_name, why));
+                }
+            }
+        }
+        self.names.insert(topic_name.clone(), topic_id);
+        self.ids.insert(topic_id, topic_name);
+        self.last_used.insert(topic_id, now_secs());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_topic_registry() {
+        use super::{ReclaimPolicy, TopicRegistry};
+
+        let mut registry = TopicRegistry::new(1, 3);
+        let not_used = |_topic_id| false;
+        assert_eq!(registry.insert("a".to_string(), not_used), Ok(1));
+        assert_eq!(registry.insert("a".to_string(), not_used), Ok(1));
+        assert_eq!(registry.insert("b".to_string(), not_used), Ok(2));
+        assert_eq!(registry.register("c".to_string(), 3), Ok(3));
+        // The reserved topic ids.
+        assert!(registry.register("d".to_string(), 0x0000).is_err());
+        assert!(registry.register("d".to_string(), 0xFFFF).is_err());
+        // Topic name and topic id are already taken.
+        assert!(registry.register("c".to_string(), 2).is_err());
+        assert!(registry.register("d".to_string(), 2).is_err());
+        // The range is exhausted.
+        assert!(registry.insert("d".to_string(), not_used).is_err());
+
+        // Topic id 2 is still used, 1 and 3 are reclaimed.
+        registry.set_policy(ReclaimPolicy::Idle(0));
+        let in_use = |topic_id| topic_id == 2;
+        assert_eq!(registry.insert("d".to_string(), in_use), Ok(3));
+        assert_eq!(registry.get_id("a"), None);
+        assert_eq!(registry.get_name(2), Some("b".to_string()));
+        // Wrap around to the first topic id of the range.
+        assert_eq!(registry.insert("e".to_string(), in_use), Ok(1));
+    }
+    #[test]
+    fn test_topic_registry_persistence() {
+        use super::TopicRegistry;
+
+        let path = std::env::temp_dir().join("test_topic_registry");
+        let _ = std::fs::remove_dir_all(&path);
+        let path = path.to_str().unwrap();
+        let not_used = |_topic_id| false;
+        {
+            let mut registry = TopicRegistry::new(1, 100);
+            assert_eq!(registry.open(path), Ok(0));
+            assert_eq!(registry.insert("a".to_string(), not_used), Ok(1));
+            assert_eq!(registry.insert("b".to_string(), not_used), Ok(2));
+            // Out of the range, not persisted.
+            assert_eq!(registry.register("p".to_string(), 200), Ok(200));
+        }
+        let mut registry = TopicRegistry::new(1, 100);
+        assert_eq!(registry.open(path), Ok(2));
+        assert_eq!(registry.get_id("b"), Some(2));
+        assert_eq!(registry.get_id("p"), None);
+        assert_eq!(registry.insert("c".to_string(), not_used), Ok(3));
+    }
+}
@@ -117,6 +117,15 @@ impl Unsubscribe {
         let remote_socket_addr = msg_header.remote_socket_addr;
         dbg!(unsubscribe.clone());
         match flag_topic_id_type(unsubscribe.flags) {
+            TOPIC_ID_TYPE_NORMAL
+                if has_wildcards(&unsubscribe.topic_name)
+                    || is_shared(&unsubscribe.topic_name) =>
+            {
+                unsubscribe_with_filter(
+                    remote_socket_addr,
+                    unsubscribe.topic_name,
+                )?;
+            }
             TOPIC_ID_TYPE_NORMAL => {
                 unsubscribe_with_topic_name(
                     remote_socket_addr,
@@ -144,10 +153,17 @@ impl Unsubscribe {
                 }
             }
             TOPIC_ID_TYPE_SHORT => {
-                return Err(eformat!(
+                if !is_short_topic_name(&unsubscribe.topic_name) {
+                    return Err(eformat!(
+                        remote_socket_addr,
+                        "invalid short topic name",
+                        unsubscribe.topic_name
+                    ));
+                }
+                unsubscribe_with_topic_name(
                     remote_socket_addr,
-                    "topic Id short topic name not supported"
-                ));
+                    unsubscribe.topic_name,
+                )?;
             }
             TOPIC_ID_TYPE_RESERVED => {
                 return Err(eformat!(
@@ -195,7 +211,6 @@ impl Unsubscribe {
                 MSG_TYPE_UNSUBACK,
                 0,
                 msg_id,
-                1,
                 bytes_buf,
             ) {
                 Ok(()) => Ok(()),
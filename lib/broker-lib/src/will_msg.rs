@@ -10,8 +10,8 @@ to the GW. Its format is shown in Table 13:
 */
 use crate::{
     broker_lib::MqttSnClient, conn_ack::ConnAck, connection::Connection,
-    eformat, function, msg_hdr::MsgHeader, MSG_LEN_WILL_MSG_HEADER,
-    MSG_TYPE_WILL_MSG, RETURN_CODE_ACCEPTED,
+    eformat, function, msg_hdr::MsgHeader, publish::Publish,
+    MSG_LEN_WILL_MSG_HEADER, MSG_TYPE_WILL_MSG, RETURN_CODE_ACCEPTED,
 };
 use bytes::{BufMut, BytesMut};
 use custom_debug::Debug;
@@ -53,6 +53,7 @@ impl WillMsg {
             if size == len as usize {
                 Connection::update_will_msg(remote_socket_addr, will.msg)?;
                 ConnAck::send(client, msg_header, RETURN_CODE_ACCEPTED)?;
+                Publish::send_offline_msgs(client, remote_socket_addr);
                 Ok(())
             } else {
                 Err(eformat!(
@@ -67,6 +68,7 @@ impl WillMsg {
             if size == len as usize && will.one == 1 {
                 Connection::update_will_msg(remote_socket_addr, will.msg)?;
                 ConnAck::send(client, msg_header, RETURN_CODE_ACCEPTED)?;
+                Publish::send_offline_msgs(client, remote_socket_addr);
                 Ok(())
             } else {
                 Err(eformat!(
@@ -6,12 +6,13 @@ use chrono::{Datelike, Local, Timelike};
 use core::hash::Hash;
 use crossbeam::channel::{unbounded, Receiver, Sender};
 use crossbeam_utils;
-use std::collections::HashMap;
+use log::*;
 use std::net::UdpSocket;
 use std::sync::atomic::{AtomicU8, Ordering};
 use std::time::{Duration, SystemTime};
 use std::{hint, thread};
-use std::{net::SocketAddr, sync::Arc, sync::Mutex};
+use std::{net::SocketAddr, sync::Arc};
+use timing_wheel::{driver, MonotonicClock, ShardedWheel};
 
 use trace_var::trace_var;
 
@@ -72,278 +73,19 @@ macro_rules! dbg {
     };
 }
 
-#[derive(Debug, Clone)]
-struct Slot<KEY: Debug + Clone> {
-    pub entries: Arc<Mutex<Vec<(KEY, usize)>>>,
-}
-
-impl<KEY: Debug + Clone> Slot<KEY> {
-    pub fn new() -> Self {
-        Slot {
-            entries: Arc::new(Mutex::new(Vec::new())),
-        }
-    }
-}
-
 // clients use 100 milli seconds
 // brokers use 10 milli seconds
-/// static TIME_WHEEL_SLEEP_DURATION:usize = 100; // in milli seconds
-// For 64 seconds at 100 ms sleeping interval, we need 10 * 64 slots, (1000/100 = 10)
-// For 64 seconds at 10 ms sleeping interval, we need 100 * 64 slots, (1000/10 = 100)
-
 // The maximum timeout duration is 64 seconds
 // The last one might be over 64, rounding up.
-/// static TIME_WHEEL_MAX_SLOTS:usize = (1000 / TIME_WHEEL_SLEEP_DURATION) * 64 * 2;
+static TIME_WHEEL_MAX_DURATION: Duration = Duration::from_secs(64 * 2);
 // Initial timeout duration is 300 ms
 static TIME_WHEEL_DEFAULT_DURATION_MS: usize = 300;
 
-#[derive(Debug, Clone)]
-pub struct TimingWheel2<KEY: Debug + Clone, VAL: Debug + Clone> {
-    max_slot: usize,       // (1000 / sleep_duration) * 64 * 2;
-    sleep_duration: usize, // in milli seconds
-    default_duration: usize,
-    cur_counter: usize,
-    slot_vec: Vec<Slot<KEY>>,
-    hash: Arc<Mutex<HashMap<KEY, VAL>>>,
-}
-
-impl<KEY: Eq + Hash + Debug + Clone, VAL: Debug + Clone>
-    TimingWheel2<KEY, VAL>
-{
-    #[trace_var(max_slot, slot_vec, default_duration)]
-    pub fn new(sleep_duration: usize, default_duration_ms: usize) -> Self {
-        dbg!((sleep_duration, default_duration_ms));
-        // verify sleep_duration (milli seconds) 1..1000.
-        let max_slot = (1000 / sleep_duration) * 64 * 2;
-        let mut slot_vec: Vec<Slot<KEY>> = Vec::with_capacity(max_slot);
-        // verify default_duration (milli seconds) 1..1000.
-        let default_duration = default_duration_ms / sleep_duration;
-
-        // Must use for loop to initialize
-        // let slot_vec:Vec<Slot<KEY>> = vec![Vec<Slot<KEY>>, max_slot] didn'KEY work.
-        // It allocates one Vec<Slot<KEY>> and all entries point to it.
-        for _ in 0..max_slot {
-            slot_vec.push(Slot::new());
-            // slot_hash.push(Arc::new(Mutex::new(HashMap::new())));
-        }
-        TimingWheel2 {
-            max_slot,
-            sleep_duration,
-            default_duration,
-            slot_vec,
-            cur_counter: 0,
-            // slot_hash,
-            hash: Arc::new(Mutex::new(HashMap::new())),
-        }
-    }
-
-    // The initial duration is set to TIME_WHEEL_INIT_DURATION, but can be
-    // changed to reflect the network the client is on, (LAN or WAN),
-    // or the latency pattern.
-    #[inline(always)]
-    #[trace_var(index, slot, hash, vec)]
-    fn schedule(
-        &mut self,
-        key: KEY,
-        val: VAL,
-        duration: usize,
-    ) -> Result<(), (String, KEY)> {
-        // store the key in a slot of the timing wheel
-        let index = (self.cur_counter + duration) % self.max_slot;
-        let slot = &self.slot_vec[index];
-        // TODO replace unwrap
-        let mut hash = self.hash.lock().unwrap();
-        let mut vec = slot.entries.lock().unwrap();
-        hash.insert(key.clone(), val);
-        vec.push((key, duration));
-        return Ok(());
-    }
-
-    // Reschedule for later expiration, but not changes to the hashmap.
-    #[trace_var(index, slot, vec)]
-    #[inline(always)]
-    fn reschedule(
-        &mut self,
-        key: KEY,
-        duration: usize,
-    ) -> Result<(), (String, KEY)> {
-        dbg!((&key, duration, self.hash.lock(), self.cur_counter));
-        // store the key in a slot of the timing wheel
-        let index = (self.cur_counter + duration) % self.max_slot;
-        let slot = &self.slot_vec[index];
-        // TODO replace unwrap
-        let mut vec = slot.entries.lock().unwrap();
-        vec.push((key, duration));
-        return Ok(());
-    }
-
-    #[inline(always)]
-    fn get_cur_counter(&mut self) -> usize {
-        self.cur_counter
-    }
-
-    #[inline(always)]
-    fn get_hash(&mut self, key: KEY) -> Result<VAL, Option<(String, KEY)>> {
-        match self.hash.lock() {
-            Ok(hash) => match hash.get(&key) {
-                Some(val) => {
-                    dbg!((&key, val));
-                    Ok(val.to_owned())
-                }
-                None => Err(None),
-            },
-            _ => {
-                let string = format!("[{}:hash.lock()]", function!());
-                Err(Some((string, key)))
-            }
-        }
-    }
-
-    /// remove the data from the hash only
-    /// the expire() won't return data from the slot if it has been deleted
-    #[inline(always)]
-    #[trace_var(hash)]
-    fn cancel(&mut self, key: KEY) -> Result<(), (String, KEY)> {
-        let mut hash = self.hash.lock().unwrap();
-        match hash.remove(&key) {
-            Some(_val) => {
-                // dbg!((key, val));
-                Ok(())
-            }
-            None => {
-                let string = format!("[{}:hash.remove()]", function!());
-                Err((string, key))
-            }
-        }
-    }
-
-    /// remove the data from the hash only
-    /// the expire() won't return data from the slot if it has been deleted
-    #[inline(always)]
-    fn reset(&mut self, key: KEY) -> Result<(), (String, KEY)> {
-        let hash = self.hash.lock().unwrap();
-        match hash.get(&key) {
-            Some(_val) => {
-                // dbg!((key, val));
-                Ok(())
-            }
-            None => {
-                let string = format!("[{}:hash.remove()]", function!());
-                Err((string, key))
-            }
-        }
-    }
-    /// expire() is called every TIME_WHEEL_SLEEP_DURATION to iterate all
-    /// the entries in the slot.
-    /// If the new duration is greater than the maximun duration
-    /// (TIME_WHEEL_MAX_SLOTS)
-    /// remove it from the hashmap, else reschedule to the new duration.
-    // #[trace_var (next_slot_index, result_vec, result)]
-    #[inline(always)]
-    fn expire(&mut self) -> Vec<(KEY, VAL)> {
-        // select and lock the current time slot in the Vec
-        let cur_slot = &self.slot_vec[self.cur_counter % self.max_slot];
-        // returning data store in this vector
-        let mut result_vec = Vec::new();
-        // TODO replace unwrap()
-        let mut cur_slot_lock = cur_slot.entries.lock().unwrap();
-        // iterate for each entry in the selected Vec
-        while let Some(top) = cur_slot_lock.pop() {
-            let mut hash = self.hash.lock().unwrap();
-            // dbg!((top.clone(), SystemTime::now()));
-            // exponetial backup is inside the expire (lib)
-            // the caller doesn't have to do it
-            let duration = top.1 * 2;
-            let retrans_hdr = top.0;
-            if duration < self.max_slot {
-                // reschedule, don't remove hash entry
-                match hash.get(&retrans_hdr) {
-                    Some(result) => {
-                        // dbg!((retrans_hdr.clone(), result.clone()));
-                        // insert entry into next slot
-                        let next_slot_index =
-                            (self.cur_counter + duration) % self.max_slot;
-                        // gather results
-                        result_vec
-                            .push((retrans_hdr.clone(), result.to_owned()));
-                        let next_slot = &self.slot_vec[next_slot_index];
-
-                        let mut next_slot_lock =
-                            next_slot.entries.lock().unwrap();
-                        // dbg!(retrans_hdr.clone());
-                        next_slot_lock.push((retrans_hdr, duration));
-                        // TODO send it back inside the loop,
-                        // eliminate return loop need the to use
-                        // RetransmitHeader to access the address
-                        // can't access it from generic KEY
-                    }
-                    // It's empty, the hash value is canceled
-                    _ => (),
-                }
-            } else {
-                // timeout duration is over the limit, remove hash entry
-                // XXX Because generic is used, we can't access the internal of
-                // the KEY and VAL.
-                // TODO need to detect connect() timeout.
-                match hash.remove(&retrans_hdr) {
-                    Some(result) => {
-                        dbg!((retrans_hdr.clone(), result.clone()));
-                        /*
-                        if retrans_hdr.msg_type == MSG_TYPE_CONNACK {
-                            panic!("connect(): timeout; destination: {:?}",
-                                retrans_hdr.addr);
-                        }
-                        */
-                        // gather results
-                        // result_vec.push((retrans_hdr.clone(), result.to_owned()));
-                    }
-                    // It's empty, the hash value is canceled
-                    _ => (),
-                }
-            }
-        }
-        // next slot with overflow back to 0
-        self.cur_counter = self.cur_counter + 1;
-        // TODO test preload this slot in CPU cache?
-        result_vec
-    }
-
-    /// expire() is called every TIME_WHEEL_SLEEP_DURATION to iterate all the entries
-    /// in the slot.
-    /// If the new duration is greater than the maximun duration (TIME_WHEEL_MAX_SLOTS)
-    /// remove it from the hashmap, else reschedule to the new duration.
-    #[inline(always)]
-    // fn keep_alive_expire(&mut self) -> Vec<(KEY, VAL)>
-    fn keep_alive_expire(&mut self) -> &Slot<KEY> {
-        // select and lock the current time slot in the Vec
-        let cur_slot = &self.slot_vec[self.cur_counter % self.max_slot];
-        self.cur_counter = self.cur_counter + 1;
-        return cur_slot;
-    }
-
-    fn print(&mut self) {
-        let slot = &self.slot_vec[0];
-        println!("+++++++++++++++++++{:?}", slot);
-        let slot = &self.slot_vec[1];
-        println!("+++++++++++++++++++{:?}", slot);
-        let slot = &self.slot_vec[2];
-        println!("+++++++++++++++++++{:?}", slot);
-        let slot = &self.slot_vec[3];
-        println!("+++++++++++++++++++{:?}", slot);
-    }
-}
-
-/// RetransmitHeader is stored in:
-/// (1) HashMap for cancellation from an ACK
-/// (2) timing wheel slots for timeouts.
-/// When the wheel reads a slot, it iterates all entries in the vector.
-/// Using the RetransmitHeader to get/remove the RetransmitData
-/// in the HashMap.
-/// If the new duration is greater that the maximum timeout period
-/// the HashMap entry will be removed.
-/// To cancel a scheduled event, remove the HashMap entry with the
-/// RetransmitHeader. Don't need to remove the entry in the slot
-/// because the slot entry lookup ignores missing HashMap entries.
+/// RetransmitHeader is the key of the timer in the timing wheel,
+/// an ACK cancels the timer with the RetransmitHeader.
+/// On a timeout the message is retransmitted and the timer restarts
+/// with the doubled timeout. If the new timeout is greater than the
+/// maximum timeout period the timer is removed.
 #[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
 pub struct RetransmitHeader {
     pub addr: SocketAddr,
@@ -355,12 +97,7 @@ pub struct RetransmitHeader {
 #[derive(Debug, Clone)]
 pub struct RetransmitData {
     pub bytes: BytesMut,
-}
-
-#[derive(Debug, Clone)]
-struct KeepAliveData {
-    conn_duration: u16,
-    latest_counter: u32,
+    pub timeout: Duration,
 }
 
 #[derive(Debug, Clone)]
@@ -371,7 +108,8 @@ pub struct RetransTimeWheel {
     pub cancel_rx: Receiver<(SocketAddr, u8, u16, u16)>,
     transmit_tx: Sender<(SocketAddr, BytesMut)>,
     transmit_rx: Receiver<(SocketAddr, BytesMut)>,
-    wheel: TimingWheel2<RetransmitHeader, RetransmitData>,
+    default_duration: Duration,
+    wheel: Arc<ShardedWheel<RetransmitHeader, RetransmitData>>,
 }
 
 impl RetransTimeWheel {
@@ -385,7 +123,10 @@ impl RetransTimeWheel {
         transmit_tx: Sender<(SocketAddr, BytesMut)>,
         transmit_rx: Receiver<(SocketAddr, BytesMut)>,
     ) -> Self {
-        let wheel = TimingWheel2::new(sleep_duration, default_duration_ms);
+        let wheel = Arc::new(ShardedWheel::new(
+            Duration::from_millis(sleep_duration as u64),
+            Arc::new(MonotonicClock::new()),
+        ));
         RetransTimeWheel {
             schedule_tx,
             schedule_rx,
@@ -393,26 +134,22 @@ impl RetransTimeWheel {
             cancel_tx,
             transmit_tx,
             transmit_rx,
+            default_duration: Duration::from_millis(default_duration_ms as u64),
             wheel,
         }
     }
     pub fn run(self) {
-        let sleep_duration = self.wheel.sleep_duration;
-        let default_duration = self.wheel.default_duration;
+        let default_duration = self.default_duration;
         let schedule_rx = self.schedule_rx.clone();
         let cancel_rx = self.cancel_rx.clone();
         let transmit_tx = self.transmit_tx.clone();
-        let w = Arc::new(Mutex::new(self.wheel));
-        let rx_wheel = w.clone();
-        let expire_wheel = w.clone();
-        let cancel_wheel = w.clone();
+        let rx_wheel = self.wheel.clone();
+        let cancel_wheel = self.wheel.clone();
         // receive messages for cancel
         let cancel_thread = thread::spawn(move || {
             loop {
                 match cancel_rx.recv() {
-                    // XXX
                     Ok((addr, msg_type, topic_id, msg_id)) => {
-                        println!("-----------------------------");
                         let retrans_hdr = RetransmitHeader {
                             addr,
                             msg_type,
@@ -420,12 +157,7 @@ impl RetransTimeWheel {
                             msg_id,
                         };
                         dbg!(retrans_hdr);
-                        {
-                            // XXX lock an entry in the array, instead of the wheel?
-                            let mut cancel_wheel_lock =
-                                cancel_wheel.lock().unwrap();
-                            cancel_wheel_lock.cancel(retrans_hdr);
-                        }
+                        cancel_wheel.remove(&retrans_hdr);
                     }
                     Err(why) => {
                         // XXX thread panic, but the rest still run
@@ -445,11 +177,12 @@ impl RetransTimeWheel {
         let schedule_thread = thread::spawn(move || {
             loop {
                 match schedule_rx.recv() {
-                    // XXX
                     Ok((addr, msg_type, topic_id, msg_id, bytes)) => {
-                        println!("&&&&&&&&&&&&&&&&&&&&&&&&&&&&&&&&&&&&&&&&&&");
                         dbg!(bytes.clone());
-                        let data = RetransmitData { bytes };
+                        let data = RetransmitData {
+                            bytes,
+                            timeout: default_duration,
+                        };
                         let retrans_hdr = RetransmitHeader {
                             addr,
                             msg_type,
@@ -458,15 +191,7 @@ impl RetransTimeWheel {
                         };
                         dbg!(retrans_hdr);
                         dbg!(default_duration);
-                        {
-                            // XXX lock an entry in the array, instead of the wheel?
-                            let mut rx_wheel_lock = rx_wheel.lock().unwrap();
-                            rx_wheel_lock.schedule(
-                                retrans_hdr,
-                                data,
-                                default_duration,
-                            );
-                        }
+                        rx_wheel.insert(retrans_hdr, data, default_duration);
                     }
                     Err(why) => {
                         // XXX thread panic, but the rest still run
@@ -483,26 +208,30 @@ impl RetransTimeWheel {
             }
         });
         // timing wheel expire checks for timeout messages
-        let expire_thread = thread::spawn(move || {
-            // tokio::spawn(async move {
-            loop {
-                // use the block to allow lock() to automatically
-                // unlock at the end on the block
-                {
-                    let mut expire_wheel_lock = expire_wheel.lock().unwrap();
-                    let v = expire_wheel_lock.expire();
-                    // TODO this lock is too long
-                    for ack in v {
-                        // dbg!(ack.clone());
-                        let (retrans_hdr, data) = ack;
-                        dbg!((retrans_hdr.addr, data.bytes.clone()));
-                        transmit_tx.send((retrans_hdr.addr, data.bytes));
+        // exponetial backup is inside the expire thread,
+        // the caller doesn't have to do it
+        let expire_thread = driver::spawn_thread(
+            "retransmit",
+            self.wheel.clone(),
+            move |retrans_hdr, data| {
+                data.timeout *= 2;
+                if data.timeout < TIME_WHEEL_MAX_DURATION {
+                    match transmit_tx
+                        .send((retrans_hdr.addr, data.bytes.clone()))
+                    {
+                        Ok(()) => Some(data.timeout),
+                        Err(why) => {
+                            // No transmit thread, stop retransmitting.
+                            error!("{:?} {}", retrans_hdr, why);
+                            None
+                        }
                     }
-                    // let len = socket_tx3.send_to(b"hello", remote_addr);
+                } else {
+                    // TODO need to detect connect() timeout.
+                    debug!("Retransmit timeout: {:?}", retrans_hdr);
+                    None
                 }
-                // dbg!(sleep_duration);
-                thread::sleep(Duration::from_millis(sleep_duration as u64));
-            }
-        });
+            },
+        );
     }
 }
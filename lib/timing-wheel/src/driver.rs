@@ -0,0 +1,172 @@
+//! Drivers poll a ShardedWheel every tick and call f with the expired
+//! timers, see TimingWheel::poll_with(). The driver only keeps a Weak
+//! reference and stops after the last Arc of the wheel is dropped, so the
+//! callbacks needing the wheel must hold a Weak too, an Arc would keep
+//! the driver running forever.
+
+use std::hash::Hash;
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::sharded::ShardedWheel;
+
+/// Drive the wheel from a named std thread.
+pub fn spawn_thread<K, V, F>(
+    name: &str,
+    wheel: Arc<ShardedWheel<K, V>>,
+    mut f: F,
+) -> io::Result<thread::JoinHandle<()>>
+where
+    K: Eq + Hash + Clone + Send + 'static,
+    V: Send + 'static,
+    F: FnMut(&K, &mut V) -> Option<Duration> + Send + 'static,
+{
+    let tick = wheel.tick();
+    let weak = Arc::downgrade(&wheel);
+    thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || loop {
+            thread::sleep(tick);
+            match weak.upgrade() {
+                Some(wheel) => {
+                    wheel.poll_with(&mut f);
+                }
+                None => break,
+            }
+        })
+}
+
+/// Drive the wheel from a tokio task, the missed ticks are delayed.
+#[cfg(feature = "tokio")]
+pub fn spawn_tokio<K, V, F>(
+    wheel: Arc<ShardedWheel<K, V>>,
+    mut f: F,
+) -> tokio::task::JoinHandle<()>
+where
+    K: Eq + Hash + Clone + Send + 'static,
+    V: Send + 'static,
+    F: FnMut(&K, &mut V) -> Option<Duration> + Send + 'static,
+{
+    spawn_tokio_with(wheel, move |key, val, _: &mut ()| f(key, val), |_| {})
+}
+
+/// Drive the wheel from a tokio task like spawn_tokio(), f also collects
+/// the expired timers into a state S, and after() gets S once the shard
+/// locks are released, e.g. to call back into code using the wheel.
+#[cfg(feature = "tokio")]
+pub fn spawn_tokio_with<K, V, S, F, G>(
+    wheel: Arc<ShardedWheel<K, V>>,
+    mut f: F,
+    mut after: G,
+) -> tokio::task::JoinHandle<()>
+where
+    K: Eq + Hash + Clone + Send + 'static,
+    V: Send + 'static,
+    S: Default + Send + 'static,
+    F: FnMut(&K, &mut V, &mut S) -> Option<Duration> + Send + 'static,
+    G: FnMut(S) + Send + 'static,
+{
+    let tick = wheel.tick();
+    let weak = Arc::downgrade(&wheel);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(tick);
+        interval
+            .set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let mut state = S::default();
+            match weak.upgrade() {
+                Some(wheel) => {
+                    wheel.poll_with(|key, val| f(key, val, &mut state));
+                }
+                None => break,
+            }
+            // The wheel is released before after() runs.
+            after(state);
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{clock::MonotonicClock, sharded::ShardedWheel};
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_spawn_thread() {
+        let tick = Duration::from_millis(5);
+        let wheel =
+            Arc::new(ShardedWheel::new(tick, Arc::new(MonotonicClock::new())));
+        let (tx, rx) = channel();
+        let handle = super::spawn_thread("test_wheel", wheel.clone(), {
+            move |key: &u32, _val: &mut ()| {
+                tx.send(*key).unwrap();
+                None
+            }
+        })
+        .unwrap();
+        wheel.insert(1, (), tick * 4);
+        wheel.insert(2, (), tick * 2);
+        let timeout = Duration::from_secs(5);
+        assert_eq!(rx.recv_timeout(timeout), Ok(2));
+        assert_eq!(rx.recv_timeout(timeout), Ok(1));
+        drop(wheel);
+        handle.join().unwrap();
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_spawn_tokio() {
+        let tick = Duration::from_millis(5);
+        let wheel =
+            Arc::new(ShardedWheel::new(tick, Arc::new(MonotonicClock::new())));
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let handle = super::spawn_tokio(wheel.clone(), {
+            move |key: &u32, _val: &mut ()| {
+                tx.send(*key).unwrap();
+                None
+            }
+        });
+        wheel.insert(1, (), tick * 2);
+        assert_eq!(rx.recv().await, Some(1));
+        drop(wheel);
+        handle.await.unwrap();
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_spawn_tokio_with() {
+        let tick = Duration::from_millis(5);
+        let wheel =
+            Arc::new(ShardedWheel::new(tick, Arc::new(MonotonicClock::new())));
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let handle = super::spawn_tokio_with(
+            wheel.clone(),
+            |key: &u32, _val: &mut (), expired: &mut Vec<u32>| {
+                expired.push(*key);
+                None
+            },
+            {
+                // A Weak, an Arc would keep the driver running.
+                let wheel = Arc::downgrade(&wheel);
+                move |expired: Vec<u32>| {
+                    // The shard locks are released.
+                    for key in expired {
+                        if let Some(wheel) = wheel.upgrade() {
+                            assert!(!wheel.contains_key(&key));
+                        }
+                        tx.send(key).unwrap();
+                    }
+                }
+            },
+        );
+        wheel.insert(1, (), tick * 2);
+        assert_eq!(rx.recv().await, Some(1));
+        drop(wheel);
+        handle.await.unwrap();
+    }
+}
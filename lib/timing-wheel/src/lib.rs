@@ -0,0 +1,14 @@
+//! Hierarchical timing wheel shared by the broker and the client.
+//! TimingWheel is the single threaded wheel, ShardedWheel splits the
+//! timers in independently locked wheels so the ingress threads can
+//! schedule and cancel timers without contending on one lock.
+//! The wheels read the time from a pluggable Clock and are driven by a
+//! std thread or, with the "tokio" feature, a tokio task.
+pub mod clock;
+pub mod driver;
+mod sharded;
+mod wheel;
+
+pub use clock::{Clock, ManualClock, MonotonicClock};
+pub use sharded::ShardedWheel;
+pub use wheel::TimingWheel;
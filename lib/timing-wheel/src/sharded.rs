@@ -0,0 +1,198 @@
+//! TimingWheel split in shards by the hash of the key, each shard has its
+//! own lock. Schedule, cancel and reschedule only lock the shard of the
+//! key, poll() locks one shard at a time.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::{clock::Clock, wheel::TimingWheel};
+
+/// Default number of shards.
+pub const DEFAULT_SHARDS: usize = 16;
+
+pub struct ShardedWheel<K, V> {
+    tick: Duration,
+    hasher: RandomState,
+    shards: Vec<Mutex<TimingWheel<K, V>>>,
+}
+
+impl<K, V> fmt::Debug for ShardedWheel<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShardedWheel")
+            .field("tick", &self.tick)
+            .field("shards", &self.shards.len())
+            .finish()
+    }
+}
+
+impl<K: Eq + Hash + Clone, V> ShardedWheel<K, V> {
+    pub fn new(tick: Duration, clock: Arc<dyn Clock>) -> Self {
+        ShardedWheel::with_shards(DEFAULT_SHARDS, tick, clock)
+    }
+
+    pub fn with_shards(
+        shards: usize,
+        tick: Duration,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        ShardedWheel {
+            tick,
+            hasher: RandomState::new(),
+            shards: (0..shards.max(1))
+                .map(|_| Mutex::new(TimingWheel::new(tick, clock.clone())))
+                .collect(),
+        }
+    }
+
+    pub fn tick(&self) -> Duration {
+        self.tick
+    }
+
+    fn shard(&self, key: &K) -> MutexGuard<'_, TimingWheel<K, V>> {
+        let index = self.hasher.hash_one(key) as usize % self.shards.len();
+        self.shards[index].lock().unwrap()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.shard(key).contains_key(key)
+    }
+
+    pub fn get(&self, key: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.shard(key).get(key).cloned()
+    }
+
+    /// Modify the value of the key in place.
+    pub fn update<R>(&self, key: &K, f: impl FnOnce(&mut V) -> R) -> Option<R> {
+        self.shard(key).get_mut(key).map(f)
+    }
+
+    pub fn remaining(&self, key: &K) -> Option<Duration> {
+        self.shard(key).remaining(key)
+    }
+
+    /// See TimingWheel::insert().
+    pub fn insert(&self, key: K, val: V, delay: Duration) -> Option<V> {
+        self.shard(&key).insert(key, val, delay)
+    }
+
+    /// See TimingWheel::reschedule().
+    pub fn reschedule(&self, key: &K, delay: Duration) -> bool {
+        self.shard(key).reschedule(key, delay)
+    }
+
+    /// Restart the timer of the key with the delay computed from its
+    /// value. Returns false if the key has no timer.
+    pub fn reschedule_with(
+        &self,
+        key: &K,
+        delay: impl FnOnce(&V) -> Duration,
+    ) -> bool {
+        let mut shard = self.shard(key);
+        match shard.get(key) {
+            Some(val) => {
+                let delay = delay(val);
+                shard.reschedule(key, delay)
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.shard(key).remove(key)
+    }
+
+    /// See TimingWheel::remove_if().
+    pub fn remove_if(
+        &self,
+        mut pred: impl FnMut(&K, &V) -> bool,
+    ) -> Vec<(K, V)> {
+        let mut removed_vec = Vec::new();
+        for shard in self.shards.iter() {
+            removed_vec.append(&mut shard.lock().unwrap().remove_if(&mut pred));
+        }
+        removed_vec
+    }
+
+    /// See TimingWheel::poll().
+    pub fn poll(&self) -> Vec<(K, V)> {
+        let mut expired_vec = Vec::new();
+        for shard in self.shards.iter() {
+            expired_vec.append(&mut shard.lock().unwrap().poll());
+        }
+        expired_vec
+    }
+
+    /// See TimingWheel::poll_with(), f is called with the lock of the
+    /// shard held.
+    pub fn poll_with(
+        &self,
+        mut f: impl FnMut(&K, &mut V) -> Option<Duration>,
+    ) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().poll_with(&mut f))
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ShardedWheel;
+    use crate::clock::ManualClock;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_sharded_wheel() {
+        let tick = Duration::from_millis(10);
+        let clock = Arc::new(ManualClock::new());
+        let wheel = Arc::new(ShardedWheel::new(tick, clock.clone()));
+        // Schedule from several threads.
+        let handle_vec: Vec<_> = (0..4)
+            .map(|n| {
+                let wheel = wheel.clone();
+                thread::spawn(move || {
+                    for key in n * 1000..(n + 1) * 1000 {
+                        wheel.insert(key, key * 2, tick * (key % 10 + 1));
+                    }
+                })
+            })
+            .collect();
+        for handle in handle_vec {
+            handle.join().unwrap();
+        }
+        assert_eq!(wheel.len(), 4000);
+        assert_eq!(wheel.get(&7), Some(14));
+        assert_eq!(wheel.update(&7, |val| *val += 1), Some(()));
+        assert_eq!(wheel.remove(&7), Some(15));
+        assert!(wheel.reschedule_with(&8, |val| tick * *val));
+        assert_eq!(wheel.remove_if(|key, _val| *key >= 3000).len(), 1000);
+        clock.advance(tick * 10);
+        assert_eq!(wheel.poll().len(), 2998);
+        assert_eq!(
+            wheel.poll_with(|_key, _val| None),
+            0,
+            "nothing left to expire"
+        );
+        clock.advance(tick * 16);
+        assert_eq!(wheel.poll(), vec![(8, 16)]);
+    }
+}
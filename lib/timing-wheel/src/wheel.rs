@@ -0,0 +1,520 @@
+//! Hierarchical timing wheel keyed by the timer key.
+//! LEVELS wheels of SLOTS slots each, a slot of level n spans
+//! SLOTS^n ticks. A timer is placed in the lowest level that holds its
+//! deadline, and cascades down to the lower levels as the time passes.
+//! The timers are stored in a slab, the slots only hold the slab index
+//! and the generation of the placement:
+//! (1) schedule and cancel are O(1), a cancelled or moved timer leaves a
+//!     stale reference in its old slot that is skipped when the slot
+//!     is processed.
+//! (2) postponing a timer only updates its deadline, the timer is placed
+//!     again when its old slot is reached, so a keep alive refreshed on
+//!     every message doesn't touch the slots.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::clock::Clock;
+
+const LEVEL_BITS: u32 = 6;
+const SLOTS: usize = 1 << LEVEL_BITS;
+const LEVELS: usize = 6;
+
+struct Entry<K, V> {
+    key: K,
+    val: V,
+    // In ticks of the wheel.
+    deadline: u64,
+}
+
+struct SlabEntry<K, V> {
+    // Incremented on every placement, the older slot references are stale.
+    generation: u32,
+    entry: Option<Entry<K, V>>,
+}
+
+// (slab index, generation)
+type SlotRef = (usize, u32);
+
+pub struct TimingWheel<K, V> {
+    tick: Duration,
+    clock: Arc<dyn Clock>,
+    // The last tick processed by advance().
+    elapsed: u64,
+    slab: Vec<SlabEntry<K, V>>,
+    free: Vec<usize>,
+    keys: HashMap<K, usize>,
+    levels: Vec<Vec<Vec<SlotRef>>>,
+}
+
+impl<K, V> fmt::Debug for TimingWheel<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TimingWheel")
+            .field("tick", &self.tick)
+            .field("elapsed", &self.elapsed)
+            .field("len", &self.keys.len())
+            .finish()
+    }
+}
+
+impl<K: Eq + Hash + Clone, V> TimingWheel<K, V> {
+    pub fn new(tick: Duration, clock: Arc<dyn Clock>) -> Self {
+        assert!(!tick.is_zero(), "zero tick");
+        let elapsed = (clock.now().as_nanos() / tick.as_nanos()) as u64;
+        TimingWheel {
+            tick,
+            clock,
+            elapsed,
+            slab: Vec::new(),
+            free: Vec::new(),
+            keys: HashMap::new(),
+            levels: (0..LEVELS)
+                .map(|_| (0..SLOTS).map(|_| Vec::new()).collect())
+                .collect(),
+        }
+    }
+
+    pub fn tick(&self) -> Duration {
+        self.tick
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.keys.contains_key(key)
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let index = *self.keys.get(key)?;
+        self.slab[index].entry.as_ref().map(|entry| &entry.val)
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let index = *self.keys.get(key)?;
+        self.slab[index].entry.as_mut().map(|entry| &mut entry.val)
+    }
+
+    /// Time left before the timer of the key expires.
+    pub fn remaining(&self, key: &K) -> Option<Duration> {
+        let index = *self.keys.get(key)?;
+        let deadline = self.slab[index].entry.as_ref()?.deadline;
+        let ticks = deadline.saturating_sub(self.now_tick());
+        Some(Duration::from_nanos(
+            (self.tick.as_nanos() * ticks as u128) as u64,
+        ))
+    }
+
+    /// Schedule the timer of the key to expire after the delay,
+    /// an existing timer of the key is replaced and its value returned.
+    pub fn insert(&mut self, key: K, val: V, delay: Duration) -> Option<V> {
+        let deadline = self.deadline(delay);
+        if let Some(&index) = self.keys.get(&key) {
+            let old_val = match self.slab[index].entry.as_mut() {
+                Some(entry) => std::mem::replace(&mut entry.val, val),
+                None => unreachable!("key without timer"),
+            };
+            self.move_deadline(index, deadline);
+            return Some(old_val);
+        }
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.slab.push(SlabEntry {
+                    generation: 0,
+                    entry: None,
+                });
+                self.slab.len() - 1
+            }
+        };
+        self.slab[index].entry = Some(Entry {
+            key: key.clone(),
+            val,
+            deadline,
+        });
+        self.keys.insert(key, index);
+        self.place(index);
+        None
+    }
+
+    /// Restart the timer of the key with a new delay.
+    /// Returns false if the key has no timer.
+    pub fn reschedule(&mut self, key: &K, delay: Duration) -> bool {
+        match self.keys.get(key) {
+            Some(&index) => {
+                let deadline = self.deadline(delay);
+                self.move_deadline(index, deadline);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Cancel the timer of the key.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let index = self.keys.remove(key)?;
+        self.release(index).map(|entry| entry.val)
+    }
+
+    /// Cancel the timers matching the predicate, O(n).
+    pub fn remove_if(
+        &mut self,
+        mut pred: impl FnMut(&K, &V) -> bool,
+    ) -> Vec<(K, V)> {
+        let index_vec: Vec<usize> = self
+            .keys
+            .values()
+            .copied()
+            .filter(|index| match &self.slab[*index].entry {
+                Some(entry) => pred(&entry.key, &entry.val),
+                None => false,
+            })
+            .collect();
+        index_vec
+            .into_iter()
+            .filter_map(|index| {
+                let entry = self.release(index)?;
+                self.keys.remove(&entry.key);
+                Some((entry.key, entry.val))
+            })
+            .collect()
+    }
+
+    /// Remove and return the expired timers, oldest deadline first.
+    pub fn poll(&mut self) -> Vec<(K, V)> {
+        let mut expired_vec = Vec::new();
+        let now = self.now_tick();
+        while let Some(index_vec) = self.next_tick(now) {
+            for index in index_vec {
+                if let Some(entry) = self.release(index) {
+                    self.keys.remove(&entry.key);
+                    expired_vec.push((entry.key, entry.val));
+                }
+            }
+        }
+        expired_vec
+    }
+
+    /// Call f with the expired timers, oldest deadline first.
+    /// f returns the delay to schedule the timer again, or None to remove
+    /// it. Returns the number of expired timers.
+    pub fn poll_with(
+        &mut self,
+        mut f: impl FnMut(&K, &mut V) -> Option<Duration>,
+    ) -> usize {
+        let mut count = 0;
+        let now = self.now_tick();
+        while let Some(index_vec) = self.next_tick(now) {
+            for index in index_vec {
+                let delay = match self.slab[index].entry.as_mut() {
+                    Some(entry) => f(&entry.key, &mut entry.val),
+                    None => continue,
+                };
+                count += 1;
+                match delay {
+                    Some(delay) => {
+                        let deadline =
+                            now.max(self.elapsed) + self.ticks(delay);
+                        if let Some(entry) = self.slab[index].entry.as_mut() {
+                            entry.deadline = deadline;
+                        }
+                        self.place(index);
+                    }
+                    None => {
+                        if let Some(entry) = self.release(index) {
+                            self.keys.remove(&entry.key);
+                        }
+                    }
+                }
+            }
+        }
+        count
+    }
+
+    fn now_tick(&self) -> u64 {
+        (self.clock.now().as_nanos() / self.tick.as_nanos()) as u64
+    }
+
+    /// At least one tick, rounded up.
+    fn ticks(&self, delay: Duration) -> u64 {
+        let ticks = delay.as_nanos().div_ceil(self.tick.as_nanos());
+        (ticks as u64).max(1)
+    }
+
+    fn deadline(&self, delay: Duration) -> u64 {
+        self.now_tick().max(self.elapsed) + self.ticks(delay)
+    }
+
+    /// A later deadline is picked up when the current slot is reached,
+    /// an earlier one needs a new slot.
+    fn move_deadline(&mut self, index: usize, deadline: u64) {
+        let placed_deadline = match self.slab[index].entry.as_mut() {
+            Some(entry) => std::mem::replace(&mut entry.deadline, deadline),
+            None => return,
+        };
+        if deadline < placed_deadline {
+            self.place(index);
+        }
+    }
+
+    /// Level and slot of the deadline relative to the elapsed tick.
+    /// The levels are the 6 bits digits of the tick, the level is the
+    /// highest digit where the deadline differs from the elapsed tick.
+    fn slot_of(&self, deadline: u64) -> (usize, usize) {
+        let deadline = deadline.max(self.elapsed);
+        let masked = (self.elapsed ^ deadline) | (SLOTS as u64 - 1);
+        let significant = u64::BITS - 1 - masked.leading_zeros();
+        // A deadline beyond the top level comes back to the top level
+        // when its slot is reached.
+        let level = ((significant / LEVEL_BITS) as usize).min(LEVELS - 1);
+        let slot =
+            (deadline >> (level as u32 * LEVEL_BITS)) as usize & (SLOTS - 1);
+        (level, slot)
+    }
+
+    fn place(&mut self, index: usize) {
+        let deadline = match &self.slab[index].entry {
+            Some(entry) => entry.deadline,
+            None => return,
+        };
+        let (level, slot) = self.slot_of(deadline);
+        let slab_entry = &mut self.slab[index];
+        slab_entry.generation = slab_entry.generation.wrapping_add(1);
+        self.levels[level][slot].push((index, slab_entry.generation));
+    }
+
+    fn release(&mut self, index: usize) -> Option<Entry<K, V>> {
+        let slab_entry = &mut self.slab[index];
+        let entry = slab_entry.entry.take()?;
+        slab_entry.generation = slab_entry.generation.wrapping_add(1);
+        self.free.push(index);
+        Some(entry)
+    }
+
+    fn is_current(&self, slot_ref: &SlotRef) -> bool {
+        let slab_entry = &self.slab[slot_ref.0];
+        slab_entry.generation == slot_ref.1 && slab_entry.entry.is_some()
+    }
+
+    /// Move to the next tick up to now with slots to process, the empty
+    /// ticks are skipped. Returns None when the wheel reaches now.
+    fn next_tick(&mut self, now: u64) -> Option<Vec<usize>> {
+        if self.elapsed >= now {
+            return None;
+        }
+        match self.next_event() {
+            Some(tick) if tick <= now => {
+                self.elapsed = tick - 1;
+                Some(self.advance_tick())
+            }
+            _ => {
+                self.elapsed = now;
+                None
+            }
+        }
+    }
+
+    /// The next tick that processes a non-empty slot.
+    fn next_event(&self) -> Option<u64> {
+        let mut next: Option<u64> = None;
+        for (level, slot_vec) in self.levels.iter().enumerate() {
+            let shift = level as u32 * LEVEL_BITS;
+            let rotation = 1u64 << (shift + LEVEL_BITS);
+            // The first tick of the current rotation of the level.
+            let base = self.elapsed & !(rotation - 1);
+            for (slot, slot_refs) in slot_vec.iter().enumerate() {
+                if slot_refs.is_empty() {
+                    continue;
+                }
+                let mut tick = base + ((slot as u64) << shift);
+                if tick <= self.elapsed {
+                    tick += rotation;
+                }
+                next = Some(next.map_or(tick, |next| next.min(tick)));
+            }
+        }
+        next
+    }
+
+    /// Move to the next tick and return the slab index of the expired
+    /// timers, they are still in the slab but not in any slot.
+    fn advance_tick(&mut self) -> Vec<usize> {
+        self.elapsed += 1;
+        let tick = self.elapsed;
+        // Cascade the higher levels first, a timer may go down to the
+        // level 0 slot of this tick.
+        for level in (1..LEVELS).rev() {
+            let shift = level as u32 * LEVEL_BITS;
+            if tick & ((1 << shift) - 1) != 0 {
+                continue;
+            }
+            let slot = (tick >> shift) as usize & (SLOTS - 1);
+            let slot_vec = std::mem::take(&mut self.levels[level][slot]);
+            for slot_ref in slot_vec {
+                if self.is_current(&slot_ref) {
+                    self.place(slot_ref.0);
+                }
+            }
+        }
+        let slot = tick as usize & (SLOTS - 1);
+        let slot_vec = std::mem::take(&mut self.levels[0][slot]);
+        let mut expired_vec = Vec::new();
+        for slot_ref in slot_vec {
+            if !self.is_current(&slot_ref) {
+                continue;
+            }
+            let index = slot_ref.0;
+            match &self.slab[index].entry {
+                Some(entry) if entry.deadline <= tick => {
+                    expired_vec.push(index)
+                }
+                // Postponed by move_deadline().
+                Some(_) => self.place(index),
+                None => {}
+            }
+        }
+        expired_vec
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TimingWheel;
+    use crate::clock::ManualClock;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const TICK: Duration = Duration::from_millis(100);
+
+    fn new_wheel() -> (TimingWheel<u32, &'static str>, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new());
+        (TimingWheel::new(TICK, clock.clone()), clock)
+    }
+
+    #[test]
+    fn test_expire() {
+        let (mut wheel, clock) = new_wheel();
+        wheel.insert(1, "a", Duration::from_millis(300));
+        wheel.insert(2, "b", Duration::from_millis(100));
+        // Rounded up to one tick.
+        wheel.insert(3, "c", Duration::from_millis(1));
+        assert_eq!(wheel.len(), 3);
+        assert!(wheel.poll().is_empty());
+        clock.advance(TICK);
+        let mut expired_vec = wheel.poll();
+        expired_vec.sort();
+        assert_eq!(expired_vec, vec![(2, "b"), (3, "c")]);
+        clock.advance(TICK);
+        assert!(wheel.poll().is_empty());
+        clock.advance(TICK);
+        assert_eq!(wheel.poll(), vec![(1, "a")]);
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn test_cancel_and_reschedule() {
+        let (mut wheel, clock) = new_wheel();
+        wheel.insert(1, "a", Duration::from_secs(1));
+        wheel.insert(2, "b", Duration::from_secs(1));
+        assert_eq!(wheel.remove(&1), Some("a"));
+        assert_eq!(wheel.remove(&1), None);
+        // Replace the value and postpone.
+        assert_eq!(wheel.insert(2, "c", Duration::from_secs(2)), Some("b"));
+        clock.advance(Duration::from_secs(1));
+        assert!(wheel.poll().is_empty());
+        assert_eq!(wheel.remaining(&2), Some(Duration::from_secs(1)));
+        // Earlier than the current deadline.
+        assert!(wheel.reschedule(&2, Duration::from_millis(500)));
+        assert!(!wheel.reschedule(&1, Duration::from_millis(500)));
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(wheel.poll(), vec![(2, "c")]);
+    }
+
+    #[test]
+    fn test_long_duration() {
+        let (mut wheel, clock) = new_wheel();
+        // Each level of the wheel and beyond the top level.
+        let secs_vec = [
+            7,
+            500,
+            30_000,
+            98_302,
+            3_000_000,
+            200_000_000,
+            7_000_000_000,
+        ];
+        for (key, secs) in secs_vec.iter().enumerate() {
+            wheel.insert(key as u32, "x", Duration::from_secs(*secs));
+        }
+        let mut now = 0;
+        for (key, secs) in secs_vec.iter().enumerate() {
+            clock.advance(Duration::from_secs(secs - now - 1));
+            assert!(wheel.poll().is_empty(), "{}", secs);
+            clock.advance(Duration::from_secs(1));
+            assert_eq!(wheel.poll(), vec![(key as u32, "x")], "{}", secs);
+            now = *secs;
+        }
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn test_poll_with() {
+        let (mut wheel, clock) = new_wheel();
+        wheel.insert(1, "a", TICK);
+        let mut count = 0;
+        // Retransmit with exponential backoff, 3 times.
+        for ticks in [1, 2, 4] {
+            clock.advance(TICK * ticks);
+            assert_eq!(
+                wheel.poll_with(|_key, _val| {
+                    count += 1;
+                    if count < 3 {
+                        Some(TICK * ticks * 2)
+                    } else {
+                        None
+                    }
+                }),
+                1
+            );
+        }
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn test_remove_if() {
+        let (mut wheel, clock) = new_wheel();
+        for key in 0..10 {
+            wheel.insert(key, "x", TICK);
+        }
+        let mut removed_vec = wheel.remove_if(|key, _val| key % 2 == 0);
+        removed_vec.sort();
+        assert_eq!(removed_vec.len(), 5);
+        assert_eq!(removed_vec[0], (0, "x"));
+        clock.advance(TICK);
+        assert_eq!(wheel.poll().len(), 5);
+    }
+
+    #[test]
+    fn test_many_timers() {
+        let (mut wheel, clock) = new_wheel();
+        for key in 0..100_000 {
+            wheel.insert(key, "x", TICK * (key % 1000 + 1));
+        }
+        // Refresh half of the timers, the slots are not touched.
+        for key in (0..100_000).step_by(2) {
+            wheel.reschedule(&key, Duration::from_secs(1000));
+        }
+        clock.advance(TICK * 1000);
+        assert_eq!(wheel.poll().len(), 50_000);
+        clock.advance(Duration::from_secs(1000));
+        assert_eq!(wheel.poll().len(), 50_000);
+    }
+}